@@ -0,0 +1,475 @@
+use std::io;
+
+use crate::api::types::*;
+use crate::storage::cache;
+use serde::Deserialize;
+use serde_json::Value;
+
+// https://archive.org/advancedsearch.php?q=odyssey+AND+mediatype:(audio)&fl[]=identifier&output=json
+// https://archive.org/metadata/odyssey_librivox
+
+#[derive(Debug, Clone)]
+pub struct ArchiveClient {
+    base_url: String,
+    rows: u32,
+}
+
+impl Default for ArchiveClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArchiveClient {
+    pub fn new() -> Self {
+        Self::with_base_url("https://archive.org/")
+    }
+
+    /// Point the client at a different host, e.g. a local stand-in for the Archive.
+    pub fn with_base_url(base_url: &str) -> Self {
+        let mut base_url = base_url.to_string();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        Self {
+            base_url,
+            rows: SEARCH_PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AdvancedSearch {
+    response: AdvancedSearchResponse,
+}
+
+#[derive(Deserialize)]
+struct AdvancedSearchResponse {
+    docs: Vec<SearchDoc>,
+}
+
+#[derive(Deserialize)]
+struct SearchDoc {
+    identifier: String,
+    #[serde(default)]
+    title: Value,
+    #[serde(default)]
+    creator: Value,
+    #[serde(default)]
+    description: Value,
+}
+
+#[derive(Deserialize)]
+struct ItemMetadata {
+    #[serde(default)]
+    metadata: Value,
+    #[serde(default)]
+    files: Vec<ItemFile>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ItemFile {
+    pub name: String,
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub format: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub track: Option<String>,
+    #[serde(default)]
+    pub length: Option<String>,
+}
+
+impl ArchiveClient {
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        genre: Option<&str>,
+    ) -> Result<Vec<Book>, Box<ureq::Error>> {
+        let url = format!("{}advancedsearch.php", self.base_url);
+
+        let mut terms = vec!["mediatype:(audio)".to_string()];
+        let text = [&query.title, &query.keywords]
+            .iter()
+            .filter_map(|field| field.as_deref())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        if !text.trim().is_empty() {
+            terms.push(format!("({})", text.trim()));
+        }
+        if let Some(author) = &query.author {
+            terms.push(format!("creator:({})", author));
+        }
+        if let Some(genre) = genre {
+            terms.push(format!("subject:({})", genre));
+        }
+        let q = terms.join(" AND ");
+        log::info!("Archive search: {}", q);
+
+        let mut request = ureq::get(&url)
+            .query("q", &q)
+            .query("fl[]", "identifier")
+            .query("fl[]", "title")
+            .query("fl[]", "creator")
+            .query("fl[]", "description")
+            .query("rows", &self.rows.to_string())
+            .query("page", &(query.page() + 1).to_string())
+            .query("output", "json");
+        request = match query.sort_order() {
+            SortOrder::Title => request.query("sort[]", "titleSorter asc"),
+            SortOrder::Date => request.query("sort[]", "publicdate desc"),
+            SortOrder::Popularity => request.query("sort[]", "downloads desc"),
+            SortOrder::Relevance => request,
+        };
+        let body = cache::fetch(request, cache::SEARCH_TTL)?;
+
+        let results: AdvancedSearch =
+            serde_json::from_str(&body).map_err(|e| ureq::Error::from(io::Error::from(e)))?;
+
+        Ok(results
+            .response
+            .docs
+            .into_iter()
+            .map(|doc| Book {
+                saved: false,
+                title: first_string(&doc.title).unwrap_or_else(|| doc.identifier.clone()),
+                author: first_string(&doc.creator).unwrap_or_default(),
+                description: first_string(&doc.description).unwrap_or_default(),
+                url: self.details_url(&doc.identifier),
+                image_URL: self.thumbnail_url(&doc.identifier),
+                chapter_titles: vec![],
+                chapter_urls: vec![],
+                chapter_durations: vec![],
+                chapter_reader: vec![],
+            })
+            .filter(|book| query.matches(book))
+            .collect())
+    }
+
+    pub fn get_book(&self, url: String) -> Result<Book, Box<ureq::Error>> {
+        let identifier = identifier_from_url(&url).ok_or_else(|| {
+            ureq::Error::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not an archive.org item",
+            ))
+        })?;
+
+        let metadata_url = format!("{}metadata/{}", self.base_url, identifier);
+        log::info!("Calling: {}", metadata_url);
+        let body = cache::fetch(ureq::get(&metadata_url), cache::BOOK_TTL)?;
+        let item: ItemMetadata =
+            serde_json::from_str(&body).map_err(|e| ureq::Error::from(io::Error::from(e)))?;
+
+        let title = item
+            .metadata
+            .get("title")
+            .and_then(first_string)
+            .unwrap_or_else(|| identifier.to_string());
+        let author = item
+            .metadata
+            .get("creator")
+            .and_then(first_string)
+            .unwrap_or_else(|| "Unknown Author".to_string());
+        let description = item
+            .metadata
+            .get("description")
+            .and_then(first_string)
+            .unwrap_or_else(|| "No description available".to_string());
+
+        let tracks = select_audio_files(&item.files);
+        if tracks.is_empty() {
+            log::warn!("No audio files found for item: {}", identifier);
+        }
+
+        Ok(Book {
+            saved: false,
+            title,
+            description,
+            chapter_titles: tracks
+                .iter()
+                .map(|file| file.title.clone().unwrap_or_else(|| file.name.clone()))
+                .collect(),
+            chapter_urls: tracks
+                .iter()
+                .map(|file| self.download_url(identifier, &file.name))
+                .collect(),
+            chapter_durations: tracks
+                .iter()
+                .map(|file| {
+                    file.length
+                        .as_deref()
+                        .and_then(parse_length)
+                        .map(format_duration)
+                        .unwrap_or_default()
+                })
+                .collect(),
+            chapter_reader: tracks.iter().map(|_| author.clone()).collect(),
+            author,
+            url: self.details_url(identifier),
+            image_URL: self.thumbnail_url(identifier),
+        })
+    }
+
+    fn details_url(&self, identifier: &str) -> String {
+        format!("{}details/{}", self.base_url, identifier)
+    }
+
+    fn thumbnail_url(&self, identifier: &str) -> String {
+        format!("{}services/img/{}", self.base_url, identifier)
+    }
+
+    // download_audio relies on this layout to find __ia_thumb.jpg next to the track
+    fn download_url(&self, identifier: &str, name: &str) -> String {
+        format!(
+            "{}download/{}/{}",
+            self.base_url,
+            identifier,
+            name.replace('%', "%25")
+                .replace(' ', "%20")
+                .replace('#', "%23")
+                .replace('?', "%3F")
+        )
+    }
+}
+
+/// Pulls the item identifier out of a details, download or metadata URL.
+pub fn identifier_from_url(url: &str) -> Option<&str> {
+    ["/details/", "/download/", "/metadata/"]
+        .iter()
+        .find_map(|marker| url.split_once(marker))
+        .and_then(|(_, rest)| rest.split(['/', '?', '#']).next())
+        .filter(|identifier| !identifier.is_empty())
+}
+
+/// Picks one playable file per track, preferring the 64kbps MP3 derivative over the original
+/// upload and ordering them by the item's track metadata.
+pub fn select_audio_files(files: &[ItemFile]) -> Vec<ItemFile> {
+    let preferred_formats = ["64Kbps MP3", "VBR MP3", "128Kbps MP3", "MP3"];
+
+    let mut tracks: Vec<ItemFile> = preferred_formats
+        .iter()
+        .map(|format| {
+            files
+                .iter()
+                .filter(|file| file.format.eq_ignore_ascii_case(format))
+                .cloned()
+                .collect::<Vec<ItemFile>>()
+        })
+        .find(|tracks| !tracks.is_empty())
+        .unwrap_or_else(|| {
+            // No MP3 at all, fall back to whatever audio was originally uploaded
+            files
+                .iter()
+                .filter(|file| file.source == "original" && is_audio_format(&file.format))
+                .cloned()
+                .collect()
+        });
+
+    tracks.sort_by(|a, b| {
+        let a_track = a.track.as_deref().and_then(parse_track);
+        let b_track = b.track.as_deref().and_then(parse_track);
+        match (a_track, b_track) {
+            (Some(a_num), Some(b_num)) => a_num.cmp(&b_num).then_with(|| a.name.cmp(&b.name)),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => a.name.cmp(&b.name),
+        }
+    });
+    tracks
+}
+
+fn is_audio_format(format: &str) -> bool {
+    let format = format.to_ascii_lowercase();
+    [
+        "mp3", "flac", "ogg", "vorbis", "wave", "aiff", "m4a", "aac", "opus",
+    ]
+    .iter()
+    .any(|audio| format.contains(audio))
+}
+
+// Track metadata comes as "3", "03" or "3/12"
+fn parse_track(track: &str) -> Option<u32> {
+    track.split('/').next()?.trim().parse().ok()
+}
+
+// Length is either seconds ("1234.56") or a clock ("20:34" / "1:20:34")
+pub fn parse_length(length: &str) -> Option<f64> {
+    if !length.contains(':') {
+        return length.trim().parse().ok();
+    }
+    length.split(':').try_fold(0.0, |total, part| {
+        part.trim()
+            .parse::<f64>()
+            .ok()
+            .map(|value| total * 60.0 + value)
+    })
+}
+
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+// Metadata fields can be either a single string or a list of them
+fn first_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.trim().to_string()),
+        Value::Array(values) => values.iter().find_map(first_string),
+        _ => None,
+    }
+    .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::setup::test_env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers every request with the body of the first route its path starts with, and
+    // returns the base url to point the client at
+    fn serve(routes: Vec<(&'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                // Skip the headers, nothing here needs them
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let response = match routes.iter().find(|(route, _)| path.starts_with(route)) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                             Connection: close\r\n\r\n"
+                        .to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        base_url
+    }
+
+    const SEARCH: &str = r#"{"response": {"docs": [
+        {"identifier": "odyssey_librivox", "title": "The Odyssey", "creator": ["Homer"]},
+        {"identifier": "untitled_item"}
+    ]}}"#;
+
+    const ODYSSEY: &str = r#"{
+        "metadata": {"title": "The Odyssey", "creator": "Homer", "description": "Books"},
+        "files": [
+            {"name": "odyssey_10.mp3", "source": "derivative", "format": "64Kbps MP3",
+             "title": "Book 10", "track": "10/12", "length": "20:34"},
+            {"name": "odyssey_02.mp3", "source": "derivative", "format": "64Kbps MP3",
+             "title": "Book 2", "track": "2", "length": "1234.4"},
+            {"name": "odyssey_01.mp3", "source": "original", "format": "VBR MP3",
+             "track": "1"},
+            {"name": "odyssey_02.flac", "source": "original", "format": "Flac", "track": "2"},
+            {"name": "bonus.mp3", "source": "derivative", "format": "64Kbps MP3"},
+            {"name": "odyssey_01.mp3", "source": "derivative", "format": "64Kbps MP3",
+             "title": "Book 1", "track": "01"}
+        ]
+    }"#;
+
+    const FLAC_ONLY: &str = r#"{
+        "metadata": {"title": ["Flac Only"]},
+        "files": [
+            {"name": "part 2.flac", "source": "original", "format": "Flac", "track": "2"},
+            {"name": "cover.jpg", "source": "original", "format": "JPEG"},
+            {"name": "part 1.flac", "source": "original", "format": "Flac", "track": "1"},
+            {"name": "part 1.ogg", "source": "derivative", "format": "Ogg Vorbis"}
+        ]
+    }"#;
+
+    #[test]
+    fn search_reads_the_results() {
+        let _env = test_env();
+        let base_url = serve(vec![("/advancedsearch.php", SEARCH)]);
+        let client = ArchiveClient::with_base_url(base_url.trim_end_matches('/'));
+
+        let books = client
+            .search(&SearchQuery::new("odyssey".to_string()), None)
+            .unwrap();
+
+        assert_eq!(books.len(), 2);
+        assert_eq!(books[0].title, "The Odyssey");
+        assert_eq!(books[0].author, "Homer");
+        assert_eq!(
+            books[0].url,
+            format!("{}details/odyssey_librivox", base_url)
+        );
+        assert_eq!(
+            books[0].image_URL,
+            format!("{}services/img/odyssey_librivox", base_url)
+        );
+        // Without a title the identifier has to do
+        assert_eq!(books[1].title, "untitled_item");
+    }
+
+    #[test]
+    fn get_book_prefers_64kbps_tracks_in_order() {
+        let _env = test_env();
+        let base_url = serve(vec![("/metadata/odyssey_librivox", ODYSSEY)]);
+        let client = ArchiveClient::with_base_url(&base_url);
+
+        let book = client
+            .get_book(format!("{}details/odyssey_librivox", base_url))
+            .unwrap();
+
+        assert_eq!(book.title, "The Odyssey");
+        assert_eq!(book.author, "Homer");
+        assert_eq!(
+            book.chapter_titles,
+            vec!["Book 1", "Book 2", "Book 10", "bonus.mp3"]
+        );
+        assert_eq!(
+            book.chapter_urls[0],
+            format!("{}download/odyssey_librivox/odyssey_01.mp3", base_url)
+        );
+        assert_eq!(book.chapter_durations, vec!["", "00:20:34", "00:20:34", ""]);
+        assert_eq!(book.chapter_reader, vec!["Homer"; 4]);
+    }
+
+    #[test]
+    fn get_book_falls_back_to_the_original_upload() {
+        let _env = test_env();
+        let base_url = serve(vec![("/metadata/flac_only", FLAC_ONLY)]);
+        let client = ArchiveClient::with_base_url(&base_url);
+
+        let book = client
+            .get_book(format!("{}details/flac_only", base_url))
+            .unwrap();
+
+        assert_eq!(book.title, "Flac Only");
+        assert_eq!(book.author, "Unknown Author");
+        assert_eq!(book.chapter_titles, vec!["part 1.flac", "part 2.flac"]);
+        assert_eq!(
+            book.chapter_urls,
+            vec![
+                format!("{}download/flac_only/part%201.flac", base_url),
+                format!("{}download/flac_only/part%202.flac", base_url),
+            ]
+        );
+    }
+}
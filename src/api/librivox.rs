@@ -1,12 +1,17 @@
-use std::vec;
+use std::{io, vec};
 
+use crate::api::archive::{format_duration, identifier_from_url};
 use crate::api::types::*;
-use serde_json::json;
+use crate::storage::cache;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use scraper::{Html, Selector};
 
 #[derive(Debug, Clone)]
 pub struct LibriVoxClient {
     base_url: String,
+    site_url: String,
+    api_url: String,
 }
 
 impl Default for LibriVoxClient {
@@ -19,18 +24,189 @@ impl LibriVoxClient {
     pub fn new() -> Self {
         Self {
             base_url: "https://librivox.app/".to_string(),
+            site_url: "https://librivox.org/".to_string(),
+            api_url: "https://librivox.org/api/feed/".to_string(),
         }
     }
 }
 
+// https://librivox.org/api/feed/audiobooks/?title=^republic&extended=1&format=json
+#[derive(Deserialize)]
+struct ApiBooks {
+    #[serde(default)]
+    books: Vec<ApiBook>,
+}
+
+#[derive(Deserialize)]
+struct ApiBook {
+    id: Value,
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    url_iarchive: String,
+    #[serde(default)]
+    authors: Vec<ApiAuthor>,
+    #[serde(default)]
+    sections: Vec<ApiSection>,
+}
+
+#[derive(Deserialize)]
+struct ApiAuthor {
+    #[serde(default)]
+    first_name: String,
+    #[serde(default)]
+    last_name: String,
+}
+
+#[derive(Deserialize)]
+struct ApiSection {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    listen_url: String,
+    #[serde(default)]
+    playtime: Value,
+    #[serde(default)]
+    readers: Vec<ApiReader>,
+}
+
+#[derive(Deserialize)]
+struct ApiReader {
+    #[serde(default)]
+    display_name: String,
+}
+
 // https://librivox.app/search.jsp?search=marxism
 
 impl LibriVoxClient {
-    pub fn search(&self, query: String) -> Result<Vec<Book>, ureq::Error> {
+    /// Structured search. Plain keyword searches go through librivox.app, anything with
+    /// filters goes through the LibriVox API. Neither can sort, so results come in the
+    /// order LibriVox gives them, see `Provider::can_sort`.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        genre: Option<&str>,
+    ) -> Result<Vec<Book>, Box<ureq::Error>> {
+        if query.is_simple() {
+            // librivox.app has every match on its one page, there's no more to load
+            if query.page() > 0 {
+                return Ok(vec![]);
+            }
+            return self.search_site(query.text());
+        }
+
+        let mut request = ureq::get(&format!("{}audiobooks/", self.api_url))
+            .query("format", "json")
+            .query("extended", "1")
+            .query("limit", &SEARCH_PAGE_SIZE.to_string())
+            .query("offset", &(query.page() * SEARCH_PAGE_SIZE).to_string());
+        let title = [&query.title, &query.keywords]
+            .iter()
+            .filter_map(|field| field.as_deref())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        if !title.trim().is_empty() {
+            request = request.query("title", title.trim());
+        }
+        if let Some(author) = &query.author {
+            request = request.query("author", author);
+        }
+        if let Some(genre) = genre {
+            request = request.query("genre", genre);
+        }
+
+        log::info!("Librivox API search: {}", request.url());
+        let body = match cache::fetch(request, cache::SEARCH_TTL) {
+            Ok(body) => body,
+            // The API answers 404 when nothing matched
+            Err(e) if matches!(*e, ureq::Error::Status(404, _)) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let results: ApiBooks = serde_json::from_str(&body)
+            .map_err(|e| ureq::Error::from(io::Error::from(e)))?;
+
+        Ok(results
+            .books
+            .into_iter()
+            .map(|book| self.api_book(book))
+            .filter(|book| query.matches(book))
+            .collect())
+    }
+
+    /// All the genres LibriVox files books under.
+    pub fn get_genres(&self) -> Result<Vec<Genre>, Box<ureq::Error>> {
+        let url = format!("{}search", self.site_url);
+        let body = cache::fetch(ureq::get(&url), cache::BOOK_TTL)?;
+        let document = Html::parse_document(&body);
+
+        let option_selector = Selector::parse("select#genre_id option").unwrap();
+        Ok(document
+            .select(&option_selector)
+            .filter_map(|option| {
+                let id = option.value().attr("value")?.parse::<u32>().ok()?;
+                let name = option.text().collect::<String>().trim().to_string();
+                // Skip the "all genres" placeholder
+                (id != 0 && !name.is_empty()).then_some(Genre { id, name })
+            })
+            .collect())
+    }
+
+    fn api_book(&self, book: ApiBook) -> Book {
+        let author = book
+            .authors
+            .iter()
+            .map(|author| format!("{} {}", author.first_name, author.last_name).trim().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        Book {
+            saved: false,
+            title: book.title,
+            chapter_titles: book.sections.iter().map(|s| s.title.clone()).collect(),
+            chapter_urls: book.sections.iter().map(|s| s.listen_url.clone()).collect(),
+            chapter_durations: book
+                .sections
+                .iter()
+                .map(|s| {
+                    value_to_string(&s.playtime)
+                        .parse::<f64>()
+                        .map(format_duration)
+                        .unwrap_or_default()
+                })
+                .collect(),
+            chapter_reader: book
+                .sections
+                .iter()
+                .map(|s| {
+                    s.readers
+                        .iter()
+                        .map(|reader| reader.display_name.clone())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                })
+                .collect(),
+            description: Html::parse_fragment(&book.description)
+                .root_element()
+                .text()
+                .collect::<String>()
+                .trim()
+                .to_string(),
+            author,
+            url: format!(
+                "{}audiobooks/?id={}&extended=1&format=json",
+                self.api_url,
+                value_to_string(&book.id)
+            ),
+            image_URL: identifier_from_url(&book.url_iarchive)
+                .map(|identifier| format!("https://archive.org/services/img/{}", identifier))
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn search_site(&self, query: String) -> Result<Vec<Book>, Box<ureq::Error>> {
         let url = format!("{}search.jsp?search={}", self.base_url, json!(query));
-        let body: String = ureq::get(&url)
-            .call()?
-            .into_string()?;
+        let body: String = cache::fetch(ureq::get(&url), cache::SEARCH_TTL)?;
 
 
         log::info!("Librivox search: {}", url);
@@ -104,6 +280,7 @@ impl LibriVoxClient {
                     format!("{}{}", self.base_url, book_url)
                 },
                 image_URL: cover_url,
+                chapter_titles: vec![],
                 chapter_urls: vec![],
                 chapter_durations: vec![],
                 chapter_reader: vec![],
@@ -112,10 +289,22 @@ impl LibriVoxClient {
         .collect())
     }
     
-    pub fn get_book(&self, url: String) -> Result<Book, ureq::Error> {
-        let body = ureq::get(&url)
-            .call().unwrap()
-            .into_string()?;
+    pub fn get_book(&self, url: String) -> Result<Book, Box<ureq::Error>> {
+        if url.starts_with(&self.api_url) {
+            let body = cache::fetch(ureq::get(&url), cache::BOOK_TTL)?;
+            let results: ApiBooks = serde_json::from_str(&body)
+                .map_err(|e| ureq::Error::from(io::Error::from(e)))?;
+            return results
+                .books
+                .into_iter()
+                .next()
+                .map(|book| self.api_book(book))
+                .ok_or_else(|| {
+                    Box::new(io::Error::new(io::ErrorKind::NotFound, "Book not found").into())
+                });
+        }
+
+        let body = cache::fetch(ureq::get(&url), cache::BOOK_TTL)?;
 
         log::info!("Calling: {}", url);
         let document = Html::parse_document(&body);
@@ -189,9 +378,18 @@ impl LibriVoxClient {
             author,
             url,
             image_URL: image_url,
+            chapter_titles: vec![],
             chapter_urls: chapters.iter().map(|(link, _, _)| link.clone()).collect(),
             chapter_durations: chapters.iter().map(|(_, duration, _)| duration.clone()).collect(),
             chapter_reader: chapters.iter().map(|(_, _, reader)| reader.clone()).collect(),
         })
     }
 }
+
+// Ids and play times come back as either strings or numbers
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
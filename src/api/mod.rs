@@ -1,4 +1,6 @@
+pub mod archive;
 pub mod librivox;
+pub mod rss;
 pub mod webimage;
 pub mod yt;
 
@@ -0,0 +1,326 @@
+use std::io;
+
+use crate::api::archive::{format_duration, parse_length};
+use crate::api::types::*;
+use crate::storage::cache;
+use roxmltree::{Document, Node};
+
+const ITUNES_NS: &str = "http://www.itunes.com/dtds/podcast-1.0.dtd";
+
+#[derive(Debug, Clone, Default)]
+pub struct RssClient {}
+
+impl RssClient {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+/// A single enclosure from a feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Episode {
+    /// The guid when the feed has one, otherwise the enclosure URL
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub duration: String,
+    pub published: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Feed {
+    pub title: String,
+    pub author: String,
+    pub description: String,
+    pub image_url: String,
+    /// Oldest episode first
+    pub episodes: Vec<Episode>,
+}
+
+impl Feed {
+    /// Maps the feed to a book where every episode is a chapter, oldest first.
+    pub fn to_book(&self, feed_url: &str) -> Book {
+        Book {
+            saved: false,
+            title: self.title.clone(),
+            chapter_titles: self.episodes.iter().map(|e| e.title.clone()).collect(),
+            chapter_urls: self.episodes.iter().map(|e| e.url.clone()).collect(),
+            chapter_durations: self.episodes.iter().map(|e| e.duration.clone()).collect(),
+            chapter_reader: self.episodes.iter().map(|_| self.author.clone()).collect(),
+            description: self.description.clone(),
+            author: self.author.clone(),
+            url: feed_url.to_string(),
+            image_URL: self.image_url.clone(),
+        }
+    }
+}
+
+/// Best guess at whether a URL points at an RSS/Atom feed rather than a web page.
+pub fn is_feed_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    // The LibriVox API lives under /api/feed/ but isn't a feed
+    !path.contains("/api/")
+        && (path.ends_with(".rss")
+            || path.ends_with(".xml")
+            || path.ends_with(".atom")
+            || path.contains("/rss")
+            || path.contains("/feed")
+            || url.contains("format=rss"))
+}
+
+impl RssClient {
+    pub fn get_feed(&self, url: &str) -> Result<Feed, Box<ureq::Error>> {
+        log::info!("Fetching feed: {}", url);
+        let body = cache::fetch(ureq::get(url), cache::FEED_TTL)?;
+        parse_feed(&body)
+            .map_err(|e| Box::new(io::Error::new(io::ErrorKind::InvalidData, e).into()))
+    }
+
+    pub fn get_book(&self, url: String) -> Result<Book, Box<ureq::Error>> {
+        Ok(self.get_feed(&url)?.to_book(&url))
+    }
+}
+
+pub fn parse_feed(body: &str) -> Result<Feed, String> {
+    let document = Document::parse(body).map_err(|e| format!("Invalid feed: {}", e))?;
+    let root = document.root_element();
+
+    match root.tag_name().name() {
+        "rss" => {
+            let channel = child(root, "channel").ok_or("RSS feed without a channel")?;
+            Ok(parse_rss_channel(channel))
+        }
+        "feed" => Ok(parse_atom_feed(root)),
+        other => Err(format!("Unsupported feed type: {}", other)),
+    }
+}
+
+fn parse_rss_channel(channel: Node) -> Feed {
+    let image_url = channel
+        .children()
+        .find(|n| n.has_tag_name((ITUNES_NS, "image")))
+        .and_then(|n| n.attribute("href"))
+        .map(|href| href.to_string())
+        .or_else(|| child(channel, "image").and_then(|image| child_text(image, "url")))
+        .unwrap_or_default();
+
+    let author = channel
+        .children()
+        .find(|n| n.has_tag_name((ITUNES_NS, "author")))
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+        .unwrap_or_default();
+
+    let mut episodes: Vec<Episode> = channel
+        .children()
+        .filter(|n| n.has_tag_name("item"))
+        .filter_map(|item| {
+            let url = child(item, "enclosure")?.attribute("url")?.to_string();
+            Some(Episode {
+                id: child_text(item, "guid").unwrap_or_else(|| url.clone()),
+                title: child_text(item, "title").unwrap_or_default(),
+                duration: item
+                    .children()
+                    .find(|n| n.has_tag_name((ITUNES_NS, "duration")))
+                    .and_then(|n| n.text())
+                    .and_then(parse_length)
+                    .map(format_duration)
+                    .unwrap_or_default(),
+                published: child_text(item, "pubDate").unwrap_or_default(),
+                url,
+            })
+        })
+        .collect();
+    // Feeds list the newest episode first
+    episodes.reverse();
+
+    Feed {
+        title: child_text(channel, "title").unwrap_or_default(),
+        author,
+        description: child_text(channel, "description").unwrap_or_default(),
+        image_url,
+        episodes,
+    }
+}
+
+fn parse_atom_feed(feed: Node) -> Feed {
+    let mut episodes: Vec<Episode> = feed
+        .children()
+        .filter(|n| n.tag_name().name() == "entry")
+        .filter_map(|entry| {
+            let url = entry
+                .children()
+                .find(|n| n.tag_name().name() == "link" && n.attribute("rel") == Some("enclosure"))?
+                .attribute("href")?
+                .to_string();
+            Some(Episode {
+                id: child_text(entry, "id").unwrap_or_else(|| url.clone()),
+                title: child_text(entry, "title").unwrap_or_default(),
+                duration: "".to_string(),
+                published: child_text(entry, "updated")
+                    .or_else(|| child_text(entry, "published"))
+                    .unwrap_or_default(),
+                url,
+            })
+        })
+        .collect();
+    episodes.reverse();
+
+    Feed {
+        title: child_text(feed, "title").unwrap_or_default(),
+        author: child(feed, "author")
+            .and_then(|author| child_text(author, "name"))
+            .unwrap_or_default(),
+        description: child_text(feed, "subtitle").unwrap_or_default(),
+        image_url: child_text(feed, "logo")
+            .or_else(|| child_text(feed, "icon"))
+            .unwrap_or_default(),
+        episodes,
+    }
+}
+
+// Matches on the local name so both namespaced (Atom) and plain (RSS) elements are found
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| {
+        n.is_element() && n.tag_name().name() == name && n.tag_name().namespace() != Some(ITUNES_NS)
+    })
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title>Bedtime Stories</title>
+    <description>A story every week</description>
+    <itunes:author>Storyteller</itunes:author>
+    <itunes:image href="https://example.com/cover.jpg"/>
+    <image><url>https://example.com/small.jpg</url></image>
+    <item>
+      <title>Episode 3</title>
+      <guid>ep-3</guid>
+      <pubDate>Mon, 15 Jan 2024 08:00:00 GMT</pubDate>
+      <itunes:duration>1:02:03</itunes:duration>
+      <enclosure url="https://example.com/3.mp3" type="audio/mpeg"/>
+    </item>
+    <item>
+      <title>Trailer without audio</title>
+    </item>
+    <item>
+      <title>Episode 2</title>
+      <itunes:duration>125</itunes:duration>
+      <enclosure url="https://example.com/2.mp3" type="audio/mpeg"/>
+    </item>
+    <item>
+      <title>Episode 1</title>
+      <guid>ep-1</guid>
+      <enclosure url="https://example.com/1.mp3" type="audio/mpeg"/>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Lectures</title>
+  <subtitle>The whole course</subtitle>
+  <author><name>Professor</name></author>
+  <icon>https://example.com/icon.png</icon>
+  <entry>
+    <title>Lecture 2</title>
+    <id>urn:lecture:2</id>
+    <updated>2024-02-02T10:00:00Z</updated>
+    <link rel="alternate" href="https://example.com/lecture-2"/>
+    <link rel="enclosure" href="https://example.com/lecture-2.mp3"/>
+  </entry>
+  <entry>
+    <title>Lecture 1</title>
+    <published>2024-01-01T10:00:00Z</published>
+    <link rel="enclosure" href="https://example.com/lecture-1.mp3"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn rss_episodes_come_oldest_first() {
+        let feed = parse_feed(RSS).unwrap();
+
+        assert_eq!(feed.title, "Bedtime Stories");
+        assert_eq!(feed.author, "Storyteller");
+        assert_eq!(feed.description, "A story every week");
+        assert_eq!(feed.image_url, "https://example.com/cover.jpg");
+        let titles: Vec<&str> = feed.episodes.iter().map(|e| e.title.as_str()).collect();
+        // Items without an enclosure have nothing to play
+        assert_eq!(titles, ["Episode 1", "Episode 2", "Episode 3"]);
+        assert_eq!(feed.episodes[0].id, "ep-1");
+        // No guid, the enclosure is the next best thing
+        assert_eq!(feed.episodes[1].id, "https://example.com/2.mp3");
+        assert_eq!(feed.episodes[2].published, "Mon, 15 Jan 2024 08:00:00 GMT");
+        assert_eq!(feed.episodes[2].duration, "01:02:03");
+        assert_eq!(feed.episodes[1].duration, "00:02:05");
+        assert_eq!(feed.episodes[0].duration, "");
+    }
+
+    #[test]
+    fn atom_entries_come_oldest_first() {
+        let feed = parse_feed(ATOM).unwrap();
+
+        assert_eq!(feed.title, "Lectures");
+        assert_eq!(feed.author, "Professor");
+        assert_eq!(feed.description, "The whole course");
+        assert_eq!(feed.image_url, "https://example.com/icon.png");
+        assert_eq!(
+            feed.episodes,
+            vec![
+                Episode {
+                    id: "https://example.com/lecture-1.mp3".to_string(),
+                    title: "Lecture 1".to_string(),
+                    url: "https://example.com/lecture-1.mp3".to_string(),
+                    duration: "".to_string(),
+                    published: "2024-01-01T10:00:00Z".to_string(),
+                },
+                Episode {
+                    id: "urn:lecture:2".to_string(),
+                    title: "Lecture 2".to_string(),
+                    url: "https://example.com/lecture-2.mp3".to_string(),
+                    duration: "".to_string(),
+                    published: "2024-02-02T10:00:00Z".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn feed_maps_to_a_book_of_episodes() {
+        let book = parse_feed(RSS).unwrap().to_book("https://example.com/feed.rss");
+
+        assert_eq!(book.url, "https://example.com/feed.rss");
+        assert_eq!(book.chapter_titles, ["Episode 1", "Episode 2", "Episode 3"]);
+        assert_eq!(book.chapter_urls[2], "https://example.com/3.mp3");
+        assert_eq!(book.chapter_reader, ["Storyteller"; 3]);
+    }
+
+    #[test]
+    fn other_documents_are_not_feeds() {
+        assert!(parse_feed("<html><body>Not a feed</body></html>").is_err());
+        assert!(parse_feed("<rss version=\"2.0\"></rss>").is_err());
+        assert!(parse_feed("not xml at all").is_err());
+    }
+
+    #[test]
+    fn feed_urls_are_told_apart_from_pages() {
+        assert!(is_feed_url("https://example.com/podcast.rss"));
+        assert!(is_feed_url("https://example.com/feed/podcast"));
+        assert!(is_feed_url("https://example.com/show?format=rss"));
+        assert!(!is_feed_url("https://librivox.org/api/feed/audiobooks/?id=52"));
+        assert!(!is_feed_url("https://librivox.org/the-odyssey-by-homer/"));
+    }
+}
@@ -8,18 +8,169 @@ pub enum AudiodyError {
     RequestError(#[from] reqwest::Error),
     #[error("Failed to parse response: {0}")]
     ParseError(String),
+    #[error("{0}")]
+    ProviderError(String),
+    #[error("Timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("{tool} failed (exit code {code:?}): {stderr}")]
+    ToolFailed {
+        tool: String,
+        code: Option<i32>,
+        stderr: String,
+    },
 }
 
+/// The places books can be searched for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    LibriVox,
+    YouTube,
+    Archive,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 3] = [Provider::LibriVox, Provider::YouTube, Provider::Archive];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::LibriVox => "LibriVox",
+            Provider::YouTube => "YouTube",
+            Provider::Archive => "Internet Archive",
+        }
+    }
+
+    /// Whether the provider can put every page of results in `order`. Those that can't give
+    /// their best matches first instead.
+    pub fn can_sort(&self, order: SortOrder) -> bool {
+        match self {
+            // The API has no sorting, and it can't be done here without fetching every page
+            Provider::LibriVox => order == SortOrder::Relevance,
+            // Videos have no usable upload date, the rest is sorted from the whole result list
+            Provider::YouTube => order != SortOrder::Date,
+            Provider::Archive => true,
+        }
+    }
+}
+
+/// Number of results asked from each provider per page
+pub const SEARCH_PAGE_SIZE: u32 = 20;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Relevance,
+    Title,
+    Date,
+    Popularity,
+}
+
+impl SortOrder {
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "title" => SortOrder::Title,
+            "date" | "newest" => SortOrder::Date,
+            "popularity" | "popular" => SortOrder::Popularity,
+            _ => SortOrder::Relevance,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Relevance => "relevance",
+            SortOrder::Title => "title",
+            SortOrder::Date => "date",
+            SortOrder::Popularity => "popularity",
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct SearchQuery {
     pub title: Option<String>,
     pub author: Option<String>,
     pub reader: Option<String>,
     pub keywords: Option<String>,
     pub genre_id: Option<u32>,
-    pub sort_order: Option<String>,
+    pub sort_order: Option<SortOrder>,
     pub page: Option<u32>
 }
 
+impl SearchQuery {
+    pub fn new(keywords: String) -> Self {
+        Self {
+            keywords: Some(keywords),
+            ..Default::default()
+        }
+    }
+
+    /// The query as plain text, for providers that only have a search box
+    pub fn text(&self) -> String {
+        [&self.title, &self.keywords, &self.author]
+            .iter()
+            .filter_map(|field| field.as_deref())
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    pub fn page(&self) -> u32 {
+        self.page.unwrap_or(0)
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        self.sort_order.unwrap_or_default()
+    }
+
+    /// True when only keywords are set, which every provider can handle directly. The page
+    /// doesn't count, every page of a query has to come from the same place.
+    pub fn is_simple(&self) -> bool {
+        self.title.is_none()
+            && self.author.is_none()
+            && self.reader.is_none()
+            && self.genre_id.is_none()
+            && self.sort_order() == SortOrder::Relevance
+    }
+
+    /// Client side author/reader filtering for providers that can't filter themselves.
+    /// Books without reader information are kept.
+    pub fn matches(&self, book: &Book) -> bool {
+        let contains = |haystack: &str, needle: &str| {
+            haystack.to_lowercase().contains(&needle.trim().to_lowercase())
+        };
+        let author_matches = self
+            .author
+            .as_deref()
+            .is_none_or(|author| contains(&book.author, author));
+        let reader_matches = self.reader.as_deref().is_none_or(|reader| {
+            book.chapter_reader.is_empty()
+                || book.chapter_reader.iter().any(|r| contains(r, reader))
+        });
+        author_matches && reader_matches
+    }
+}
+
+/// Where a chapter sits inside a single audio file, in seconds
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChapterOffset {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl ChapterOffset {
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Genre {
+    pub id: u32,
+    pub name: String,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Books {
@@ -31,6 +182,8 @@ pub struct Books {
 pub struct Book {
     pub saved : bool,
     pub title: String,
+    #[serde(default)]
+    pub chapter_titles: Vec<String>,
     pub chapter_urls: Vec<String>,
     pub chapter_durations: Vec<String>,
     pub chapter_reader: Vec<String>,
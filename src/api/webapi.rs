@@ -1,4 +1,15 @@
-use super::{librivox::LibriVoxClient, types::Book, yt::YouTubeClient};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::storage::genres;
+
+use super::{
+    archive::{identifier_from_url, ArchiveClient},
+    librivox::LibriVoxClient,
+    rss::{is_feed_url, RssClient},
+    types::{AudiodyError, Book, ChapterOffset, Genre, Provider, SearchQuery},
+    yt::{is_playlist_url, YouTubeClient},
+};
 
 // Auto set language to English which is recorded_langage=1
 /*
@@ -13,10 +24,17 @@ https://freeclassicaudiobooks.com/
 https://www.learnoutloud.com/Free-Audiobooks
 */
 
+/// How long a single provider gets to answer a search
+pub const SEARCH_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[derive(Debug, Clone)]
 pub struct WebApiClient {
     youtube_client: YouTubeClient,
     libri_client: LibriVoxClient,
+    archive_client: ArchiveClient,
+    rss_client: RssClient,
+    /// Providers searches go to, shared between clones so settings changes reach them all
+    providers: Arc<RwLock<Vec<Provider>>>,
 }
 
 impl Default for WebApiClient {
@@ -30,29 +48,140 @@ impl WebApiClient {
         Self {
             youtube_client: YouTubeClient::new(),
             libri_client: LibriVoxClient::new(),
+            archive_client: ArchiveClient::new(),
+            rss_client: RssClient::new(),
+            providers: Arc::new(RwLock::new(Provider::ALL.to_vec())),
         }
     }
+
+    pub fn providers(&self) -> Vec<Provider> {
+        self.providers.read().unwrap().clone()
+    }
+
+    /// Limits searches to `providers`, from the next search on.
+    pub fn set_providers(&self, providers: Vec<Provider>) {
+        *self.providers.write().unwrap() = providers;
+    }
 }
 
 // https://librivox.app/search.jsp?search=marxism
 
 impl WebApiClient {
     
-    pub async fn search(&self, query: String) -> Result<Vec<Vec<Book>>, ureq::Error> {
-        // Librivox search
-        let libri_books = self.libri_client.search(query.to_string()).unwrap();
+    /// Queries every enabled provider at once. `on_result` is called as soon as each provider
+    /// answers, fails or runs out of time, so one slow or broken provider never holds up the rest.
+    pub async fn search<F>(&self, query: &SearchQuery, timeout: Duration, on_result: F)
+    where
+        F: Fn(Provider, Result<Vec<Book>, AudiodyError>) + Send + Sync + 'static,
+    {
+        // Looking up the genre name can hit the network too
+        let genre = match query.genre_id {
+            Some(genre_id) => {
+                let client = self.clone();
+                tokio::task::spawn_blocking(move || client.genre_name(genre_id))
+                    .await
+                    .unwrap_or_default()
+            }
+            None => None,
+        };
+
+        let on_result = Arc::new(on_result);
+        let mut searches = tokio::task::JoinSet::new();
+        for provider in self.providers() {
+            let client = self.clone();
+            let query = query.clone();
+            let genre = genre.clone();
+            let on_result = on_result.clone();
+            searches.spawn(async move {
+                // Its own task so a panicking provider is reported like any other error
+                let search = tokio::spawn(async move {
+                    client.search_provider(provider, query, genre).await
+                });
+                let result = match tokio::time::timeout(timeout, search).await {
+                    Ok(Ok(result)) => result,
+                    Ok(Err(e)) => Err(AudiodyError::ProviderError(e.to_string())),
+                    Err(_) => Err(AudiodyError::Timeout(timeout)),
+                };
+                if let Err(e) = &result {
+                    log::warn!("{} search failed: {}", provider.name(), e);
+                }
+                on_result(provider, result);
+            });
+        }
+        while searches.join_next().await.is_some() {}
+    }
+
+    async fn search_provider(
+        &self,
+        provider: Provider,
+        query: SearchQuery,
+        genre: Option<String>,
+    ) -> Result<Vec<Book>, AudiodyError> {
+        let provider_error = |e: &dyn std::fmt::Display| AudiodyError::ProviderError(e.to_string());
+        match provider {
+            Provider::LibriVox => {
+                let client = self.libri_client.clone();
+                tokio::task::spawn_blocking(move || client.search(&query, genre.as_deref()))
+                    .await
+                    .map_err(|e| provider_error(&e))?
+                    .map_err(|e| provider_error(&e))
+            }
+            // YouTube has no idea about genres
+            Provider::YouTube if genre.is_some() => Ok(vec![]),
+            Provider::YouTube => self
+                .youtube_client
+                .search(&query)
+                .await
+                .map_err(|e| provider_error(&e)),
+            Provider::Archive => {
+                let client = self.archive_client.clone();
+                tokio::task::spawn_blocking(move || client.search(&query, genre.as_deref()))
+                    .await
+                    .map_err(|e| provider_error(&e))?
+                    .map_err(|e| provider_error(&e))
+            }
+        }
+    }
 
-        // Youtube search
-        let yt_books = self.youtube_client.search(query.to_string()).await?;
-        let books = vec![libri_books, yt_books];
-        Ok(books)
+    /// LibriVox genres, cached on disk.
+    pub fn get_genres(&self) -> Vec<Genre> {
+        genres::get_genres(|| self.libri_client.get_genres())
     }
 
-    pub async fn get_book(&self, url: String) -> Result<Book, ureq::Error> {
+    /// One page of LibriVox books in a genre.
+    pub fn get_genre_books(&self, genre: &Genre, page: u32) -> Result<Vec<Book>, Box<ureq::Error>> {
+        let query = SearchQuery {
+            genre_id: Some(genre.id),
+            page: Some(page),
+            ..Default::default()
+        };
+        self.libri_client.search(&query, Some(&genre.name))
+    }
+
+    fn genre_name(&self, genre_id: u32) -> Option<String> {
+        self.get_genres()
+            .into_iter()
+            .find(|genre| genre.id == genre_id)
+            .map(|genre| genre.name)
+    }
+
+    /// Chapter offsets for a YouTube book kept as one file.
+    pub async fn get_chapter_offsets(&self, url: String) -> Result<Vec<ChapterOffset>, ureq::Error> {
+        self.youtube_client.get_chapter_offsets(url).await
+    }
+
+    pub async fn get_book(&self, url: String) -> Result<Book, Box<ureq::Error>> {
         // Librivox search
         log::info!("Getting book: {}", url);
-        if url.contains("librivox") {
-            let libri_book = self.libri_client.get_book(url).unwrap();
+        // Feeds and archive identifiers often contain "librivox", so check these first
+        if is_feed_url(&url) {
+            let feed_book = self.rss_client.get_book(url)?;
+            Ok(feed_book)
+        } else if url.contains("archive.org") {
+            let archive_book = self.archive_client.get_book(url)?;
+            Ok(archive_book)
+        } else if url.contains("librivox") {
+            let libri_book = self.libri_client.get_book(url)?;
             Ok(libri_book)
         } else if url.contains("youtube.com") {
             let yt_book = self.youtube_client.get_book(url).await?;
@@ -65,10 +194,68 @@ impl WebApiClient {
                 url: "".to_string(),
                 description: "".to_string(),
                 saved: false,
+                chapter_titles: vec![],
                 chapter_urls: vec![],
                 chapter_durations: vec![],
                 chapter_reader: vec![],
             })
         }
     }
-}
\ No newline at end of file
+}
+/// Name of whoever `get_book` would ask for the book at `url`, empty if nobody would.
+pub fn provider_name(url: &str) -> &'static str {
+    if is_feed_url(url) {
+        "RSS"
+    } else if url.contains("archive.org") {
+        Provider::Archive.name()
+    } else if url.contains("librivox") {
+        Provider::LibriVox.name()
+    } else if url.contains("youtube.com") {
+        Provider::YouTube.name()
+    } else {
+        ""
+    }
+}
+
+/// What identifies the book at `url` with its provider, so the different urls one book can
+/// be reached by come out the same: the LibriVox catalog id, the YouTube video or playlist
+/// id, the Archive identifier or the feed url. Anything else is its own url.
+pub fn source_id(url: &str) -> String {
+    let url = url.trim();
+    let id = match provider_name(url) {
+        "RSS" => None,
+        name if name == Provider::Archive.name() => identifier_from_url(url).map(str::to_string),
+        // The API has `?id=123`, librivox.app pages have the same number in theirs
+        name if name == Provider::LibriVox.name() => query_param(url, "id")
+            .or_else(|| query_param(url, "bookId"))
+            .or_else(|| {
+                url.split(['?', '#'])
+                    .next()?
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .filter(|segment| segment.chars().all(|c| c.is_ascii_digit()))
+                    .map(str::to_string)
+            })
+            .filter(|id| !id.is_empty()),
+        name if name == Provider::YouTube.name() => match is_playlist_url(url) {
+            true => query_param(url, "list"),
+            false => query_param(url, "v"),
+        },
+        _ => None,
+    };
+    id.unwrap_or_else(|| url.to_string())
+}
+
+// Value of `key` in the query of `url`
+fn query_param(url: &str, key: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('#')
+        .next()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty())
+}
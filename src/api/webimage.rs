@@ -1,30 +1,21 @@
-use image::{io::Reader as ImageReader, open};
 use slint::{Image, Rgba8Pixel, SharedPixelBuffer};
-use std::io::Cursor;
 
-pub async fn url_to_buffer(url: String) -> Result<Image, Box<dyn std::error::Error>> {
-    log::info!("Converting: {url}");
-    let mut url = url;
-    if url.contains("lh3.googleusercontent.com") {
-        url.push('0');
-    }
-    let rgba_img;
-    if !url.contains(".webp") {
-        let client = reqwest::Client::new();
-        let response = client.get(&url).send().await?;
-        log::info!("Getting image from {}", url);
+use crate::storage::covers::{get_cover, CoverSize};
 
-        // Get bytes from response
-        let buffer = response.bytes().await?;
-        // Convert to image buffer with proper error handling
-        let img = ImageReader::new(Cursor::new(buffer))
-            .with_guessed_format()?
-            .decode()?;
-        // Convert to RGBA8
-        rgba_img = img.into_rgba8();
-    } else {
-        rgba_img = open(&url).unwrap().into_rgba8();
-    }
+/// Grey square shown in place of a cover until the real one has loaded
+pub fn placeholder_cover() -> Image {
+    let mut pixel_buffer = SharedPixelBuffer::<Rgba8Pixel>::new(1, 1);
+    pixel_buffer.make_mut_slice()[0] = Rgba8Pixel::new(60, 60, 60, 255);
+    Image::from_rgba8(pixel_buffer)
+}
+
+/// Loads a cover through the cover cache. Pixel buffers can be sent between threads, unlike
+/// images, so this can run away from the event loop.
+pub fn load_cover(
+    url: &str,
+    size: CoverSize,
+) -> Result<SharedPixelBuffer<Rgba8Pixel>, Box<dyn std::error::Error>> {
+    let rgba_img = image::open(get_cover(url, size)?)?.into_rgba8();
 
     let width = rgba_img.width();
     let height = rgba_img.height();
@@ -37,5 +28,5 @@ pub async fn url_to_buffer(url: String) -> Result<Image, Box<dyn std::error::Err
         .make_mut_bytes()
         .copy_from_slice(&rgba_img.into_raw());
 
-    Ok(Image::from_rgba8(pixel_buffer))
+    Ok(pixel_buffer)
 }
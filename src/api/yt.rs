@@ -1,12 +1,90 @@
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, RwLock};
 use std::{fs, thread, vec};
 
+use crate::api::archive::format_duration;
 use crate::api::types::*;
-use rusty_ytdl::search::{SearchResult, YouTube};
-use rusty_ytdl::Video;
+use crate::storage::binaries::{self, Tool};
+use crate::storage::cache;
+use crate::storage::save::part_file;
+use rusty_ytdl::search::{
+    Playlist, PlaylistSearchOptions, SearchOptions, SearchResult, SearchType, YouTube,
+};
+use rusty_ytdl::{Video, VideoOptions, VideoQuality, VideoSearchOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
 use tokio::runtime::Runtime;
 
+/// How YouTube audio gets downloaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadBackend {
+    /// The managed yt-dlp, which converts everything to mp3 and splits chapters itself
+    YtDlp,
+    /// Fetches the m4a audio stream straight from YouTube, for platforms that can't run yt-dlp.
+    /// Chapters are cut out of it as raw AAC, or played from offsets in the one file.
+    Native,
+}
+
+impl DownloadBackend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DownloadBackend::YtDlp => "yt-dlp",
+            DownloadBackend::Native => "native",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DownloadBackend> {
+        [DownloadBackend::YtDlp, DownloadBackend::Native]
+            .into_iter()
+            .find(|backend| backend.name() == name)
+    }
+
+    /// Extension of the whole videos this backend writes, native chapters are `SPLIT_EXTENSION`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DownloadBackend::YtDlp => "mp3",
+            DownloadBackend::Native => "m4a",
+        }
+    }
+}
+
+/// Chapters split from a native download keep their AAC frames as they are, an m4a can't be
+/// cut up without rebuilding its index
+pub const SPLIT_EXTENSION: &str = "aac";
+/// What a native download is saved as while it's being split into chapters
+const SPLIT_SOURCE: &str = "video";
+
+// How many results a sorted search is sorted from, paging stops there
+const SORTED_RESULTS: u32 = 5 * SEARCH_PAGE_SIZE;
+
+static DOWNLOAD_BACKEND: RwLock<Option<DownloadBackend>> = RwLock::new(None);
+
+/// The backend used for YouTube downloads. Unless set with `set_download_backend` it comes from
+/// AUDIODY_YOUTUBE_BACKEND, and defaults to native on Android where yt-dlp can't run.
+pub fn download_backend() -> DownloadBackend {
+    if let Some(backend) = *DOWNLOAD_BACKEND.read().unwrap() {
+        return backend;
+    }
+    std::env::var("AUDIODY_YOUTUBE_BACKEND")
+        .ok()
+        .and_then(|name| DownloadBackend::from_name(&name))
+        .unwrap_or(if cfg!(target_os = "android") {
+            DownloadBackend::Native
+        } else {
+            DownloadBackend::YtDlp
+        })
+}
+
+/// Switches the YouTube download backend, downloads that already started keep their backend.
+pub fn set_download_backend(backend: DownloadBackend) {
+    log::info!("Downloading YouTube audio with {}", backend.name());
+    *DOWNLOAD_BACKEND.write().unwrap() = Some(backend);
+}
+
 #[derive(Debug, Clone)]
 pub struct YouTubeClient {
     youtube: YouTube,
@@ -30,63 +108,289 @@ impl YouTubeClient {
 
 // Find a way to improve the image quality!
 impl YouTubeClient {
-    pub async fn search(&self, query: String) -> Result<Vec<Book>, ureq::Error> {
-        let results = self
+    pub async fn search(&self, query: &SearchQuery) -> Result<Vec<Book>, ureq::Error> {
+        // rusty_ytdl does its own requests, so cache the books instead of the pages
+        let cache_key = format!("youtube:search:{:?}", query);
+        if let Some(books) = cache::load(&cache_key, cache::SEARCH_TTL) {
+            return Ok(books);
+        }
+
+        // YouTube has no paging, so ask for everything up to this page and skip the rest. A
+        // sorted search always asks for the same results so every page is sorted alike.
+        let limit = match query.sort_order() {
+            SortOrder::Title | SortOrder::Popularity => SORTED_RESULTS,
+            SortOrder::Relevance | SortOrder::Date => (query.page() + 1) * SEARCH_PAGE_SIZE,
+        };
+        let options = SearchOptions {
+            limit: limit as u64,
+            // Playlists too, lots of audiobooks are uploaded one video per chapter
+            search_type: SearchType::All,
+            ..Default::default()
+        };
+        let mut results = match self
             .youtube
-            .search(query + " audiobook", None)
+            .search(query.text() + " audiobook", Some(&options))
             .await
-            .unwrap();
+        {
+            Ok(results) => results,
+            Err(e) => {
+                return cache::load_stale(&cache_key)
+                    .ok_or_else(|| io::Error::other(e.to_string()).into())
+            }
+        };
+
+        match query.sort_order() {
+            SortOrder::Popularity => results.sort_by_key(|result| match result {
+                SearchResult::Video(video) => std::cmp::Reverse(video.views),
+                SearchResult::Playlist(playlist) => std::cmp::Reverse(playlist.views),
+                _ => std::cmp::Reverse(0),
+            }),
+            SortOrder::Title => results.sort_by_key(|result| match result {
+                SearchResult::Video(video) => video.title.to_lowercase(),
+                SearchResult::Playlist(playlist) => playlist.name.to_lowercase(),
+                _ => String::new(),
+            }),
+            SortOrder::Relevance | SortOrder::Date => {}
+        }
 
         let books: Vec<Book> = results
             .iter()
+            .skip((query.page() * SEARCH_PAGE_SIZE) as usize)
+            .take(SEARCH_PAGE_SIZE as usize)
             .filter_map(|result| {
                 match result {
                     SearchResult::Video(video) => Some(Book {
                         title: video.title.clone(),
                         author: video.channel.name.clone(),
-                        image_URL: video.thumbnails.first().unwrap().url.clone(),
+                        image_URL: video
+                            .thumbnails
+                            .first()
+                            .map(|thumbnail| thumbnail.url.clone())
+                            .unwrap_or_default(),
                         url: video.url.clone(),
                         description: video.description.clone(),
                         saved: false,
+                        chapter_titles: vec![video.title.clone()],
                         chapter_urls: vec![video.url.clone()],
                         chapter_durations: vec![video.duration.to_string()],
                         chapter_reader: vec![video.channel.name.clone()],
                     }),
-                    _ => None, // Skip playlists and channels
+                    // Chapters get filled in by get_book, search results don't list the videos
+                    SearchResult::Playlist(playlist) => Some(Book {
+                        title: playlist.name.clone(),
+                        author: playlist.channel.name.clone(),
+                        image_URL: playlist
+                            .thumbnails
+                            .first()
+                            .map(|thumbnail| thumbnail.url.clone())
+                            .unwrap_or_default(),
+                        url: playlist.url.clone(),
+                        description: "".to_string(),
+                        saved: false,
+                        chapter_titles: vec![],
+                        chapter_urls: vec![],
+                        chapter_durations: vec![],
+                        chapter_reader: vec![],
+                    }),
+                    _ => None, // Skip channels
                 }
             })
+            .filter(|book| query.matches(book))
             .collect();
 
+
+        cache::store(&cache_key, &books);
         Ok(books)
     }
     pub async fn get_book(&self, url: String) -> Result<Book, ureq::Error> {
-        let video = Video::new(url.clone()).unwrap();
+        let cache_key = format!("youtube:book:{}", url);
+        if let Some(book) = cache::load(&cache_key, cache::BOOK_TTL) {
+            return Ok(book);
+        }
+        if is_playlist_url(&url) {
+            return match self.get_playlist(url).await {
+                Ok(book) => {
+                    cache::store(&cache_key, &book);
+                    Ok(book)
+                }
+                Err(e) => cache::load_stale(&cache_key).ok_or(e),
+            };
+        }
 
-        let video_info: rusty_ytdl::VideoInfo = video.get_info().await.unwrap();
+        let video = Video::new(url.clone()).map_err(|e| io::Error::other(e.to_string()))?;
 
-        Ok(Book {
+        let video_info: rusty_ytdl::VideoInfo = match video.get_info().await {
+            Ok(video_info) => video_info,
+            Err(e) => {
+                return cache::load_stale(&cache_key)
+                    .ok_or_else(|| io::Error::other(e.to_string()).into())
+            }
+        };
+
+        let offsets = chapter_offsets(&video_info);
+        let book = Book {
             saved: false,
+            // Videos without chapters are one long chapter
+            chapter_titles: if video_info.video_details.chapters.is_empty() {
+                vec![video_info.video_details.title.clone()]
+            } else {
+                video_info
+                    .video_details
+                    .chapters
+                    .iter()
+                    .map(|chapter| chapter.title.clone())
+                    .collect()
+            },
             title: video_info.video_details.title,
-            chapter_urls: video_info
-                .video_details
-                .chapters
+            chapter_urls: offsets
                 .iter()
-                .map(|capt| video_info.video_details.video_url.to_string())
+                .map(|_| video_info.video_details.video_url.to_string())
                 .collect(),
-            chapter_durations: video_info
-                .video_details
-                .chapters
+            chapter_durations: offsets
                 .iter()
-                .map(|chapter| chapter.start_time.to_string())
+                .map(|offset| format_duration(offset.duration()))
                 .collect(),
             chapter_reader: vec![video_info.video_details.owner_channel_name.clone()],
             description: video_info.video_details.description,
             author: video_info.video_details.owner_channel_name.clone(),
             url,
-            image_URL: video_info.video_details.thumbnails[0].url.clone(),
+            image_URL: video_info
+                .video_details
+                .thumbnails
+                .first()
+                .map(|thumbnail| thumbnail.url.clone())
+                .unwrap_or_default(),
+        };
+        cache::store(&cache_key, &book);
+        Ok(book)
+    }
+
+    /// Where each chapter of a video starts and ends, for playing it from a single file.
+    pub async fn get_chapter_offsets(&self, url: String) -> Result<Vec<ChapterOffset>, ureq::Error> {
+        let cache_key = format!("youtube:offsets:{}", url);
+        if let Some(offsets) = cache::load(&cache_key, cache::BOOK_TTL) {
+            return Ok(offsets);
+        }
+
+        let video = Video::new(url).map_err(|e| io::Error::other(e.to_string()))?;
+        let video_info = match video.get_info().await {
+            Ok(video_info) => video_info,
+            Err(e) => {
+                return cache::load_stale(&cache_key)
+                    .ok_or_else(|| io::Error::other(e.to_string()).into())
+            }
+        };
+        let offsets = chapter_offsets(&video_info);
+        cache::store(&cache_key, &offsets);
+        Ok(offsets)
+    }
+
+    /// Downloads the whole video as one audio file, chapters are then played from offsets in it.
+    /// `stem` is the file name without extension, which depends on the download backend.
+    pub fn get_book_audio(
+        &self,
+        url: String,
+        path: String,
+        stem: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let backend = download_backend();
+        let file = PathBuf::from(&path).join(format!("{}.{}", stem, backend.extension()));
+        if backend == DownloadBackend::Native {
+            download_native(&url, &file)?;
+            return Ok(file);
+        }
+
+        binaries::run(
+            Tool::YtDlp,
+            [
+                "--concurrent-fragments",
+                "5",
+                "--extract-audio",
+                "--audio-format",
+                "mp3",
+                "--no-playlist",
+                "-P",
+                &path,
+                "-o",
+                &format!("{}.%(ext)s", stem),
+                &url,
+            ],
+        )?;
+
+        Ok(file)
+    }
+
+    /// Maps a playlist to a book where every video is a chapter.
+    async fn get_playlist(&self, url: String) -> Result<Book, ureq::Error> {
+        let options = PlaylistSearchOptions {
+            fetch_all: true,
+            ..Default::default()
+        };
+        let mut playlist = Playlist::get(url.clone(), Some(&options))
+            .await
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        playlist.fetch(None).await;
+
+        Ok(Book {
+            saved: false,
+            title: playlist.name.clone(),
+            chapter_titles: playlist.videos.iter().map(|video| video.title.clone()).collect(),
+            chapter_urls: playlist.videos.iter().map(|video| video.url.clone()).collect(),
+            chapter_durations: playlist
+                .videos
+                .iter()
+                .map(|video| format_duration(video.duration as f64 / 1000.0))
+                .collect(),
+            chapter_reader: playlist
+                .videos
+                .iter()
+                .map(|video| video.channel.name.clone())
+                .collect(),
+            description: "".to_string(),
+            author: playlist.channel.name.clone(),
+            url,
+            image_URL: playlist
+                .thumbnails
+                .first()
+                .map(|thumbnail| thumbnail.url.clone())
+                .unwrap_or_default(),
         })
     }
 
+    /// Downloads a single video of a playlist book as the audio for `chapter`. The file is
+    /// numbered from 1 like the ones `--split-chapters` makes.
+    pub fn get_playlist_item(
+        &self,
+        url: String,
+        path: String,
+        chapter: i32,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let file_name = format!("chapter_{}", chapter + 1);
+        let backend = download_backend();
+        let file = PathBuf::from(&path).join(format!("{}.{}", file_name, backend.extension()));
+        if backend == DownloadBackend::Native {
+            download_native(&url, &file)?;
+            return Ok(file);
+        }
+
+        binaries::run(
+            Tool::YtDlp,
+            [
+                "--extract-audio",
+                "--audio-format",
+                "mp3",
+                "--no-playlist", // Item links carry the playlist id too
+                "-P",
+                &path,
+                "-o",
+                &format!("{}.%(ext)s", file_name),
+                &url,
+            ],
+        )?;
+
+        Ok(file)
+    }
+
     // We should have two one for specific chapter and one for the whole book
     pub fn get_chapter(
         &self,
@@ -94,14 +398,14 @@ impl YouTubeClient {
         path: String,
         chapter: i32,
     ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        if download_backend() == DownloadBackend::Native {
+            return self.get_chapter_native(&url, Path::new(&path), chapter);
+        }
         let video = Video::new(url.clone()).unwrap();
 
         let video_info: rusty_ytdl::VideoInfo =
             Runtime::new().unwrap().block_on(video.get_info()).unwrap();
 
-        let libraries_dir = PathBuf::from("libs");
-        let youtube = libraries_dir.join("yt-dlp");
-
         // For some reason it takes so much longer to download a single chapter
 
     
@@ -118,29 +422,27 @@ impl YouTubeClient {
         }
         
         if chapter_urls.len() == 0 {
-            let _ = Command::new(youtube)
-            .args([
-                "--concurrent-fragments",
-                "5",               // Number of fragments to download concurrently
-                "--extract-audio", // Extract audio only
-                "--audio-format",
-                "mp3",              // Set audio format to mp3
-                "--write-auto-sub", // Download auto-generated subtitles
-                "--sub-lang",
-                "en",                   // Set subtitle language to English
-                "--split-chapters",     // Split the video into chapters
-                "--restrict-filenames", // Ensure filenames are safe and consistent
-                "--write-thumbnail",    // Download the thumbnail
-                "-P",
-                &path, // Output directory
-                "-o",
-                "chapter_%(section_number)s.%(ext)s", // Output filename template
-                &url,                                 // Video URL
-            ])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .output()
-            .expect("failed to execute process");
+            binaries::run(
+                Tool::YtDlp,
+                [
+                    "--concurrent-fragments",
+                    "5",               // Number of fragments to download concurrently
+                    "--extract-audio", // Extract audio only
+                    "--audio-format",
+                    "mp3",              // Set audio format to mp3
+                    "--write-auto-sub", // Download auto-generated subtitles
+                    "--sub-lang",
+                    "en",                   // Set subtitle language to English
+                    "--split-chapters",     // Split the video into chapters
+                    "--restrict-filenames", // Ensure filenames are safe and consistent
+                    "--write-thumbnail",    // Download the thumbnail
+                    "-P",
+                    &path, // Output directory
+                    "-o",
+                    "chapter_%(section_number)s.%(ext)s", // Output filename template
+                    &url,                                 // Video URL
+                ],
+            )?;
             
             for item in fs::read_dir(path.clone())? {
                 let item = item?;
@@ -153,6 +455,270 @@ impl YouTubeClient {
                 }
             }
         }
-        Ok(PathBuf::from(path).join(chapter_urls.get(chapter as usize).unwrap()))
+        let chapter_file = chapter_urls
+            .get(chapter as usize)
+            .ok_or("yt-dlp didn't produce that chapter")?;
+        Ok(PathBuf::from(path).join(chapter_file))
+    }
+
+    // Downloads the whole video once and splits it up, so the chapters after this one are
+    // there straight away too
+    fn get_chapter_native(
+        &self,
+        url: &str,
+        path: &Path,
+        chapter: i32,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let chapter_file =
+            |index: usize| path.join(format!("chapter_{}.{}", index, SPLIT_EXTENSION));
+        let file = chapter_file(chapter.max(0) as usize);
+        if !file.exists() {
+            let offsets = Runtime::new()?.block_on(self.get_chapter_offsets(url.to_string()))?;
+            let extension = DownloadBackend::Native.extension();
+            let video = path.join(format!("{}.{}", SPLIT_SOURCE, extension));
+            download_native(url, &video)?;
+            let split = split_native(&video, &offsets, chapter_file);
+            let _ = fs::remove_file(&video);
+            split?;
+        }
+        if !file.exists() {
+            return Err("The video doesn't have that chapter".into());
+        }
+        Ok(file)
+    }
+}
+
+// Saves the best AAC audio stream of a video as is. rodio plays m4a directly, so unlike
+// yt-dlp there's nothing to convert and no ffmpeg needed.
+fn download_native(url: &str, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let options = VideoOptions {
+        quality: VideoQuality::HighestAudio,
+        // The opus/webm streams can't be decoded, mp4 audio is AAC
+        filter: VideoSearchOptions::Custom(Arc::new(|format| {
+            format.has_audio && !format.has_video && format.mime_type.container == "mp4"
+        })),
+        ..Default::default()
+    };
+    let video = Video::new_with_options(url, options)?;
+
+    log::info!("Downloading {} to {}", url, file.display());
+    // Only give the file its real name once it's complete, a half download isn't playable
+    let part = part_file(file);
+    // The stream can't be picked up where it stopped, so what there is of it is no use
+    if let Err(e) = Runtime::new()?.block_on(video.download(&part)) {
+        let _ = fs::remove_file(&part);
+        return Err(e.into());
+    }
+    fs::rename(part, file)?;
+    Ok(())
+}
+
+// Cuts a native download into a file per chapter. The AAC frames are copied over as they are,
+// each behind an ADTS header so the chapters can be played on their own.
+fn split_native(
+    file: &Path,
+    offsets: &[ChapterOffset],
+    chapter_file: impl Fn(usize) -> PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = MediaSourceStream::new(Box::new(fs::File::open(file)?), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(DownloadBackend::Native.extension());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format.default_track().ok_or("The download has no audio")?;
+    let track_id = track.id;
+    let config = adts_config(track.codec_params.extra_data.as_deref().unwrap_or_default())?;
+    let time_base = track.codec_params.time_base.ok_or("The download has no time base")?;
+
+    let packets = std::iter::from_fn(|| loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() != track_id => continue,
+            Ok(packet) => {
+                let time = time_base.calc_time(packet.ts());
+                return Some(Ok((time.seconds as f64 + time.frac, packet.data)));
+            }
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return None
+            }
+            Err(e) => return Some(Err(e)),
+        }
+    });
+    write_chapters(packets, offsets, config, chapter_file)
+}
+
+// Writes AAC frames, each with the time it starts at, to the file of the chapter it's in
+fn write_chapters<E: std::error::Error + 'static>(
+    packets: impl Iterator<Item = Result<(f64, Box<[u8]>), E>>,
+    offsets: &[ChapterOffset],
+    config: (u8, u8, u8),
+    chapter_file: impl Fn(usize) -> PathBuf,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Written to a part file first like any download, the chapter gets its name once it's whole
+    let finish = |(file, mut writer): (PathBuf, BufWriter<fs::File>)| -> io::Result<()> {
+        writer.flush()?;
+        fs::rename(part_file(&file), file)
+    };
+    let mut chapter = 0;
+    let mut output: Option<(PathBuf, BufWriter<fs::File>)> = None;
+    for packet in packets {
+        let (seconds, data) = packet?;
+        // Each chapter runs until the next one starts
+        while offsets
+            .get(chapter + 1)
+            .is_some_and(|next| seconds >= next.start)
+        {
+            chapter += 1;
+            if let Some(done) = output.take() {
+                finish(done)?;
+            }
+        }
+        let (_, writer) = match &mut output {
+            Some(output) => output,
+            None => {
+                let file = chapter_file(chapter);
+                let writer = BufWriter::new(fs::File::create(part_file(&file))?);
+                output.insert((file, writer))
+            }
+        };
+        writer.write_all(&adts_header(config, data.len()))?;
+        writer.write_all(&data)?;
+    }
+    if let Some(done) = output {
+        finish(done)?;
+    }
+    Ok(())
+}
+
+// Object type, sample rate index and channel layout from the AudioSpecificConfig of an mp4
+// track, which is all an ADTS header needs
+fn adts_config(config: &[u8]) -> Result<(u8, u8, u8), Box<dyn std::error::Error>> {
+    let [first, second, ..] = *config else {
+        return Err("The download doesn't say what its audio is".into());
+    };
+    let object_type = first >> 3;
+    let rate_index = ((first & 0x07) << 1) | (second >> 7);
+    let channels = (second >> 3) & 0x0f;
+    // Only plain AAC-LC fits in ADTS, and that's what YouTube serves
+    if object_type != 2 || rate_index > 12 {
+        return Err(format!("Can't split AAC of object type {}", object_type).into());
+    }
+    Ok((object_type, rate_index, channels))
+}
+
+fn adts_header((object_type, rate_index, channels): (u8, u8, u8), len: usize) -> [u8; 7] {
+    // The length counts the header too
+    let frame_len = len + 7;
+    [
+        0xff,
+        // MPEG-4, no CRC
+        0xf1,
+        ((object_type - 1) << 6) | (rate_index << 2) | (channels >> 2),
+        ((channels & 0x03) << 6) | (frame_len >> 11) as u8,
+        (frame_len >> 3) as u8,
+        ((frame_len & 0x07) << 5) as u8 | 0x1f,
+        // Buffer fullness all ones for variable bitrate, and one frame
+        0xfc,
+    ]
+}
+
+/// Whether a YouTube URL points at a whole playlist rather than a single video.
+pub fn is_playlist_url(url: &str) -> bool {
+    url.contains("/playlist?") || (url.contains("list=") && !url.contains("v="))
+}
+
+// Each chapter runs until the next one starts, the last one until the end of the video
+fn chapter_offsets(video_info: &rusty_ytdl::VideoInfo) -> Vec<ChapterOffset> {
+    let length = video_info
+        .video_details
+        .length_seconds
+        .parse::<f64>()
+        .unwrap_or_default();
+    let starts: Vec<f64> = match video_info.video_details.chapters.as_slice() {
+        [] => vec![0.0],
+        chapters => chapters.iter().map(|chapter| chapter.start_time as f64).collect(),
+    };
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| ChapterOffset {
+            start: *start,
+            end: starts.get(i + 1).copied().unwrap_or(length.max(*start)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AAC-LC at 44.1kHz in stereo, like YouTube's m4a audio
+    const AAC_LC: [u8; 2] = [0x12, 0x10];
+
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audiody-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // The frames symphonia finds in an ADTS file, along with the track it describes
+    fn read_adts(file: &Path) -> (Vec<Box<[u8]>>, Option<u32>, Option<usize>) {
+        let file = Box::new(fs::File::open(file).unwrap());
+        let stream = MediaSourceStream::new(file, Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension(SPLIT_EXTENSION);
+        let mut format = symphonia::default::get_probe()
+            .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+            .unwrap()
+            .format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let frames = std::iter::from_fn(|| format.next_packet().ok().map(|packet| packet.data))
+            .collect();
+        (frames, params.sample_rate, params.channels.map(|channels| channels.count()))
+    }
+
+    #[test]
+    fn chapters_get_the_frames_between_their_offsets() {
+        let dir = test_dir("split-native");
+        let offsets = [
+            ChapterOffset { start: 0.0, end: 2.0 },
+            ChapterOffset { start: 2.0, end: 5.0 },
+        ];
+        // A frame every half second, each one a different length so they can be told apart
+        let packets = (0..10).map(|i| {
+            Ok::<_, io::Error>((i as f64 * 0.5, vec![i as u8; 10 + i].into_boxed_slice()))
+        });
+        let chapter_file =
+            |index: usize| dir.join(format!("chapter_{}.{}", index, SPLIT_EXTENSION));
+
+        write_chapters(packets, &offsets, adts_config(&AAC_LC).unwrap(), chapter_file).unwrap();
+
+        let (first, sample_rate, channels) = read_adts(&chapter_file(0));
+        assert_eq!(sample_rate, Some(44100));
+        assert_eq!(channels, Some(2));
+        assert_eq!(first.len(), 4);
+        assert_eq!(&*first[3], &[3; 13]);
+        let (second, _, _) = read_adts(&chapter_file(1));
+        assert_eq!(second.len(), 6);
+        assert_eq!(&*second[0], &[4; 14]);
+        // Nothing is left behind half written
+        assert!(!part_file(&chapter_file(0)).exists());
+        assert!(!part_file(&chapter_file(1)).exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn only_aac_lc_can_be_split() {
+        // HE-AAC, which ADTS has no way of saying
+        assert!(adts_config(&[0x2b, 0x92]).is_err());
+        assert!(adts_config(&[]).is_err());
+        assert_eq!(adts_config(&AAC_LC).unwrap(), (2, 4, 2));
     }
 }
@@ -1,16 +1,26 @@
-use rodio::{Decoder, OutputStream, Sink, Source};
+use rodio::{Decoder, OutputStream, Sink};
 use std::fs::File;
 use std::io::BufReader;
+use std::path::Path;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use tokio::time::error::Elapsed;
 
+use crate::api::types::ChapterOffset;
+use crate::storage::library;
+
 #[derive(Clone)]
 pub struct AudioService {
     stream_handle: Arc<Mutex<Option<rodio::OutputStreamHandle>>>,
     sink: Arc<Mutex<Option<Sink>>>,
     command_tx: mpsc::Sender<AudioCommand>,
     playback_distance: Arc<Mutex<u64>>,
+    /// Chapters inside the playing file, empty when every chapter is its own file
+    chapter_offsets: Arc<Mutex<Vec<ChapterOffset>>>,
+    /// How far the skip buttons jump back and forward, in seconds
+    skip_intervals: Arc<Mutex<(u32, u32)>>,
+    /// The file lined up after the playing one, so it doesn't get lined up twice
+    queued: Arc<Mutex<Option<String>>>,
 }
 
 // Commands for audio control
@@ -112,19 +122,78 @@ impl AudioService {
             sink,
             command_tx,
             playback_distance,
+            chapter_offsets: Arc::new(Mutex::new(vec![])),
+            skip_intervals: Arc::new(Mutex::new((10, 10))),
+            queued: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Plays `path` once the current file ends, unless it's lined up already.
     pub fn queue(&self, path: String) {
+        let mut queued = self.queued.lock().unwrap();
+        if queued.as_ref() == Some(&path) {
+            return;
+        }
+        *queued = Some(path.clone());
         // Send a signal to the audio thread to pause
         self.command_tx.send(AudioCommand::Queue(path)).unwrap();
     }
 
     pub fn start(&self, path: String) {
+        *self.chapter_offsets.lock().unwrap() = vec![];
+        *self.queued.lock().unwrap() = None;
         // Send a signal to the audio thread to pause
         self.command_tx.send(AudioCommand::Start(path)).unwrap();
     }
 
+    /// Starts a book kept as one file `position` seconds into `chapter`. Positions and seeking
+    /// are then relative to whichever chapter is playing.
+    pub fn start_chapters(
+        &self,
+        path: String,
+        offsets: Vec<ChapterOffset>,
+        chapter: usize,
+        position: f32,
+    ) {
+        let start = offsets.get(chapter).map(|offset| offset.start).unwrap_or_default();
+        *self.chapter_offsets.lock().unwrap() = offsets;
+        *self.queued.lock().unwrap() = None;
+        self.command_tx.send(AudioCommand::Start(path)).unwrap();
+        self.command_tx
+            .send(AudioCommand::Seek(start as f32 + position))
+            .unwrap();
+    }
+
+    /// The chapter playing in a book kept as one file, None for a file per chapter.
+    pub fn current_chapter(&self) -> Option<usize> {
+        let offsets = self.chapter_offsets.lock().unwrap();
+        if offsets.is_empty() {
+            return None;
+        }
+        let pos = self.get_file_pos() as f64;
+        Some(
+            offsets
+                .iter()
+                .rposition(|offset| pos >= offset.start)
+                .unwrap_or(0),
+        )
+    }
+
+    /// Length of the chapter playing in a book kept as one file.
+    pub fn current_chapter_len(&self) -> Option<f32> {
+        let chapter = self.current_chapter()?;
+        let offsets = self.chapter_offsets.lock().unwrap();
+        offsets.get(chapter).map(|offset| offset.duration() as f32)
+    }
+
+    // Where the current chapter starts in the file, 0 for a file per chapter
+    fn chapter_start(&self) -> f32 {
+        match self.current_chapter() {
+            Some(chapter) => self.chapter_offsets.lock().unwrap()[chapter].start as f32,
+            None => 0.0,
+        }
+    }
+
     pub fn pause(&self) {
         // Send a signal to the audio thread to pause
         self.command_tx.send(AudioCommand::Pause).unwrap();
@@ -147,10 +216,24 @@ impl AudioService {
             .unwrap();
     }
 
+    pub fn set_skip_intervals(&self, back: u32, forward: u32) {
+        *self.skip_intervals.lock().unwrap() = (back, forward);
+    }
+
+    pub fn skip_backward(&self) {
+        let (back, _) = *self.skip_intervals.lock().unwrap();
+        self.seek_relative(-(back as i64));
+    }
+
+    pub fn skip_forward(&self) {
+        let (_, forward) = *self.skip_intervals.lock().unwrap();
+        self.seek_relative(forward as i64);
+    }
+
     pub fn seek(&self, seconds: f32) {
         // Send a signal to the audio thread to set the speed
         self.command_tx
-            .send(AudioCommand::Seek(seconds))
+            .send(AudioCommand::Seek(self.chapter_start() + seconds))
             .unwrap();
     }
 
@@ -161,15 +244,19 @@ impl AudioService {
             .unwrap();
     }
 
-    pub fn get_chapter_len(&self, chapter_path: &str) -> std::time::Duration {
+    /// Length of a chapter file, None when it can't be read or the decoder can't tell.
+    pub fn get_chapter_len(&self, chapter_path: &str) -> Option<std::time::Duration> {
         log::info!("Getting chapter length for: {}", chapter_path);
-        let file = std::fs::File::open(chapter_path).unwrap();
-        let source = Decoder::new(BufReader::new(file)).unwrap();
-        let duration: std::time::Duration = source.total_duration().unwrap();
-        duration
+        // Raw AAC chapters don't say how long they are, the library counts them out
+        library::duration(Path::new(chapter_path)).map(std::time::Duration::from_secs_f64)
     }
 
+    /// Position in the current chapter.
     pub fn get_current_pos(&self) -> f32 {
+        self.get_file_pos() - self.chapter_start()
+    }
+
+    fn get_file_pos(&self) -> f32 {
         if let Some(sink) = self.sink.lock().unwrap().as_ref() {
            sink.get_pos().as_secs_f32()
         } else {
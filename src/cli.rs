@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+
+use crate::storage::binaries::{self, Tool};
+use crate::storage::export::export_m4b;
+use crate::storage::import::{import, ImportMode};
+use crate::storage::library;
+use crate::storage::opml::{export_opml, import_opml};
+use crate::storage::subscriptions::{
+    feed_settings, get_subscriptions, set_feed_settings, unsubscribe, EpisodeOrder, FeedSettings,
+};
+
+const USAGE: &str = "Usage:
+    audiody                         Start the app
+    audiody import-opml <file>      Subscribe to the feeds in an OPML file
+    audiody export-opml <file>      Write the library and subscriptions to an OPML file
+    audiody rescan                  Rebuild the library index from the music folder
+    audiody move-library <dir>      Move every saved book to another folder
+    audiody import <path> [--in-place]
+                                    Add a folder or file of audio to the library, copying it
+                                    unless --in-place is given
+    audiody export <book> [path]    Write a saved book as one .m4b with chapter markers, to
+                                    path or the Audiody folder in Downloads
+    audiody subscriptions           List the subscribed feeds and how they are kept
+    audiody feed-settings <book> [oldest-first|newest-first] [keep=<n>|keep=all] [auto=<n>]
+                                    Change the episode order of a feed, how many episodes to
+                                    keep and how many new ones to download on every refresh
+    audiody unsubscribe <book>      Stop refreshing a feed, downloaded episodes are kept
+    audiody binaries                List the installed yt-dlp and ffmpeg
+    audiody update-binaries [ver]   Update yt-dlp (pinning it to ver if given) and ffmpeg
+    audiody set-binary <tool> <path>
+                                    Use your own yt-dlp or ffmpeg instead of a downloaded one
+
+Set AUDIODY_YOUTUBE_BACKEND=native to download YouTube audio without yt-dlp and ffmpeg.";
+
+/// Runs a command line action if one was given. Returns None when the app should start as usual.
+pub fn run(args: &[String]) -> Option<Result<(), Box<dyn std::error::Error>>> {
+    let command = args.first()?;
+    let _ = env_logger::try_init();
+
+    let result = match (command.as_str(), args.get(1)) {
+        ("import-opml", Some(file)) => import_opml(&PathBuf::from(file)).map(|books| {
+            for book in &books {
+                println!("Subscribed to {}", book);
+            }
+            println!("Imported {} feeds", books.len());
+        }),
+        ("export-opml", Some(file)) => export_opml(&PathBuf::from(file)).map(|count| {
+            println!("Exported {} books to {}", count, file);
+        }),
+        ("move-library", Some(dir)) => {
+            let mut last_percent = None;
+            library::move_library(&PathBuf::from(dir), |copied, total| {
+                let percent = (copied * 100).checked_div(total).unwrap_or(100);
+                if last_percent != Some(percent) {
+                    last_percent = Some(percent);
+                    println!("{}%", percent);
+                }
+            })
+            .map(|count| println!("Moved {} books to {}", count, dir))
+        }
+        ("import", Some(path)) => {
+            let mode = match args.get(2).map(|arg| arg.as_str()) {
+                Some("--in-place") => ImportMode::InPlace,
+                _ => ImportMode::Copy,
+            };
+            import(&PathBuf::from(path), mode).map(|book| println!("Imported {} as {}", path, book))
+        }
+        ("export", Some(book)) => {
+            let mut last_percent = None;
+            let target = args.get(2).map(PathBuf::from);
+            export_m4b(book, target.as_deref(), |done, total| {
+                let percent = ((done * 100.0 / total) as u64).min(100);
+                if total > 0.0 && last_percent != Some(percent) {
+                    last_percent = Some(percent);
+                    println!("{}%", percent);
+                }
+            })
+            .map(|file| println!("Exported {} to {}", book, file.display()))
+        }
+        ("subscriptions", _) => get_subscriptions().map(|subscriptions| {
+            for (book, settings) in subscriptions {
+                let feed = settings.feed.unwrap_or_default();
+                println!("{}  {}\n    {}", book, settings.title, describe_feed(&feed));
+            }
+        }),
+        ("feed-settings", Some(book)) => change_feed_settings(book, &args[2..]),
+        ("unsubscribe", Some(book)) => {
+            unsubscribe(book).map(|_| println!("Unsubscribed from {}", book))
+        }
+        ("rescan", _) => library::rescan().map(|count| {
+            println!("Found {} books", count);
+        }),
+        ("binaries", _) => {
+            for tool in Tool::ALL {
+                match binaries::installed(tool) {
+                    Some(installed) => println!(
+                        "{} {} at {}{}",
+                        tool.name(),
+                        installed.version,
+                        installed.path.display(),
+                        if installed.user_provided { " (yours)" } else { "" }
+                    ),
+                    None => println!("{} is not installed", tool.name()),
+                }
+            }
+            Ok(())
+        }
+        ("update-binaries", version) => tokio::runtime::Runtime::new()
+            .map_err(|e| e.into())
+            .and_then(|runtime| runtime.block_on(binaries::ensure_installed()))
+            .and_then(|_| binaries::update(Tool::YtDlp, version.map(|v| v.as_str())))
+            .and_then(|_| binaries::update(Tool::Ffmpeg, None))
+            .map(|_| println!("Binaries are up to date")),
+        ("set-binary", Some(tool)) => match (Tool::from_name(tool), args.get(2)) {
+            (Some(tool), Some(path)) => binaries::set_user_path(tool, &PathBuf::from(path))
+                .map(|installed| println!("Using {} {}", tool.name(), installed.version)),
+            _ => Err("Usage: audiody set-binary <yt-dlp|ffmpeg> <path>".into()),
+        },
+        ("help" | "--help" | "-h", _) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("Unknown command: {}\n{}", args.join(" "), USAGE).into()),
+    };
+    Some(result)
+}
+
+fn describe_feed(feed: &FeedSettings) -> String {
+    let order = match feed.order {
+        EpisodeOrder::OldestFirst => "oldest first",
+        EpisodeOrder::NewestFirst => "newest first",
+    };
+    let retention = match feed.retention {
+        Some(count) => format!("keeping {} episodes", count),
+        None => "keeping every episode".to_string(),
+    };
+    format!(
+        "{}, {}, downloading {} new on refresh",
+        order, retention, feed.auto_download
+    )
+}
+
+// Whatever isn't given stays as it is
+fn change_feed_settings(book: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut feed = feed_settings(book)?;
+    for arg in args {
+        match arg.split_once('=') {
+            None if arg == "oldest-first" => feed.order = EpisodeOrder::OldestFirst,
+            None if arg == "newest-first" => feed.order = EpisodeOrder::NewestFirst,
+            Some(("keep", "all")) => feed.retention = None,
+            Some(("keep", count)) => feed.retention = Some(count.parse()?),
+            Some(("auto", count)) => feed.auto_download = count.parse()?,
+            _ => return Err(format!("Unknown feed setting: {}\n{}", arg, USAGE).into()),
+        }
+    }
+    set_feed_settings(book, feed.order, feed.retention, feed.auto_download)?;
+    println!("{} is now {}", book, describe_feed(&feed));
+    Ok(())
+}
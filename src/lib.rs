@@ -2,23 +2,40 @@ use api::webapi;
 use audio::audios::AudioService;
 use slint::{ComponentHandle, Model};
 use std::path;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self};
 use std::{path::PathBuf, vec};
 
 pub mod api;
 pub mod audio;
+pub mod cli;
 pub mod storage;
 
-use api::{webapi::WebApiClient, webimage::url_to_buffer};
-use storage::save::{download_audio, get_progress, save_progress, settings};
-use storage::saved::{check_book_chapter_url, extract_number, get_saved_book};
+use api::types::{AudiodyError, Genre, Provider, SearchQuery, SortOrder};
+use api::webapi::SEARCH_TIMEOUT;
+use api::yt::{download_backend, is_playlist_url, DownloadBackend};
+use api::{rss::is_feed_url, webapi::WebApiClient, webimage::{load_cover, placeholder_cover}};
+use storage::binaries;
+use storage::config::{self, AppConfig, Theme};
+use storage::library::{self, add_book, book_dir, book_id};
+use storage::covers::CoverSize;
+use storage::downloads::{DownloadManager, DownloadPolicy, JobStatus, DEFAULT_WORKERS};
+use storage::save::{
+    book_progress, flush_progress, get_chapter_offsets, get_progress, mark_listened,
+    save_progress, set_chapter_offsets, set_finished, settings, single_file_path,
+};
+use storage::saved::{check_book_chapter_url, clean_stale_partials, get_saved_book};
 use storage::saved::get_saved_books;
+use storage::genres::{cached_genres, find_genre};
+use storage::export::export_m4b;
+use storage::import::{import, ImportMode};
+use storage::opml::{export_opml, import_opml};
 use storage::setup::music_dir;
+use storage::subscriptions::{order_book, refresh_all, subscribe, REFRESH_INTERVAL};
 use tokio::runtime::{Handle, Runtime}; // 0.3.5
 
-use yt_dlp::Youtube;
-
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
@@ -33,33 +50,37 @@ pub fn main() {
     #[cfg(target_os = "android")]
     STATE.with(|ui| *ui.borrow_mut() = Some(state));
     tokio::task::block_in_place(|| main_window.run().unwrap());
+    flush_progress();
 }
 
 fn init() -> State {
     #[cfg(all(debug_assertions, target_arch = "wasm32"))]
     console_error_panic_hook::set_once();
 
-    // YT-DLP download should only be ran once
-    // Downloading binaries
-    thread::spawn(|| {
-        // change this to be in the home folder, or the normal place to install scripts!
-        let executables_dir = PathBuf::from("libs");
-        let output_dir = PathBuf::from("output");
-        log::info!("Downloading binaries");
-        Runtime::new()
-            .unwrap()
-            .block_on(Youtube::with_new_binaries(executables_dir, output_dir))
-            .unwrap();
-    });
+    // Only downloads yt-dlp and ffmpeg when they are missing or fail verification.
+    // The native YouTube downloader doesn't need them at all.
+    if download_backend() == DownloadBackend::YtDlp {
+        thread::spawn(|| {
+            if let Err(e) = Runtime::new().unwrap().block_on(binaries::ensure_installed()) {
+                log::error!("Failed to install binaries: {}", e);
+            }
+        });
+    }
 
     let main_window: AppWindow = AppWindow::new().unwrap();
     let audio_state: AudioState<'_> = main_window.global::<AudioState>();
     let audio_service = AudioService::new();
     let webapi_client = WebApiClient::new();
+    let download_manager = DownloadManager::new(DEFAULT_WORKERS);
     let previous_views = Arc::from(Mutex::new(vec![0]));
 
     // Set up the download folder
     music_dir();
+    thread::spawn(|| {
+        if let Err(e) = clean_stale_partials() {
+            log::warn!("Failed to clean up partial downloads: {}", e);
+        }
+    });
 
     handle_ui_actions(
         &main_window,
@@ -67,6 +88,7 @@ fn init() -> State {
         previous_views,
         &audio_service,
         &webapi_client,
+        &download_manager,
     );
 
     State { main_window }
@@ -79,42 +101,61 @@ fn handle_ui_actions(
     previous_views: Arc<Mutex<Vec<i32>>>,
     audio_service: &AudioService,
     webapi_client: &WebApiClient,
+    download_manager: &DownloadManager,
 ) {
     let mut inital_playback_distance: u64 = 0;
+    handle_config(main_window, audio_state, audio_service, webapi_client);
+
     // Get saved books:
     handle_saved_books(main_window);
 
+    handle_feed_refresh(main_window);
+
     handle_previous_page_navigate(main_window, audio_state, Arc::clone(&previous_views));
 
     handle_page_navigate(audio_state, Arc::clone(&previous_views));
 
     handle_search(main_window, audio_state, webapi_client);
 
-    handle_chapter_download_and_play(main_window, audio_state, audio_service);
+    handle_chapter_download_and_play(main_window, audio_state, audio_service, download_manager);
+
+    handle_chapter_download(main_window, audio_state, download_manager);
 
-    handle_chapter_download(main_window, audio_state);
+    handle_downloads(main_window, audio_state, download_manager);
 
     handle_book_view(main_window, audio_state, webapi_client);
 
+    handle_single_file(main_window, audio_state, webapi_client);
+
+    handle_opml(main_window, audio_state);
+
+    handle_library(main_window, audio_state, download_manager);
+
+    handle_import(main_window, audio_state);
+    handle_export(main_window, audio_state);
+    handle_finished(main_window, audio_state);
+
+    handle_genres(main_window, audio_state, webapi_client);
+
     // Playback handles
     handle_playing(main_window, audio_state, audio_service);
 
-    handle_add_queue(main_window, audio_state, audio_service);
+    handle_add_queue(main_window, audio_state, audio_service, download_manager);
 
     handle_pause(main_window, audio_state, audio_service);
 
-    handle_resume(main_window, audio_state, audio_service);
+    handle_resume(main_window, audio_state, audio_service, download_manager);
 
-    handle_play_next(main_window, audio_state, audio_service);
+    handle_play_next(main_window, audio_state, audio_service, download_manager);
 
     let audio_service_clone = audio_service.clone();
     audio_state.on_skip_backward(move || {
-        audio_service_clone.seek_relative(-10);
+        audio_service_clone.skip_backward();
     });
 
     let audio_service_clone = audio_service.clone();
     audio_state.on_skip_forward(move || {
-        audio_service_clone.seek_relative(10);
+        audio_service_clone.skip_forward();
     });
 
     let main_window_weak = main_window.as_weak();
@@ -126,36 +167,151 @@ fn handle_ui_actions(
     });
 }
 
+/// Fills the settings view in from the config and applies it, then saves and applies it again
+/// whenever something is changed there.
+fn handle_config(
+    main_window: &AppWindow,
+    audio_state: &AudioState<'_>,
+    audio_service: &AudioService,
+    webapi_client: &WebApiClient,
+) {
+    let app_config = config::get();
+    audio_state.set_default_speed(app_config.default_speed);
+    audio_state.set_skip_back_secs(app_config.skip_back_secs as i32);
+    audio_state.set_skip_forward_secs(app_config.skip_forward_secs as i32);
+    audio_state.set_theme(app_config.theme.name().into());
+    audio_state.set_tag_downloads(app_config.tag_downloads);
+    for provider in Provider::ALL {
+        let enabled = !app_config.disabled_providers.contains(&provider);
+        set_search_enabled(audio_state, provider, enabled);
+    }
+    if let Ok(dir) = music_dir() {
+        audio_state.set_library_dir(dir.display().to_string().into());
+    }
+    audio_state.invoke_apply_theme();
+
+    // The player starts out at the default speed
+    audio_state.set_speed(app_config.default_speed);
+    audio_service.set_speed(app_config.default_speed);
+    apply_config(&app_config, audio_service, webapi_client);
+    if let Some(problem) = config::load_problem() {
+        audio_state.set_settings_status(problem.into());
+    }
+
+    let main_window_weak = main_window.as_weak();
+    let audio_service = audio_service.clone();
+    let webapi_client = webapi_client.clone();
+    audio_state.on_save_config(move || {
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let audio_state = main_window.global::<AudioState>();
+        let previous_speed = config::get().default_speed;
+        let result = config::update(|app_config| {
+            app_config.default_speed = audio_state.get_default_speed();
+            app_config.skip_back_secs = audio_state.get_skip_back_secs().max(0) as u32;
+            app_config.skip_forward_secs = audio_state.get_skip_forward_secs().max(0) as u32;
+            app_config.theme = Theme::from_name(&audio_state.get_theme());
+            app_config.tag_downloads = audio_state.get_tag_downloads();
+            for provider in Provider::ALL {
+                let enabled = get_search_enabled(&audio_state, provider);
+                app_config.set_provider_enabled(provider, enabled);
+            }
+        });
+        match result {
+            Ok(app_config) => {
+                if app_config.default_speed != previous_speed {
+                    audio_state.set_speed(app_config.default_speed);
+                    audio_service.set_speed(app_config.default_speed);
+                }
+                apply_config(&app_config, &audio_service, &webapi_client);
+            }
+            Err(e) => {
+                log::error!("Failed to save the config: {}", e);
+                audio_state.set_settings_status(format!("Failed to save settings: {}", e).into());
+            }
+        }
+    });
+}
+
+fn apply_config(
+    app_config: &AppConfig,
+    audio_service: &AudioService,
+    webapi_client: &WebApiClient,
+) {
+    audio_service.set_skip_intervals(app_config.skip_back_secs, app_config.skip_forward_secs);
+    webapi_client.set_providers(app_config.enabled_providers());
+}
+
+fn get_search_enabled(audio_state: &AudioState<'_>, provider: Provider) -> bool {
+    match provider {
+        Provider::LibriVox => audio_state.get_search_libi_enabled(),
+        Provider::YouTube => audio_state.get_search_yt_enabled(),
+        Provider::Archive => audio_state.get_search_archive_enabled(),
+    }
+}
+
+fn set_search_enabled(audio_state: &AudioState<'_>, provider: Provider, enabled: bool) {
+    match provider {
+        Provider::LibriVox => audio_state.set_search_libi_enabled(enabled),
+        Provider::YouTube => audio_state.set_search_yt_enabled(enabled),
+        Provider::Archive => audio_state.set_search_archive_enabled(enabled),
+    }
+}
+
 fn handle_saved_books(main_window: &AppWindow) {
-    let saved_books = get_saved_books().unwrap();
+    // The library can be on a drive that isn't plugged in
+    let saved_books = get_saved_books().unwrap_or_else(|e| {
+        log::error!("Failed to load saved books: {}", e);
+        vec![]
+    });
     let main_window_weak = main_window.as_weak();
 
     thread::spawn(move || {
         let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+            let speed = main_window.global::<AudioState>().get_speed();
             let saved_books_converted: Vec<BookItem> = saved_books
                 .into_iter()
                 .map(|book| BookItem {
+                    id: book_id(&book.url, &book.title).into(),
                     title: book.title.into(),
                     author: book.author.into(),
                     description: book.description.clone().into(),
                     book_url: book.url.into(),
                     saved: book.saved,
-                    image: Runtime::new()
-                        .unwrap()
-                        .block_on(url_to_buffer(book.image_URL))
-                        .unwrap(),
+                    image: placeholder_cover(),
+                    image_url: book.image_URL.into(),
+                    chapter_titles: slint::ModelRc::new(slint::VecModel::from(
+                        book.chapter_titles
+                            .into_iter()
+                            .map(|title| title.into())
+                            .collect::<Vec<slint::SharedString>>(),
+                    )),
                     chapter_urls: slint::ModelRc::new(slint::VecModel::from(
                         book.chapter_urls
                             .into_iter()
                             .map(|url| url.into())
                             .collect::<Vec<slint::SharedString>>(),
                     )),
-                    chapter_durations: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                    chapter_reader: slint::ModelRc::new(slint::VecModel::from(vec![])),
+                    chapter_durations: slint::ModelRc::new(slint::VecModel::from(
+                        book.chapter_durations
+                            .into_iter()
+                            .map(|duration| duration.into())
+                            .collect::<Vec<slint::SharedString>>(),
+                    )),
+                    chapter_reader: slint::ModelRc::new(slint::VecModel::from(
+                        book.chapter_reader
+                            .into_iter()
+                            .map(|reader| reader.into())
+                            .collect::<Vec<slint::SharedString>>(),
+                    )),
+                    ..Default::default()
                 })
+                .map(|book_item| with_progress(book_item, speed))
                 .collect();
 
             let books = slint::ModelRc::new(slint::VecModel::from(saved_books_converted));
+            load_covers(&main_window, &books);
             main_window
                 .global::<AudioState>()
                 .set_home_page_books(books)
@@ -163,6 +319,38 @@ fn handle_saved_books(main_window: &AppWindow) {
     });
 }
 
+// Fills in how far into a saved book the listener is, with the time left at `speed`
+fn with_progress(mut book_item: BookItem, speed: f32) -> BookItem {
+    let progress = book_progress(&book_item.id).unwrap_or_default();
+    let chapter_listened: Vec<bool> = (0..book_item.chapter_urls.row_count() as u32)
+        .map(|chapter| progress.finished || progress.listened.binary_search(&chapter).is_ok())
+        .collect();
+
+    book_item.progress = progress.fraction().map(|fraction| fraction as f32).unwrap_or(-1.0);
+    book_item.progress_text = match (progress.fraction(), progress.remaining(speed)) {
+        _ if progress.finished => "Finished".into(),
+        (Some(fraction), Some(remaining)) if progress.position > 0.0 => format!(
+            "{}% · {} left",
+            (fraction * 100.0).floor(),
+            format_time_left(remaining)
+        )
+        .into(),
+        _ => "".into(),
+    };
+    book_item.finished = progress.finished;
+    book_item.chapter_listened = slint::ModelRc::new(slint::VecModel::from(chapter_listened));
+    book_item
+}
+
+// "3h 10m", or "25m" under an hour
+fn format_time_left(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).ceil() as u64;
+    match minutes / 60 {
+        0 => format!("{}m", minutes),
+        hours => format!("{}h {}m", hours, minutes % 60),
+    }
+}
+
 fn handle_previous_page_navigate(
     main_window: &AppWindow,
     audio_state: &AudioState<'_>,
@@ -204,79 +392,687 @@ fn handle_search(
     audio_state: &AudioState<'_>,
     webapi_client: &WebApiClient,
 ) {
+    // The last query, so "load more" and the filters know what to build on
+    let current_query = Arc::new(Mutex::new(SearchQuery::default()));
+    // Bumped by every search, answers to older ones are dropped
+    let generation = Arc::new(AtomicU64::new(0));
+
     let main_window_weak = main_window.as_weak();
     let webapi_client_clone = webapi_client.clone();
+    let current_query_clone = current_query.clone();
+    let generation_clone = generation.clone();
     audio_state.on_on_search_clicked(move |query| {
-        let webapi_client_clone = webapi_client_clone.clone();
         let main_window_weak = main_window_weak.clone();
+        // Pasting a feed URL into search subscribes to it
+        if query.starts_with("http") && is_feed_url(&query) {
+            handle_subscribe(main_window_weak, query.to_string());
+            return;
+        }
+        // Searching for a genre name opens the genre instead
+        if let Some(genre) = find_genre(&cached_genres(), &query) {
+            let main_window = main_window_weak.upgrade().unwrap();
+            main_window
+                .global::<AudioState>()
+                .invoke_open_genre(genre.name.into());
+            return;
+        }
+        let main_window = main_window_weak.upgrade().unwrap();
+        main_window.global::<AudioState>().set_search_filter(SearchFilter {
+            text: query.clone(),
+            ..Default::default()
+        });
+
+        let search_query = SearchQuery::new(query.to_string());
+        *current_query_clone.lock().unwrap() = search_query.clone();
+        run_search(
+            main_window_weak,
+            webapi_client_clone.clone(),
+            search_query,
+            false,
+            generation_clone.clone(),
+        );
+    });
+
+    let main_window_weak = main_window.as_weak();
+    let webapi_client_clone = webapi_client.clone();
+    let current_query_clone = current_query.clone();
+    let generation_clone = generation.clone();
+    audio_state.on_advanced_search(move |filter| {
+        let main_window_weak = main_window_weak.clone();
+        let webapi_client_clone = webapi_client_clone.clone();
+        let current_query_clone = current_query_clone.clone();
+        let generation_clone = generation_clone.clone();
+        main_window_weak
+            .upgrade()
+            .unwrap()
+            .global::<AudioState>()
+            .set_search_loading(true);
+        thread::spawn(move || {
+            let search_query = search_query_from_filter(&webapi_client_clone, &filter);
+            *current_query_clone.lock().unwrap() = search_query.clone();
+            run_search(
+                main_window_weak,
+                webapi_client_clone,
+                search_query,
+                false,
+                generation_clone,
+            );
+        });
+    });
+
+    let main_window_weak = main_window.as_weak();
+    let webapi_client_clone = webapi_client.clone();
+    audio_state.on_load_more_results(move || {
+        let search_query = {
+            let mut current_query = current_query.lock().unwrap();
+            current_query.page = Some(current_query.page() + 1);
+            current_query.clone()
+        };
         main_window_weak
             .upgrade()
             .unwrap()
             .global::<AudioState>()
-            .set_current_view(100);
+            .set_search_loading(true);
+        run_search(
+            main_window_weak.clone(),
+            webapi_client_clone.clone(),
+            search_query,
+            true,
+            generation.clone(),
+        );
+    });
+}
+
+fn search_query_from_filter(webapi_client: &WebApiClient, filter: &SearchFilter) -> SearchQuery {
+    let non_empty = |text: &slint::SharedString| {
+        let text = text.trim();
+        (!text.is_empty()).then(|| text.to_string())
+    };
+
+    // Genres are typed by name, the providers want the LibriVox genre id
+    let genre_id = non_empty(&filter.genre).and_then(|name| {
+        webapi_client
+            .get_genres()
+            .into_iter()
+            .find(|genre| genre.name.eq_ignore_ascii_case(&name))
+            .map(|genre| genre.id)
+    });
+
+    SearchQuery {
+        keywords: non_empty(&filter.text),
+        author: non_empty(&filter.author),
+        reader: non_empty(&filter.reader),
+        genre_id,
+        sort_order: Some(SortOrder::from_name(&filter.sort)),
+        page: None,
+        ..Default::default()
+    }
+}
+
+fn run_search(
+    main_window_weak: slint::Weak<AppWindow>,
+    webapi_client: WebApiClient,
+    search_query: SearchQuery,
+    append: bool,
+    generation: Arc<AtomicU64>,
+) {
+    let this_search = generation.fetch_add(1, Ordering::SeqCst) + 1;
+    // Show the search view straight away, each provider fills its row in when it answers
+    let providers = webapi_client.providers();
+    let enabled = providers.clone();
+    let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+        let audio_state = main_window.global::<AudioState>();
+        for provider in Provider::ALL {
+            if !append || !enabled.contains(&provider) {
+                set_search_results(&audio_state, provider, slint::ModelRc::default());
+            }
+            let status = if enabled.contains(&provider) {
+                "Searching..."
+            } else {
+                "Turned off in the settings"
+            };
+            set_search_status(&audio_state, provider, status.into());
+        }
+        if enabled.is_empty() {
+            audio_state.set_search_loading(false);
+        }
+        if audio_state.get_current_view() != 1 {
+            audio_state.set_current_view(1);
+            audio_state.invoke_add_previous_page(1);
+        }
+    });
+
+    thread::spawn(move || {
+        let pending = Arc::new(Mutex::new(providers.len()));
+        // Providers answer from different threads, and the weak handle is not Sync
+        let main_window_weak = Mutex::new(main_window_weak);
+        let sort = search_query.sort_order();
+        Runtime::new().unwrap().block_on(webapi_client.search(
+            &search_query,
+            SEARCH_TIMEOUT,
+            move |provider, result| {
+                let pending = pending.clone();
+                let generation = generation.clone();
+                let main_window_weak = main_window_weak.lock().unwrap().clone();
+                let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                    // A newer search has taken over the results
+                    if generation.load(Ordering::SeqCst) != this_search {
+                        return;
+                    }
+                    let audio_state = main_window.global::<AudioState>();
+                    let status = match result {
+                        Ok(books) => {
+                            // Load more keeps what is already shown and adds the next page
+                            let new_items = search_results_model(books);
+                            let items = if append {
+                                let items: Vec<BookItem> = get_search_results(&audio_state, provider)
+                                    .iter()
+                                    .chain(new_items.iter())
+                                    .collect();
+                                slint::ModelRc::new(slint::VecModel::from(items))
+                            } else {
+                                new_items
+                            };
+                            let status = if items.row_count() == 0 {
+                                "No results".to_string()
+                            } else if !provider.can_sort(sort) {
+                                format!(
+                                    "{} can't sort by {}, showing the best matches first",
+                                    provider.name(),
+                                    sort.name()
+                                )
+                            } else {
+                                "".to_string()
+                            };
+                            load_covers(&main_window, &items);
+                            set_search_results(&audio_state, provider, items);
+                            status
+                        }
+                        Err(AudiodyError::Timeout(_)) => {
+                            format!("{} took too long to answer", provider.name())
+                        }
+                        Err(e) => format!("{} failed: {}", provider.name(), e),
+                    };
+                    set_search_status(&audio_state, provider, status.into());
+                    finish_provider(&audio_state, &pending);
+                });
+            },
+        ));
+    });
+}
+
+// Stops the "load more" spinner once the last provider has answered
+fn finish_provider(audio_state: &AudioState<'_>, pending: &Mutex<usize>) {
+    let mut pending = pending.lock().unwrap();
+    *pending -= 1;
+    if *pending == 0 {
+        audio_state.set_search_loading(false);
+    }
+}
+
+fn get_search_results(audio_state: &AudioState<'_>, provider: Provider) -> slint::ModelRc<BookItem> {
+    match provider {
+        Provider::LibriVox => audio_state.get_search_libi(),
+        Provider::YouTube => audio_state.get_search_yt(),
+        Provider::Archive => audio_state.get_search_archive(),
+    }
+}
+
+fn set_search_results(
+    audio_state: &AudioState<'_>,
+    provider: Provider,
+    books: slint::ModelRc<BookItem>,
+) {
+    match provider {
+        Provider::LibriVox => audio_state.set_search_libi(books),
+        Provider::YouTube => audio_state.set_search_yt(books),
+        Provider::Archive => audio_state.set_search_archive(books),
+    }
+}
+
+fn set_search_status(audio_state: &AudioState<'_>, provider: Provider, status: slint::SharedString) {
+    match provider {
+        Provider::LibriVox => audio_state.set_search_libi_status(status),
+        Provider::YouTube => audio_state.set_search_yt_status(status),
+        Provider::Archive => audio_state.set_search_archive_status(status),
+    }
+}
+
+fn handle_genres(
+    main_window: &AppWindow,
+    audio_state: &AudioState<'_>,
+    webapi_client: &WebApiClient,
+) {
+    // The open genre and the last page of it that was loaded
+    let current_genre = Arc::new(Mutex::new((Genre::default(), 0)));
+
+    let main_window_weak = main_window.as_weak();
+    let webapi_client_clone = webapi_client.clone();
+    audio_state.on_load_genres(move || {
+        let main_window_weak = main_window_weak.clone();
+        let webapi_client_clone = webapi_client_clone.clone();
+        thread::spawn(move || {
+            let genres: Vec<slint::SharedString> = webapi_client_clone
+                .get_genres()
+                .into_iter()
+                .map(|genre| genre.name.into())
+                .collect();
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                main_window
+                    .global::<AudioState>()
+                    .set_genres(slint::ModelRc::new(slint::VecModel::from(genres)));
+            });
+        });
+    });
+
+    let main_window_weak = main_window.as_weak();
+    let webapi_client_clone = webapi_client.clone();
+    let current_genre_clone = current_genre.clone();
+    audio_state.on_open_genre(move |name| {
+        let main_window_weak = main_window_weak.clone();
+        let webapi_client_clone = webapi_client_clone.clone();
+        let current_genre_clone = current_genre_clone.clone();
+        let main_window = main_window_weak.upgrade().unwrap();
+        main_window.global::<AudioState>().set_current_view(100);
+        main_window
+            .global::<AudioState>()
+            .set_page_name(name.clone());
         thread::spawn(move || {
-            let webapi_client_clone = webapi_client_clone.clone();
+            let Some(genre) = find_genre(&webapi_client_clone.get_genres(), &name) else {
+                log::error!("Unknown genre: {}", name);
+                return;
+            };
+            *current_genre_clone.lock().unwrap() = (genre.clone(), 0);
+            let books = webapi_client_clone
+                .get_genre_books(&genre, 0)
+                .unwrap_or_else(|e| {
+                    log::error!("Failed to get books for {}: {}", genre.name, e);
+                    vec![]
+                });
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                let books = search_results_model(books);
+                load_covers(&main_window, &books);
+                main_window.global::<AudioState>().set_genre_books(books);
+                main_window.global::<AudioState>().set_current_view(7);
+            });
+        });
+    });
 
+    let main_window_weak = main_window.as_weak();
+    let webapi_client_clone = webapi_client.clone();
+    audio_state.on_load_more_genre(move || {
+        let main_window_weak = main_window_weak.clone();
+        let webapi_client_clone = webapi_client_clone.clone();
+        let current_genre = current_genre.clone();
+        main_window_weak
+            .upgrade()
+            .unwrap()
+            .global::<AudioState>()
+            .set_search_loading(true);
+        thread::spawn(move || {
+            let (genre, page) = {
+                let mut current_genre = current_genre.lock().unwrap();
+                current_genre.1 += 1;
+                current_genre.clone()
+            };
+            let books = webapi_client_clone
+                .get_genre_books(&genre, page)
+                .unwrap_or_else(|e| {
+                    log::error!("Failed to get books for {}: {}", genre.name, e);
+                    vec![]
+                });
             let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-                let books = Runtime::new()
-                    .unwrap()
-                    .block_on(webapi_client_clone.search(query.to_string()))
-                    .unwrap();
-                let libri_book_items: Vec<BookItem> = books[0]
-                    .clone()
-                    .into_iter()
-                    .map(|book| BookItem {
-                        title: book.title.into(),
-                        author: book.author.into(),
-                        description: book.description.clone().into(),
-                        book_url: book.url.into(),
-                        saved: book.saved,
-                        image: Runtime::new()
-                            .unwrap()
-                            .block_on(url_to_buffer(book.image_URL))
-                            .unwrap(),
-                        chapter_urls: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                        chapter_durations: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                        chapter_reader: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                    })
+                let audio_state = main_window.global::<AudioState>();
+                let items: Vec<BookItem> = audio_state
+                    .get_genre_books()
+                    .iter()
+                    .chain(search_results_model(books).iter())
                     .collect();
-                let libri_book_model = slint::ModelRc::new(slint::VecModel::from(libri_book_items));
-
-                let yt_book_items: Vec<BookItem> = books[1]
-                    .clone()
-                    .into_iter()
-                    .map(|book| BookItem {
-                        title: book.title.into(),
-                        author: book.author.into(),
-                        description: book.description.clone().into(),
-                        book_url: book.url.into(),
-                        saved: book.saved,
-                        image: Runtime::new()
-                            .unwrap()
-                            .block_on(url_to_buffer(book.image_URL))
-                            .unwrap(),
-                        chapter_urls: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                        chapter_durations: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                        chapter_reader: slint::ModelRc::new(slint::VecModel::from(vec![])),
-                    })
-                    .collect();
-                let yt_book_model = slint::ModelRc::new(slint::VecModel::from(yt_book_items));
+                let items = slint::ModelRc::new(slint::VecModel::from(items));
+                load_covers(&main_window, &items);
+                audio_state.set_genre_books(items);
+                audio_state.set_search_loading(false);
+            });
+        });
+    });
+}
 
+fn handle_opml(main_window: &AppWindow, audio_state: &AudioState<'_>) {
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_import_opml(move |path| {
+        let main_window_weak = main_window_weak.clone();
+        thread::spawn(move || {
+            let status = match import_opml(&PathBuf::from(path.as_str())) {
+                Ok(books) => format!("Imported {} feeds", books.len()),
+                Err(e) => format!("Import failed: {}", e),
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
                 main_window
                     .global::<AudioState>()
-                    .set_search_libi(libri_book_model);
+                    .set_settings_status(status.into());
+                handle_saved_books(&main_window);
+            });
+        });
+    });
+
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_export_opml(move |path| {
+        let main_window_weak = main_window_weak.clone();
+        thread::spawn(move || {
+            let status = match export_opml(&PathBuf::from(path.as_str())) {
+                Ok(count) => format!("Exported {} books to {}", count, path),
+                Err(e) => format!("Export failed: {}", e),
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
                 main_window
                     .global::<AudioState>()
-                    .set_search_yt(yt_book_model);
-                main_window.global::<AudioState>().set_current_view(1);
+                    .set_settings_status(status.into());
+            });
+        });
+    });
+
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_rescan_library(move || {
+        let main_window_weak = main_window_weak.clone();
+        thread::spawn(move || {
+            let status = match library::rescan() {
+                Ok(count) => format!("Found {} books", count),
+                Err(e) => format!("Rescan failed: {}", e),
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
                 main_window
                     .global::<AudioState>()
-                    .invoke_add_previous_page(1);
+                    .set_settings_status(status.into());
+                handle_saved_books(&main_window);
             });
         });
     });
 }
 
+/// Moving the library to another folder, or switching to a folder that already has one.
+fn handle_library(
+    main_window: &AppWindow,
+    audio_state: &AudioState<'_>,
+    download_manager: &DownloadManager,
+) {
+    let main_window_weak = main_window.as_weak();
+    let download_manager = download_manager.clone();
+    audio_state.on_move_library(move |path| {
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let audio_state = main_window.global::<AudioState>();
+        if audio_state.get_library_move_progress() >= 0.0 {
+            return;
+        }
+        audio_state.set_library_move_progress(0.0);
+        audio_state.set_settings_status("Moving the library...".into());
+
+        let main_window_weak = main_window_weak.clone();
+        let download_manager = download_manager.clone();
+        let target = PathBuf::from(path.trim());
+        thread::spawn(move || {
+            let progress_weak = main_window_weak.clone();
+            let result = download_manager.move_library(&target, |copied, total| {
+                let progress = match total {
+                    0 => 1.0,
+                    total => copied as f32 / total as f32,
+                };
+                let _ = progress_weak.upgrade_in_event_loop(move |main_window| {
+                    main_window
+                        .global::<AudioState>()
+                        .set_library_move_progress(progress);
+                });
+            });
+            let status = match result {
+                Ok(count) => format!("Moved {} books to {}", count, target.display()),
+                Err(e) => format!("Moving the library failed: {}", e),
+            };
+            finish_library_change(main_window_weak, status);
+        });
+    });
+
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_use_library_folder(move |path| {
+        let main_window_weak = main_window_weak.clone();
+        let dir = PathBuf::from(path.trim());
+        thread::spawn(move || {
+            let status = match library::use_library_dir(&dir) {
+                Ok(count) => format!("Found {} books in {}", count, dir.display()),
+                Err(e) => format!("Can't use {}: {}", dir.display(), e),
+            };
+            finish_library_change(main_window_weak, status);
+        });
+    });
+}
+
+fn handle_import(main_window: &AppWindow, audio_state: &AudioState<'_>) {
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_import_book(move |path, in_place| {
+        let main_window_weak = main_window_weak.clone();
+        let path = PathBuf::from(path.trim());
+        let mode = if in_place { ImportMode::InPlace } else { ImportMode::Copy };
+        if let Some(main_window) = main_window_weak.upgrade() {
+            main_window
+                .global::<AudioState>()
+                .set_settings_status(format!("Importing {}...", path.display()).into());
+        }
+        thread::spawn(move || {
+            let status = match import(&path, mode).and_then(|book| {
+                library::book(&book)?.ok_or_else(|| "It's not in the library".into())
+            }) {
+                Ok(book) => {
+                    format!("Imported {} with {} chapters", book.title, book.chapters.len())
+                }
+                Err(e) => format!("Failed to import {}: {}", path.display(), e),
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                main_window
+                    .global::<AudioState>()
+                    .set_settings_status(status.into());
+                handle_saved_books(&main_window);
+            });
+        });
+    });
+}
+
+fn handle_export(main_window: &AppWindow, audio_state: &AudioState<'_>) {
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_export_book(move |id| {
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let audio_state = main_window.global::<AudioState>();
+        if audio_state.get_book_view_export_progress() >= 0.0 {
+            return;
+        }
+        audio_state.set_book_view_export_progress(0.0);
+        audio_state.set_book_view_export_status("Exporting...".into());
+
+        let main_window_weak = main_window_weak.clone();
+        let id = id.to_string();
+        thread::spawn(move || {
+            let progress_weak = main_window_weak.clone();
+            let result = export_m4b(&id, None, |done, total| {
+                let progress = if total > 0.0 { (done / total) as f32 } else { 1.0 };
+                let _ = progress_weak.upgrade_in_event_loop(move |main_window| {
+                    main_window
+                        .global::<AudioState>()
+                        .set_book_view_export_progress(progress);
+                });
+            });
+            let status = match result {
+                Ok(file) => format!("Exported to {}", file.display()),
+                Err(e) => format!("Export failed: {}", e),
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                let audio_state = main_window.global::<AudioState>();
+                audio_state.set_book_view_export_progress(-1.0);
+                audio_state.set_book_view_export_status(status.into());
+            });
+        });
+    });
+}
+
+fn handle_finished(main_window: &AppWindow, audio_state: &AudioState<'_>) {
+    let main_window_weak = main_window.as_weak();
+    audio_state.on_set_finished(move |id, finished| {
+        if let Err(e) = set_finished(&id, finished) {
+            log::error!("Failed to mark {} as finished: {}", id, e);
+            return;
+        }
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let audio_state = main_window.global::<AudioState>();
+        let book_view = audio_state.get_book_view();
+        if book_view.id == id {
+            audio_state.set_book_view(with_progress(book_view, audio_state.get_speed()));
+        }
+        handle_saved_books(&main_window);
+    });
+}
+
+fn finish_library_change(main_window_weak: slint::Weak<AppWindow>, status: String) {
+    let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+        let audio_state = main_window.global::<AudioState>();
+        audio_state.set_library_move_progress(-1.0);
+        audio_state.set_settings_status(status.into());
+        if let Ok(dir) = music_dir() {
+            audio_state.set_library_dir(dir.display().to_string().into());
+        }
+        handle_saved_books(&main_window);
+    });
+}
+
+fn handle_subscribe(main_window_weak: slint::Weak<AppWindow>, feed_url: String) {
+    main_window_weak
+        .upgrade()
+        .unwrap()
+        .global::<AudioState>()
+        .set_current_view(100);
+    thread::spawn(move || match subscribe(&feed_url) {
+        Ok(book) => {
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                handle_saved_books(&main_window);
+                let id = book_id(&book.url, &book.title);
+                main_window
+                    .global::<AudioState>()
+                    .invoke_on_book_view(book.url.into(), id.into());
+            });
+        }
+        Err(e) => {
+            log::error!("Failed to subscribe to {}: {}", feed_url, e);
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                main_window.global::<AudioState>().invoke_go_to_previous_page();
+            });
+        }
+    });
+}
+
+fn handle_feed_refresh(main_window: &AppWindow) {
+    let main_window_weak = main_window.as_weak();
+    thread::spawn(move || loop {
+        if refresh_all() > 0 {
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                handle_saved_books(&main_window);
+            });
+        }
+        thread::sleep(REFRESH_INTERVAL);
+    });
+}
+
+/// Fills in the covers of a book list in the background, rows show a placeholder until then.
+fn load_covers(main_window: &AppWindow, books: &slint::ModelRc<BookItem>) {
+    let urls = books
+        .iter()
+        // Rows that were already loaded keep their cover
+        .filter(|book| book.image.size().width <= 1)
+        .map(|book| book.image_url.to_string())
+        .collect();
+    load_cover_urls(main_window.as_weak(), urls, CoverSize::Thumbnail);
+}
+
+fn load_cover_urls(main_window_weak: slint::Weak<AppWindow>, urls: Vec<String>, size: CoverSize) {
+    thread::spawn(move || {
+        let mut loaded = HashSet::new();
+        for url in urls {
+            if url.is_empty() || !loaded.insert(url.clone()) {
+                continue;
+            }
+            let pixel_buffer = match load_cover(&url, size) {
+                Ok(pixel_buffer) => pixel_buffer,
+                Err(e) => {
+                    log::warn!("Failed to load cover {}: {}", url, e);
+                    continue;
+                }
+            };
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                let audio_state = main_window.global::<AudioState>();
+                let image = slint::Image::from_rgba8(pixel_buffer);
+                match size {
+                    // The same book can show up in a few lists at once
+                    CoverSize::Thumbnail => {
+                        for books in [
+                            audio_state.get_home_page_books(),
+                            audio_state.get_search_libi(),
+                            audio_state.get_search_yt(),
+                            audio_state.get_search_archive(),
+                            audio_state.get_genre_books(),
+                        ] {
+                            for row in 0..books.row_count() {
+                                if let Some(mut book) = books.row_data(row) {
+                                    if book.image_url == url.as_str() {
+                                        book.image = image.clone();
+                                        books.set_row_data(row, book);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    CoverSize::Full => {
+                        let mut book_view = audio_state.get_book_view();
+                        if book_view.image_url == url.as_str() {
+                            book_view.image = image.clone();
+                            audio_state.set_book_view(book_view);
+                        }
+                        let mut now_playing = audio_state.get_now_playing();
+                        if now_playing.image_url == url.as_str() {
+                            now_playing.image = image;
+                            audio_state.set_now_playing(now_playing);
+                        }
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn search_results_model(books: Vec<api::types::Book>) -> slint::ModelRc<BookItem> {
+    let book_items: Vec<BookItem> = books
+        .into_iter()
+        .map(|book| BookItem {
+            id: book_id(&book.url, &book.title).into(),
+            title: book.title.into(),
+            author: book.author.into(),
+            description: book.description.clone().into(),
+            book_url: book.url.into(),
+            saved: book.saved,
+            image: placeholder_cover(),
+            image_url: book.image_URL.into(),
+            chapter_titles: slint::ModelRc::new(slint::VecModel::from(vec![])),
+            chapter_urls: slint::ModelRc::new(slint::VecModel::from(vec![])),
+            chapter_durations: slint::ModelRc::new(slint::VecModel::from(vec![])),
+            chapter_reader: slint::ModelRc::new(slint::VecModel::from(vec![])),
+            progress: -1.0,
+            progress_text: "".into(),
+            finished: false,
+            chapter_listened: slint::ModelRc::new(slint::VecModel::from(vec![])),
+        })
+        .collect();
+    slint::ModelRc::new(slint::VecModel::from(book_items))
+}
+
 fn handle_pause(main_window: &AppWindow, audio_state: &AudioState, audio_service: &AudioService) {
     let main_window_weak = main_window.as_weak();
     let audio_service_clone = audio_service.clone();
@@ -287,6 +1083,7 @@ fn handle_pause(main_window: &AppWindow, audio_state: &AudioState, audio_service
                 audio_service_clone.play();
             } else {
                 audio_service_clone.pause();
+                flush_progress();
             }
         }
     });
@@ -296,44 +1093,58 @@ fn handle_add_queue(
     main_window: &AppWindow,
     audio_state: &AudioState,
     audio_service: &AudioService,
+    download_manager: &DownloadManager,
 ) {
     let main_window_weak = main_window.as_weak();
     let audio_service_clone = audio_service.clone();
+    let download_manager_clone = download_manager.clone();
+    // The timer asks every tick near the end of a chapter, one download at a time is plenty
+    let pending: Arc<Mutex<Option<(String, i32)>>> = Arc::new(Mutex::new(None));
     audio_state.on_queue_next_track(move || {
-        let audio_service_clone = audio_service_clone.clone();
-        let main_window_weak = main_window_weak.clone();
-        let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-            let playing_book = main_window.global::<AudioState>().get_now_playing();
-            let settings: settings = get_progress(playing_book.title.as_str()).unwrap();
-            let next_chapter = settings.current_chapter.unwrap() + 1;
-
-            // Store current settings before download
-            let current_settings = settings.clone();
-
-            let download = download_audio(
-                &playing_book.title.to_string(),
-                next_chapter,
-                &playing_book
-                    .chapter_urls
-                    .row_data(next_chapter.try_into().unwrap())
-                    .unwrap()
-                    .to_string(),
-                &settings.book_url,
-            )
-            .unwrap()
-            .display()
-            .to_string();
-
-            // Restore settings after download
-            save_progress(
-                &playing_book.title.to_string(),
-                current_settings.current_chapter,
-                &current_settings.book_url,
-                current_settings.current_chapter_time,
-            );
+        // Chapters in a single file just carry on playing into the next one
+        if audio_service_clone.current_chapter().is_some() {
+            return;
+        }
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let playing_book = main_window.global::<AudioState>().get_now_playing();
+        let Ok(settings) = get_progress(&playing_book.id) else {
+            return;
+        };
+        let next_chapter = settings.current_chapter.unwrap_or_default() + 1;
+        // Nothing after the last chapter
+        let Some(url) = playing_book.chapter_urls.row_data(next_chapter.max(0) as usize) else {
+            return;
+        };
+        let next = (playing_book.id.to_string(), next_chapter);
+        {
+            let mut pending = pending.lock().unwrap();
+            if pending.as_ref() == Some(&next) {
+                return;
+            }
+            *pending = Some(next);
+        }
 
-            audio_service_clone.queue(download);
-        });
+        let audio_service_clone = audio_service_clone.clone();
+        let pending = pending.clone();
+        download_manager_clone.fetch_chapter(
+            &playing_book.title,
+            &playing_book.book_url,
+            &playing_book.image_url,
+            next_chapter,
+            &url,
+            move |download| match download {
+                // Asked again next tick, which is quick now it's there and the service ignores
+                // files it has lined up already
+                Ok(file) => {
+                    *pending.lock().unwrap() = None;
+                    audio_service_clone.queue(file.display().to_string());
+                }
+                // Left pending, so a failed or cancelled download isn't started over every tick
+                Err(e) => log::warn!("Failed to get chapter {} ready: {}", next_chapter + 1, e),
+            },
+        );
     });
 }
 
@@ -341,17 +1152,29 @@ fn handle_play_next(
     main_window: &AppWindow,
     audio_state: &AudioState,
     audio_service: &AudioService,
+    download_manager: &DownloadManager,
 ) {
     let main_window_weak = main_window.as_weak();
     let audio_service_clone = audio_service.clone();
+    let download_manager_clone = download_manager.clone();
     audio_state.on_notif_next_track(move || {
         let audio_service_clone = audio_service_clone.clone();
         let main_window_weak = main_window_weak.clone();
+        let download_manager_clone = download_manager_clone.clone();
+        // handle_playing keeps track of chapters in a single file
+        if audio_service_clone.current_chapter().is_some() {
+            return;
+        }
         let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
             let currrent_settings: settings =
-                get_progress(&main_window.global::<AudioState>().get_now_playing().title).unwrap();
+                get_progress(&main_window.global::<AudioState>().get_now_playing().id).unwrap();
+            let book = main_window.global::<AudioState>().get_now_playing().id;
+            let heard = currrent_settings.current_chapter.unwrap_or_default().max(0) as u32;
+            if let Err(e) = mark_listened(&book, heard) {
+                log::warn!("Failed to mark chapter {} of {} as heard: {}", heard, book, e);
+            }
             save_progress(
-                &main_window.global::<AudioState>().get_now_playing().title,
+                &main_window.global::<AudioState>().get_now_playing().id,
                 Some((currrent_settings.current_chapter).unwrap() + 1),
                 &main_window
                     .global::<AudioState>()
@@ -360,6 +1183,11 @@ fn handle_play_next(
                     .as_str(),
                 Some((0.0) as f64),
             );
+            prefetch_chapters(
+                &download_manager_clone,
+                &main_window.global::<AudioState>().get_now_playing(),
+                currrent_settings.current_chapter.unwrap() + 1,
+            );
         });
     });
 }
@@ -368,114 +1196,208 @@ fn handle_chapter_download_and_play(
     main_window: &AppWindow,
     audio_state: &AudioState,
     audio_service: &AudioService,
+    download_manager: &DownloadManager,
 ) {
     let main_window_weak = main_window.as_weak();
     let audio_service_clone = audio_service.clone();
-    audio_state.on_chapter_download_and_play(move |book, chapter, URL| {
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_chapter_download_and_play(move |_book, chapter, URL| {
         let audio_service_clone = audio_service_clone.clone();
         let main_window_weak = main_window_weak.clone();
-        thread::spawn(move || {
-            let audio_service_clone = audio_service_clone.clone();
-            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-                match download_audio(
-                    &book,
-                    chapter,
-                    &URL,
-                    &main_window
-                        .global::<AudioState>()
-                        .get_book_view()
-                        .book_url
-                        .to_string(),
-                ) {
-                    Ok(path_buf) => {
-                        if let Some(path_str) = path_buf.to_str() {
-                            log::info!("Downloaded audio path: {}", path_str);
-                            log::info!("Starting to play!!");
-                            audio_service_clone.start(path_str.clone().to_string());
-                            audio_service_clone.play();
-                            main_window.global::<AudioState>().set_playback_length(audio_service_clone.get_chapter_len(
-                                &check_book_chapter_url(
-                                    get_progress(&main_window.global::<AudioState>().get_now_playing().title.to_string())
-                                        .unwrap().current_chapter.unwrap().try_into().unwrap(),
-                                    main_window.global::<AudioState>().get_now_playing().title.to_string()
-                                ).unwrap().unwrap().display().to_string().as_str()
-                            ).as_secs_f32());
-
-                            main_window.global::<AudioState>().set_paused(false);
-                            // TODO: Optimise this so that it doesnt refresh the whole thing
-                            main_window.global::<AudioState>().get_home_page_books();
-                            let child = path_buf
-                                .file_name()
-                                .unwrap()
-                                .to_ascii_lowercase()
-                                .into_string()
-                                .unwrap()
-                                .split_off(8); // Remove first 8 chars ("chapter_")
-
-                            let mut chapter_number = child[..child.len() - 4].to_string(); // Remove last 4 chars (".mp3")
-                            log::info!("chapter to get{}", path_str);
-                            if !child.contains("chapter") {
-                                chapter_number = extract_number(path_str.clone()).unwrap_or(0).to_string();
+        let download_manager_clone = download_manager_clone.clone();
+        let Some(book_view) = main_window_weak
+            .upgrade()
+            .map(|main_window| main_window.global::<AudioState>().get_book_view())
+        else {
+            return;
+        };
+        let book = book_id(&book_view.book_url, &book_view.title);
+        let book_url = book_view.book_url.to_string();
+        // The download manager gets it ready, then it's played on the event loop
+        let download_manager = download_manager_clone.clone();
+        download_manager.fetch_chapter(
+            &book_view.title,
+            &book_view.book_url,
+            &book_view.image_url,
+            chapter,
+            &URL,
+            move |download| {
+                let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                    // Whatever was playing stops here
+                    flush_progress();
+                    match download {
+                        Ok(path_buf) => {
+                            // Books kept as one file play the chapter straight from its offset
+                            let offsets = get_chapter_offsets(&book);
+                            if let Some(offset) = offsets.get(chapter as usize).copied() {
+                                let audio_state = main_window.global::<AudioState>();
+                                audio_service_clone.start_chapters(
+                                    path_buf.display().to_string(),
+                                    offsets,
+                                    chapter as usize,
+                                    0.0,
+                                );
+                                audio_service_clone.play();
+                                audio_state.set_playback_length(offset.duration() as f32);
+                                audio_state.set_paused(false);
+                                let _ = save_progress(&book, Some(chapter), &book_url, None);
+                                return;
                             }
-                            if let Ok(chapter_num) = chapter_number.parse::<i32>() {
-                                save_progress(
-                                    &main_window.global::<AudioState>().get_now_playing().title,
-                                    Some(chapter_num),
-                                    &main_window
-                                        .global::<AudioState>()
-                                        .get_now_playing()
-                                        .book_url
-                                        .as_str(),
-                                    None,
+                            if let Some(path_str) = path_buf.to_str() {
+                                log::info!("Downloaded audio path: {}", path_str);
+                                log::info!("Starting to play!!");
+                                audio_service_clone.start(path_str.clone().to_string());
+                                audio_service_clone.play();
+                                prefetch_chapters(
+                                    &download_manager_clone,
+                                    &main_window.global::<AudioState>().get_book_view(),
+                                    chapter,
+                                );
+                                main_window.global::<AudioState>().set_playback_length(
+                                    audio_service_clone
+                                        .get_chapter_len(path_str)
+                                        .map_or(1.0, |len| len.as_secs_f32()),
                                 );
+
+                                main_window.global::<AudioState>().set_paused(false);
+                                // TODO: Optimise this so that it doesnt refresh the whole thing
+                                main_window.global::<AudioState>().get_home_page_books();
+                                // Imported and playlist files are named any which way, the
+                                // chapter that was asked for is the one that's playing
+                                let _ = save_progress(&book, Some(chapter), &book_url, None);
                             } else {
-                                log::error!("Failed to parse chapter number: {}", chapter_number);
+                                log::info!("Error: Path contains invalid UTF-8");
                             }
-                        } else {
-                            log::info!("Error: Path contains invalid UTF-8");
+                        }
+                        Err(e) => {
+                            log::info!("Error downloading audio: {}", e);
                         }
                     }
-                    Err(e) => {
-                        log::info!("Error downloading audio: {}", e);
-                    }
-                }
-            });
-        });
+                });
+            },
+        );
     })
 }
 
-fn handle_chapter_download(main_window: &AppWindow, audio_state: &AudioState) {
+fn handle_chapter_download(
+    main_window: &AppWindow,
+    audio_state: &AudioState,
+    download_manager: &DownloadManager,
+) {
     let main_window_weak = main_window.as_weak();
-    audio_state.on_chapter_download(move |book, chapter, URL| {
-        let main_window_weak = main_window_weak.clone();
-        thread::spawn(move || {
-            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-                match download_audio(
-                    &book,
-                    chapter,
-                    &URL,
-                    &main_window
-                        .global::<AudioState>()
-                        .get_book_view()
-                        .book_url
-                        .to_string(),
-                ) {
-                    Ok(path_buf) => {
-                        if let Some(path_str) = path_buf.to_str() {
-                            log::info!("Downloaded audio path: {}", path_str);
-                            // TODO: Optimise this so that it doesnt refresh the whole thing
-                            main_window.global::<AudioState>().get_home_page_books();
-                        } else {
-                            log::info!("Error: Path contains invalid UTF-8");
-                        }
-                    }
-                    Err(e) => {
-                        log::info!("Error downloading audio: {}", e);
-                    }
-                }
-            });
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_chapter_download(move |_book, chapter, URL| {
+        if let Some(main_window) = main_window_weak.upgrade() {
+            let book_view = main_window.global::<AudioState>().get_book_view();
+            download_manager_clone.enqueue_chapter(
+                &book_view.title,
+                &book_view.book_url,
+                &book_view.image_url,
+                chapter,
+                &URL,
+            );
+        }
+    });
+
+    let main_window_weak = main_window.as_weak();
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_book_download(move || {
+        if let Some(main_window) = main_window_weak.upgrade() {
+            let book_view = main_window.global::<AudioState>().get_book_view();
+            download_manager_clone.enqueue_book(
+                &book_view.title,
+                &book_view.book_url,
+                &book_view.image_url,
+                book_view.chapter_urls.iter().map(|url| url.to_string()).collect(),
+            );
+        }
+    });
+}
+
+// Downloads the chapters after `chapter` ahead of time, as far as the download policy says.
+// Books kept as one file don't need it, their chapters all come with the first one.
+fn prefetch_chapters(download_manager: &DownloadManager, book: &BookItem, chapter: i32) {
+    if !get_chapter_offsets(&book.id).is_empty() {
+        return;
+    }
+    let chapter_urls: Vec<String> = book.chapter_urls.iter().map(|url| url.to_string()).collect();
+    download_manager.prefetch(
+        &book.title,
+        &book.book_url,
+        &book.image_url,
+        &chapter_urls,
+        chapter.max(0) as usize,
+    );
+}
+
+/// Keeps the downloads list up to date and passes the buttons on to the download manager.
+fn handle_downloads(
+    main_window: &AppWindow,
+    audio_state: &AudioState,
+    download_manager: &DownloadManager,
+) {
+    let main_window_weak = main_window.as_weak();
+    let finished = Mutex::new(HashSet::new());
+    download_manager.on_change(move |jobs| {
+        // Saved books only need reloading when something new is on disk
+        let mut finished = finished.lock().unwrap();
+        let mut newly_finished = false;
+        for job in jobs.iter().filter(|job| job.status == JobStatus::Done) {
+            newly_finished |= finished.insert(job.id);
+        }
+
+        let items: Vec<DownloadItem> = jobs
+            .iter()
+            .map(|job| DownloadItem {
+                id: job.id as i32,
+                title: job.book.clone().into(),
+                detail: job.description().into(),
+                status: job.status.name().into(),
+                progress: job.progress().unwrap_or(-1.0),
+                error: job.error.clone().unwrap_or_default().into(),
+            })
+            .collect();
+        let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+            main_window
+                .global::<AudioState>()
+                .set_downloads(slint::ModelRc::new(slint::VecModel::from(items)));
+            if newly_finished {
+                handle_saved_books(&main_window);
+            }
         });
-    })
+    });
+
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_pause_download(move |id| download_manager_clone.pause(id as u64));
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_resume_download(move |id| download_manager_clone.resume(id as u64));
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_retry_download(move |id| download_manager_clone.retry(id as u64));
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_cancel_download(move |id| download_manager_clone.cancel(id as u64));
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_clear_finished_downloads(move || download_manager_clone.clear_finished());
+
+    let policy = download_manager.policy();
+    audio_state.set_prefetch_chapters(policy.prefetch_chapters as i32);
+    audio_state.set_download_on_metered(policy.allow_metered);
+    audio_state.set_min_free_space_mb(policy.min_free_space_mb as i32);
+    let main_window_weak = main_window.as_weak();
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_save_download_policy(move || {
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let audio_state = main_window.global::<AudioState>();
+        let policy = DownloadPolicy {
+            prefetch_chapters: audio_state.get_prefetch_chapters().max(0) as usize,
+            allow_metered: audio_state.get_download_on_metered(),
+            min_free_space_mb: audio_state.get_min_free_space_mb().max(0) as u64,
+        };
+        if let Err(e) = download_manager_clone.set_policy(policy) {
+            log::error!("Failed to save the download policy: {}", e);
+        }
+    });
 }
 
 fn handle_book_view(
@@ -485,49 +1407,66 @@ fn handle_book_view(
 ) {
     let main_window_weak = main_window.as_weak();
     let webapi_client = webapi_client.clone();
-    audio_state.on_on_book_view(move |book_url, book_title| {
+    audio_state.on_on_book_view(move |book_url, id| {
         let main_window_weak = main_window_weak.clone();
         let webapi_client_clone = webapi_client.clone();
-        main_window_weak
-            .upgrade()
-            .unwrap()
-            .global::<AudioState>()
-            .set_current_view(100);
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        main_window.global::<AudioState>().set_current_view(100);
         thread::spawn(move || {
-            let webapi_client_clone = webapi_client_clone.clone();
-            let main_window_weak = main_window_weak.clone();
-
-            let mut book: api::types::Book = Default::default();
-            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-                if let Ok(Some(book_thing)) = get_saved_book(book_title.to_string()) {
-                    book = book_thing;
-
-                    let mut book_online = Runtime::new()
-                        .unwrap()
-                        .block_on(webapi_client_clone.clone().get_book(book_url.to_string()))
-                        .unwrap();
-
-                    book_online.image_URL = book.image_URL;
+            let saved_book = get_saved_book(id.to_string()).ok().flatten();
+            let online_book = match Runtime::new() {
+                Ok(runtime) => runtime
+                    .block_on(webapi_client_clone.get_book(book_url.to_string()))
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            // Book pages come from the cache when offline, if even that fails the
+            // saved copy is good enough to listen to
+            let mut book = match (saved_book, online_book) {
+                (Some(saved_book), Ok(mut book_online)) => {
+                    book_online.image_URL = saved_book.image_URL;
                     book_online.saved = true;
-                    book = book_online;
-                } else {
-                    book = Runtime::new()
-                        .unwrap()
-                        .block_on(webapi_client_clone.clone().get_book(book_url.to_string()))
-                        .unwrap();
+                    book_online
                 }
+                (Some(saved_book), Err(e)) => {
+                    log::warn!("Using saved copy of {}: {}", saved_book.title, e);
+                    saved_book
+                }
+                (None, Ok(book_online)) => book_online,
+                (None, Err(e)) => {
+                    log::error!("Failed to get book {}: {}", book_url, e);
+                    let _ = main_window_weak.upgrade_in_event_loop(|main_window| {
+                        main_window.global::<AudioState>().set_current_view(0);
+                    });
+                    return;
+                }
+            };
 
+            // Subscriptions keep their own episode order
+            if let Some(feed) = get_progress(&id).ok().and_then(|s| s.feed) {
+                book = order_book(book, &feed);
+            }
+
+            // Only the finished book goes to the event loop, the slint models live there
+            let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
                 let book_item = BookItem {
+                    id: book_id(&book.url, &book.title).into(),
                     title: book.title.into(),
                     author: book.author.into(),
                     description: book.description.clone().into(),
                     book_url: book.url.into(),
                     saved: book.saved,
-                    image: Runtime::new()
-                        .unwrap()
-                        .block_on(url_to_buffer(book.image_URL))
-                        .unwrap(),
+                    image: placeholder_cover(),
+                    image_url: book.image_URL.into(),
                     // Find a better way of doing this
+                    chapter_titles: slint::ModelRc::new(slint::VecModel::from(
+                        book.chapter_titles
+                            .into_iter()
+                            .map(|title| title.into())
+                            .collect::<Vec<slint::SharedString>>(),
+                    )),
                     chapter_urls: slint::ModelRc::new(slint::VecModel::from(
                         book.chapter_urls
                             .into_iter()
@@ -548,15 +1487,76 @@ fn handle_book_view(
                             .map(|reader| reader.into())
                             .collect::<Vec<slint::SharedString>>(),
                     )),
+                    ..Default::default()
                 };
+                let speed = main_window.global::<AudioState>().get_speed();
+                let book_item = with_progress(book_item, speed);
 
-                main_window.global::<AudioState>().set_book_view(book_item);
-                main_window.global::<AudioState>().set_current_view(5);
+                load_cover_urls(
+                    main_window.as_weak(),
+                    vec![book_item.image_url.to_string()],
+                    CoverSize::Full,
+                );
+                let audio_state = main_window.global::<AudioState>();
+                audio_state.set_book_view_can_single_file(
+                    book_item.book_url.contains("youtube.com")
+                        && !is_playlist_url(&book_item.book_url),
+                );
+                audio_state.set_book_view_single_file(!get_chapter_offsets(&book_item.id).is_empty());
+                // What happened to the last book exported doesn't belong to this one
+                if audio_state.get_book_view_export_progress() < 0.0 {
+                    audio_state.set_book_view_export_status("".into());
+                }
+                audio_state.set_book_view(book_item);
+                audio_state.set_current_view(5);
             });
         });
     });
 }
 
+// Switches a YouTube book between a file per chapter and one file played from chapter offsets
+fn handle_single_file(
+    main_window: &AppWindow,
+    audio_state: &AudioState<'_>,
+    webapi_client: &WebApiClient,
+) {
+    let main_window_weak = main_window.as_weak();
+    let webapi_client = webapi_client.clone();
+    audio_state.on_set_single_file(move |enabled| {
+        let Some(main_window) = main_window_weak.upgrade() else {
+            return;
+        };
+        let book_view = main_window.global::<AudioState>().get_book_view();
+        let title = book_view.title.to_string();
+        let book_url = book_view.book_url.to_string();
+        let webapi_client = webapi_client.clone();
+        thread::spawn(move || {
+            let offsets = if enabled {
+                let offsets = match Runtime::new() {
+                    Ok(runtime) => runtime
+                        .block_on(webapi_client.get_chapter_offsets(book_url.clone()))
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match offsets {
+                    Ok(offsets) => offsets,
+                    Err(e) => {
+                        log::error!("Failed to get chapters of {}: {}", title, e);
+                        return;
+                    }
+                }
+            } else {
+                vec![]
+            };
+            let saved = add_book(&title, &book_url)
+                .and_then(|book| set_chapter_offsets(&book, &book_url, offsets));
+            if let Err(e) = saved {
+                log::error!("Failed to save chapter mode for {}: {}", title, e);
+            }
+        });
+    });
+}
+
 fn handle_playing(
     main_window: &AppWindow,
     audio_state: &AudioState<'_>,
@@ -573,16 +1573,38 @@ fn handle_playing(
 
             // Make this far more efficient
             let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
+                // Playing on past the end of a chapter in a single file moves to the next one
+                if let Some(chapter) = audio_service_clone.current_chapter() {
+                    let audio_state = main_window.global::<AudioState>();
+                    let book = audio_state.get_now_playing().id.to_string();
+                    let previous = get_progress(&book).ok().and_then(|s| s.current_chapter);
+                    if previous != Some(chapter as i32) {
+                        // Playing on into the next chapter means the last one was heard
+                        let heard = previous.filter(|previous| *previous + 1 == chapter as i32);
+                        if let Some(Err(e)) = heard.map(|heard| mark_listened(&book, heard as u32)) {
+                            log::warn!("Failed to mark a chapter of {} as heard: {}", book, e);
+                        }
+                        audio_state.set_playback_length(
+                            audio_service_clone.current_chapter_len().unwrap_or(1.0),
+                        );
+                        let _ = save_progress(
+                            &book,
+                            Some(chapter as i32),
+                            audio_state.get_now_playing().book_url.as_str(),
+                            Some(0.0),
+                        );
+                    }
+                }
                 let current_pos = audio_service_clone.get_current_pos() as f32;
                 main_window.global::<AudioState>().set_timing(
                     current_pos / main_window.global::<AudioState>().get_playback_length()
                 );
-                let chapter_len = main_window.global::<AudioState>().get_playback_length();
+                let book = main_window.global::<AudioState>().get_now_playing().id.to_string();
                 let _ = save_progress(
-                    &main_window.global::<AudioState>().get_now_playing().title.to_string(),
-                    get_progress(&main_window.global::<AudioState>().get_now_playing().title.to_string()).unwrap().current_chapter,
+                    &book,
+                    get_progress(&book).ok().and_then(|settings| settings.current_chapter),
                     main_window.global::<AudioState>().get_now_playing().book_url.as_str(),
-                    Some((current_pos / chapter_len) as f64)
+                    Some(current_pos as f64)
                 );
             });
         });
@@ -593,35 +1615,67 @@ fn handle_resume(
     main_window: &AppWindow,
     audio_state: &AudioState<'_>,
     audio_service: &AudioService,
+    download_manager: &DownloadManager,
 ) {
     let main_window_weak = main_window.as_weak();
     let audio_service_clone = audio_service.clone();
-    audio_state.on_resume(move |title| {
+    let download_manager_clone = download_manager.clone();
+    audio_state.on_resume(move |book| {
         let main_window_weak = main_window_weak.clone();
         let audio_service_clone = audio_service_clone.clone();
+        let download_manager_clone = download_manager_clone.clone();
         thread::spawn(move || {
             let audio_service_clone = audio_service_clone.clone();
             let main_window_weak = main_window_weak.clone();
             let _ = main_window_weak.upgrade_in_event_loop(move |main_window| {
-                let audio_path = music_dir().unwrap().as_path().join(title);
+                // Whatever was playing stops here, and this book may have a position to write
+                flush_progress();
+                let audio_path = book_dir(&book).unwrap();
                 let settings_file = audio_path.clone().join("settings.json");
                 let settings = settings::load(&settings_file).unwrap();
 
                 let play_pos = settings.current_chapter_time.unwrap();
-                audio_service_clone.start(audio_path.display().to_string());
-                main_window.global::<AudioState>().set_playback_length(audio_service_clone.get_chapter_len(
-                    &check_book_chapter_url(
-                        get_progress(&main_window.global::<AudioState>().get_now_playing().title.to_string())
-                            .unwrap().current_chapter.unwrap().try_into().unwrap(),
-                        main_window.global::<AudioState>().get_now_playing().title.to_string()
-                    ).unwrap().unwrap().display().to_string().as_str()
-                ).as_secs_f32());
-                audio_service_clone.seek(
-                    (play_pos as f32
-                        * main_window.global::<AudioState>().get_playback_length()) as f32
+                if let Some(offset) = settings
+                    .current_chapter
+                    .and_then(|chapter| settings.chapter_offsets.get(chapter as usize).copied())
+                {
+                    let audio_state = main_window.global::<AudioState>();
+                    audio_service_clone.start_chapters(
+                        single_file_path(&audio_path).display().to_string(),
+                        settings.chapter_offsets.clone(),
+                        settings.current_chapter.unwrap_or_default() as usize,
+                        play_pos as f32,
+                    );
+                    audio_state.set_playback_length(offset.duration() as f32);
+                    audio_state.set_now_playing(audio_state.get_book_view());
+                    audio_service_clone.play();
+                    audio_state.set_paused(false);
+                    audio_state.set_playing(true);
+                    return;
+                }
+                let chapter = settings.current_chapter.unwrap_or_default().max(0) as u32;
+                let Some(chapter_file) = check_book_chapter_url(chapter, book.to_string())
+                    .ok()
+                    .flatten()
+                else {
+                    log::error!("Chapter {} of {} isn't downloaded", chapter + 1, book);
+                    return;
+                };
+                let chapter_file = chapter_file.display().to_string();
+                audio_service_clone.start(chapter_file.clone());
+                main_window.global::<AudioState>().set_playback_length(
+                    audio_service_clone
+                        .get_chapter_len(&chapter_file)
+                        .map_or(1.0, |len| len.as_secs_f32()),
                 );
+                audio_service_clone.seek(play_pos as f32);
                 
                 let current_book_view = main_window.global::<AudioState>().get_book_view();
+                prefetch_chapters(
+                    &download_manager_clone,
+                    &current_book_view,
+                    settings.current_chapter.unwrap_or_default(),
+                );
 
                 main_window
                     .global::<AudioState>()
@@ -0,0 +1,371 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+use yt_dlp::fetcher::deps::LibraryInstaller;
+
+use crate::api::types::AudiodyError;
+
+use super::files::now;
+
+/// The external tools Audiody downloads and runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tool {
+    YtDlp,
+    Ffmpeg,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 2] = [Tool::YtDlp, Tool::Ffmpeg];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::YtDlp => "yt-dlp",
+            Tool::Ffmpeg => "ffmpeg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tool> {
+        Tool::ALL.into_iter().find(|tool| tool.name() == name)
+    }
+
+    fn version_arg(&self) -> &'static str {
+        match self {
+            Tool::YtDlp => "--version",
+            Tool::Ffmpeg => "-version",
+        }
+    }
+}
+
+/// What is known about an installed tool, kept in `binaries.json` next to the binaries.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstalledTool {
+    pub path: PathBuf,
+    pub version: String,
+    pub sha256: String,
+    pub installed_at: u64,
+    /// Set when the user pointed us at their own binary, those are never replaced
+    #[serde(default)]
+    pub user_provided: bool,
+    /// yt-dlp version to stay on when updating, like "2024.12.23" or "nightly"
+    #[serde(default)]
+    pub pinned: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Manifest {
+    tools: HashMap<String, InstalledTool>,
+}
+
+/// Where downloaded tools live
+/// Lin: /home/alice/.local/share/Audiody/bin
+/// Win: C:\Users\Alice\AppData\Roaming\Audiody\bin
+/// Mac: /Users/Alice/Library/Application Support/Audiody/bin
+pub fn bin_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let base_dir = dirs::data_dir().ok_or("Unable to get data directory")?;
+    let dir = base_dir.join("Audiody").join("bin");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn manifest_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(bin_dir()?.join("binaries.json"))
+}
+
+fn load_manifest() -> Manifest {
+    manifest_file()
+        .ok()
+        .and_then(|file| fs::read_to_string(file).ok())
+        .and_then(|manifest| serde_json::from_str(&manifest).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(manifest: &Manifest) -> Result<(), Box<dyn std::error::Error>> {
+    fs::write(manifest_file()?, serde_json::to_string_pretty(manifest)?)?;
+    Ok(())
+}
+
+/// The recorded details of a tool, if it has been installed.
+pub fn installed(tool: Tool) -> Option<InstalledTool> {
+    load_manifest().tools.remove(tool.name())
+}
+
+/// Path of the binary to run for `tool`.
+pub fn tool_path(tool: Tool) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match installed(tool) {
+        Some(installed) => Ok(installed.path),
+        None => Ok(bin_dir()?.join(executable_name(tool))),
+    }
+}
+
+fn executable_name(tool: Tool) -> String {
+    if cfg!(windows) {
+        format!("{}.exe", tool.name())
+    } else {
+        tool.name().to_string()
+    }
+}
+
+/// Makes sure every tool is installed and hasn't been tampered with, downloading whatever is
+/// missing or doesn't match its recorded checksum.
+pub async fn ensure_installed() -> Result<(), Box<dyn std::error::Error>> {
+    for tool in Tool::ALL {
+        match installed(tool) {
+            Some(installed) if installed.user_provided => {
+                if !installed.path.exists() {
+                    log::warn!(
+                        "{} is set to {}, which doesn't exist",
+                        tool.name(),
+                        installed.path.display()
+                    );
+                }
+            }
+            Some(installed) if verify(&installed) => {}
+            Some(installed) => {
+                log::warn!(
+                    "{} at {} doesn't match its checksum, reinstalling",
+                    tool.name(),
+                    installed.path.display()
+                );
+                install(tool).await?;
+            }
+            None => {
+                install(tool).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Downloads the latest release of `tool` and records it.
+pub async fn install(tool: Tool) -> Result<InstalledTool, Box<dyn std::error::Error>> {
+    log::info!("Downloading {}", tool.name());
+    let installer = LibraryInstaller::new(bin_dir()?);
+    let path = match tool {
+        Tool::YtDlp => installer.install_youtube(None).await?,
+        Tool::Ffmpeg => installer.install_ffmpeg(None).await?,
+    };
+    let pinned = installed(tool).and_then(|installed| installed.pinned);
+    let installed = record(tool, &path, false, pinned)?;
+
+    // A fresh download is always the latest release, so go back to the pinned one
+    if tool == Tool::YtDlp && installed.pinned.is_some() {
+        return update(tool, installed.pinned.as_deref());
+    }
+    Ok(installed)
+}
+
+/// Updates a tool in place. yt-dlp can move to a specific version with `version`, which is
+/// then pinned for later updates. ffmpeg always gets reinstalled from the latest build.
+pub fn update(
+    tool: Tool,
+    version: Option<&str>,
+) -> Result<InstalledTool, Box<dyn std::error::Error>> {
+    let current = installed(tool).ok_or(format!("{} is not installed", tool.name()))?;
+    if current.user_provided {
+        return Err(format!(
+            "{} is set to {}, update it yourself",
+            tool.name(),
+            current.path.display()
+        )
+        .into());
+    }
+
+    match tool {
+        Tool::YtDlp => {
+            let target = version
+                .map(|version| version.to_string())
+                .or(current.pinned.clone())
+                .unwrap_or_else(|| "stable".to_string());
+            log::info!("Updating yt-dlp to {}", target);
+            run(tool, ["--update-to", target.as_str()])?;
+            record(
+                tool,
+                &current.path,
+                false,
+                version
+                    .map(|version| version.to_string())
+                    .or(current.pinned),
+            )
+        }
+        Tool::Ffmpeg => tokio::runtime::Runtime::new()?.block_on(install(tool)),
+    }
+}
+
+/// Uses a binary the user already has instead of a downloaded one.
+pub fn set_user_path(tool: Tool, path: &Path) -> Result<InstalledTool, Box<dyn std::error::Error>> {
+    if !path.is_file() {
+        return Err(format!("{} is not a file", path.display()).into());
+    }
+    record(tool, path, true, None)
+}
+
+// Stores the version and checksum of the binary at `path`
+fn record(
+    tool: Tool,
+    path: &Path,
+    user_provided: bool,
+    pinned: Option<String>,
+) -> Result<InstalledTool, Box<dyn std::error::Error>> {
+    let version = read_version(tool, path)?;
+    let installed = InstalledTool {
+        path: path.to_path_buf(),
+        sha256: checksum(path)?,
+        version,
+        installed_at: now(),
+        user_provided,
+        pinned,
+    };
+    log::info!(
+        "Using {} {} at {}",
+        tool.name(),
+        installed.version,
+        path.display()
+    );
+
+    let mut manifest = load_manifest();
+    manifest
+        .tools
+        .insert(tool.name().to_string(), installed.clone());
+    save_manifest(&manifest)?;
+    Ok(installed)
+}
+
+fn verify(installed: &InstalledTool) -> bool {
+    checksum(&installed.path).is_ok_and(|sha256| sha256 == installed.sha256)
+}
+
+fn checksum(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn read_version(tool: Tool, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let output = Command::new(path).arg(tool.version_arg()).output()?;
+    check_output(tool, &output)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or_default();
+    // ffmpeg says "ffmpeg version 7.1 Copyright ...", yt-dlp just prints the version
+    Ok(match tool {
+        Tool::YtDlp => first_line.trim().to_string(),
+        Tool::Ffmpeg => first_line
+            .split_whitespace()
+            .nth(2)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+/// Runs a tool and waits for it. A non-zero exit becomes an error carrying its stderr.
+pub fn run<I, S>(tool: Tool, args: I) -> Result<Output, AudiodyError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let (mut command, path) = command(tool)?;
+    let output = command
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| failed_to_start(tool, &path, e))?;
+    check_output(tool, &output)?;
+    Ok(output)
+}
+
+/// Like `run`, but hands every line the tool prints to `on_line` as soon as it's printed,
+/// for tools that report their progress as they go.
+pub fn run_with_lines<I, S>(
+    tool: Tool,
+    args: I,
+    mut on_line: impl FnMut(&str),
+) -> Result<(), AudiodyError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let (mut command, path) = command(tool)?;
+    let mut child = command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed_to_start(tool, &path, e))?;
+
+    // stderr is read on the side, a full pipe would stall the tool
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr = thread::spawn(move || {
+        let mut output = vec![];
+        let _ = stderr.read_to_end(&mut output);
+        output
+    });
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            on_line(&line);
+        }
+    }
+
+    let status = child.wait().map_err(|e| failed_to_start(tool, &path, e))?;
+    let output = Output {
+        status,
+        stdout: vec![],
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    check_output(tool, &output)
+}
+
+// The command for `tool` and the binary it runs
+fn command(tool: Tool) -> Result<(Command, PathBuf), AudiodyError> {
+    let path = tool_path(tool).map_err(|e| AudiodyError::ProviderError(e.to_string()))?;
+    let mut command = Command::new(&path);
+    // yt-dlp needs ffmpeg to extract audio, point it at ours
+    if tool == Tool::YtDlp {
+        if let Ok(ffmpeg) = tool_path(Tool::Ffmpeg) {
+            if ffmpeg.exists() {
+                command.arg("--ffmpeg-location").arg(ffmpeg);
+            }
+        }
+    }
+    Ok((command, path))
+}
+
+fn failed_to_start(tool: Tool, path: &Path, error: std::io::Error) -> AudiodyError {
+    AudiodyError::ToolFailed {
+        tool: tool.name().to_string(),
+        code: None,
+        stderr: format!("Failed to run {}: {}", path.display(), error),
+    }
+}
+
+fn check_output(tool: Tool, output: &Output) -> Result<(), AudiodyError> {
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    log::error!("{} failed: {}", tool.name(), stderr.trim());
+    Err(AudiodyError::ToolFailed {
+        tool: tool.name().to_string(),
+        code: output.status.code(),
+        // The last lines are the ones that say what went wrong
+        stderr: stderr
+            .lines()
+            .rev()
+            .take(5)
+            .collect::<Vec<&str>>()
+            .into_iter()
+            .rev()
+            .collect::<Vec<&str>>()
+            .join("\n"),
+    })
+}
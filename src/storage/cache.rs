@@ -0,0 +1,434 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::files::now;
+use super::setup::config_dir;
+
+/// Search results go stale quickly, new books show up all the time
+pub const SEARCH_TTL: Duration = Duration::from_secs(60 * 60);
+/// Book pages hardly ever change once they are up
+pub const BOOK_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Feeds get checked every refresh, so only skip the request for a little while
+pub const FEED_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Once the cache grows past this the least recently used entries get dropped
+const CACHE_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CacheEntry {
+    key: String,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    fetched_at: u64,
+    last_used: u64,
+    size: u64,
+}
+
+impl CacheEntry {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        now().saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+}
+
+fn cache_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = config_dir()?.join("cache");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// Entries are stored as <hash>.json for the metadata next to <hash>.body for the content
+fn entry_paths(key: &str) -> Option<(PathBuf, PathBuf)> {
+    let hash: String = Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let dir = cache_dir().ok()?;
+    Some((
+        dir.join(format!("{}.json", hash)),
+        dir.join(format!("{}.body", hash)),
+    ))
+}
+
+fn read_entry(key: &str) -> Option<(CacheEntry, String)> {
+    let (meta_path, body_path) = entry_paths(key)?;
+    let entry: CacheEntry = serde_json::from_str(&fs::read_to_string(meta_path).ok()?).ok()?;
+    // Two keys sharing a hash is unlikely, but don't hand back the wrong page if it happens
+    if entry.key != key {
+        return None;
+    }
+    let body = fs::read_to_string(body_path).ok()?;
+    Some((entry, body))
+}
+
+fn write_entry(entry: &CacheEntry, body: &str) {
+    let result = entry_paths(&entry.key)
+        .ok_or_else(|| "No cache directory".into())
+        .and_then(
+            |(meta_path, body_path)| -> Result<(), Box<dyn std::error::Error>> {
+                fs::write(body_path, body)?;
+                fs::write(meta_path, serde_json::to_string(entry)?)?;
+                Ok(())
+            },
+        );
+    if let Err(e) = result {
+        log::warn!("Failed to cache {}: {}", entry.key, e);
+    }
+}
+
+// Bumps the entry so eviction knows it's still in use
+fn touch(mut entry: CacheEntry) {
+    entry.last_used = now();
+    if let Some((meta_path, _)) = entry_paths(&entry.key) {
+        if let Ok(meta) = serde_json::to_string(&entry) {
+            let _ = fs::write(meta_path, meta);
+        }
+    }
+}
+
+/// Sends a GET request through the cache. A fresh copy is returned without touching the
+/// network, an expired one is revalidated with its ETag/Last-Modified, and when the server
+/// can't be reached at all the last copy is used so things keep working offline.
+pub fn fetch(request: ureq::Request, ttl: Duration) -> Result<String, Box<ureq::Error>> {
+    let key = request.url().to_string();
+    let cached = read_entry(&key);
+
+    if let Some((entry, body)) = cached {
+        if entry.is_fresh(ttl) {
+            log::debug!("Cache hit: {}", key);
+            touch(entry);
+            return Ok(body);
+        }
+        return revalidate(request, entry, body);
+    }
+
+    let response = request.call()?;
+    store_response(key, response)
+}
+
+fn revalidate(
+    mut request: ureq::Request,
+    mut entry: CacheEntry,
+    body: String,
+) -> Result<String, Box<ureq::Error>> {
+    if let Some(etag) = &entry.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &entry.last_modified {
+        request = request.set("If-Modified-Since", last_modified);
+    }
+
+    match request.call() {
+        Ok(response) if response.status() == 304 => {
+            log::debug!("Cache revalidated: {}", entry.key);
+            entry.fetched_at = now();
+            entry.last_used = now();
+            write_entry(&entry, &body);
+            Ok(body)
+        }
+        Ok(response) => store_response(entry.key, response),
+        // Offline or the server is having a bad day, an old copy beats nothing
+        Err(ureq::Error::Transport(e)) => {
+            log::warn!("Using cached copy of {}: {}", entry.key, e);
+            Ok(body)
+        }
+        Err(ureq::Error::Status(status, _)) if status >= 500 => {
+            log::warn!(
+                "Using cached copy of {}: server answered {}",
+                entry.key,
+                status
+            );
+            Ok(body)
+        }
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+fn store_response(key: String, response: ureq::Response) -> Result<String, Box<ureq::Error>> {
+    let etag = response.header("ETag").map(|etag| etag.to_string());
+    let last_modified = response
+        .header("Last-Modified")
+        .map(|last_modified| last_modified.to_string());
+    let body = response.into_string().map_err(ureq::Error::from)?;
+
+    write_entry(
+        &CacheEntry {
+            key,
+            etag,
+            last_modified,
+            fetched_at: now(),
+            last_used: now(),
+            size: body.len() as u64,
+        },
+        &body,
+    );
+    evict();
+    Ok(body)
+}
+
+/// A result cached with `store`, if there is one younger than `ttl`.
+pub fn load<T: DeserializeOwned>(key: &str, ttl: Duration) -> Option<T> {
+    let (entry, body) = read_entry(key)?;
+    if !entry.is_fresh(ttl) {
+        return None;
+    }
+    let value = serde_json::from_str(&body).ok()?;
+    touch(entry);
+    Some(value)
+}
+
+/// A result cached with `store` no matter how old it is, for when fetching failed.
+pub fn load_stale<T: DeserializeOwned>(key: &str) -> Option<T> {
+    let (_, body) = read_entry(key)?;
+    serde_json::from_str(&body).ok()
+}
+
+/// Caches an already parsed result, for providers that don't go through `fetch`.
+pub fn store<T: Serialize>(key: &str, value: &T) {
+    let body = match serde_json::to_string(value) {
+        Ok(body) => body,
+        Err(e) => {
+            log::warn!("Failed to cache {}: {}", key, e);
+            return;
+        }
+    };
+    write_entry(
+        &CacheEntry {
+            key: key.to_string(),
+            fetched_at: now(),
+            last_used: now(),
+            size: body.len() as u64,
+            ..Default::default()
+        },
+        &body,
+    );
+    evict();
+}
+
+fn evict() {
+    evict_to(CACHE_SIZE_LIMIT);
+}
+
+// Removes the least recently used entries until the cache fits in `limit` bytes
+fn evict_to(limit: u64) {
+    let Ok(dir) = cache_dir() else {
+        return;
+    };
+    let Ok(items) = fs::read_dir(&dir) else {
+        return;
+    };
+
+    let mut entries: Vec<(PathBuf, CacheEntry)> = items
+        .filter_map(|item| item.ok())
+        .map(|item| item.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .filter_map(|path| {
+            let entry = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+            Some((path, entry))
+        })
+        .collect();
+
+    let mut total: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+    if total <= limit {
+        return;
+    }
+
+    entries.sort_by_key(|(_, entry)| entry.last_used);
+    for (meta_path, entry) in entries {
+        if total <= limit {
+            break;
+        }
+        log::debug!("Evicting {} from the cache", entry.key);
+        let _ = fs::remove_file(meta_path.with_extension("body"));
+        let _ = fs::remove_file(&meta_path);
+        total = total.saturating_sub(entry.size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::setup::test_env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Answers every request with whatever `respond` makes of its headers, and returns the
+    // url to fetch along with the headers of every request that came in
+    fn serve<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/page", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    head.push_str(&line);
+                    line.clear();
+                }
+                let response = respond(&head);
+                seen.lock().unwrap().push(head);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, requests)
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    fn status(code: u16) -> String {
+        format!(
+            "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            code
+        )
+    }
+
+    #[test]
+    fn fresh_copy_is_used_until_it_expires() {
+        let _env = test_env();
+        let (url, requests) = serve(|_| ok("first"));
+
+        assert_eq!(fetch(ureq::get(&url), SEARCH_TTL).unwrap(), "first");
+        assert_eq!(fetch(ureq::get(&url), SEARCH_TTL).unwrap(), "first");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Nothing is fresh with no time to live, so this one goes to the server again
+        fetch(ureq::get(&url), Duration::ZERO).unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn expired_copy_is_revalidated_with_its_etag() {
+        let _env = test_env();
+        let (url, requests) = serve(|head| {
+            if head.contains("If-None-Match: \"v1\"") {
+                status(304)
+            } else {
+                ok("first")
+            }
+        });
+
+        fetch(ureq::get(&url), SEARCH_TTL).unwrap();
+        // A 304 has no body, the cached one is handed back
+        assert_eq!(fetch(ureq::get(&url), Duration::ZERO).unwrap(), "first");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("If-None-Match: \"v1\""));
+    }
+
+    #[test]
+    fn cached_copy_is_used_when_the_server_is_down() {
+        let _env = test_env();
+        let failing = Arc::new(Mutex::new(false));
+        let fail = failing.clone();
+        let (url, _) = serve(move |_| {
+            if *fail.lock().unwrap() {
+                status(503)
+            } else {
+                ok("first")
+            }
+        });
+        fetch(ureq::get(&url), SEARCH_TTL).unwrap();
+
+        *failing.lock().unwrap() = true;
+        assert_eq!(fetch(ureq::get(&url), Duration::ZERO).unwrap(), "first");
+
+        // Nothing listening at all
+        let offline = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/page", listener.local_addr().unwrap())
+        };
+        write_entry(
+            &CacheEntry {
+                key: offline.clone(),
+                size: 3,
+                ..Default::default()
+            },
+            "old",
+        );
+        assert_eq!(fetch(ureq::get(&offline), SEARCH_TTL).unwrap(), "old");
+    }
+
+    #[test]
+    fn client_errors_are_not_hidden_by_the_cache() {
+        let _env = test_env();
+        let (url, _) = serve(|_| status(404));
+        write_entry(
+            &CacheEntry {
+                key: url.clone(),
+                size: 3,
+                ..Default::default()
+            },
+            "old",
+        );
+
+        let error = fetch(ureq::get(&url), SEARCH_TTL).unwrap_err();
+        assert!(matches!(*error, ureq::Error::Status(404, _)));
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let _env = test_env();
+        let _ = fs::remove_dir_all(cache_dir().unwrap());
+        for (key, last_used) in [("evict-old", 1), ("evict-newest", 3), ("evict-middle", 2)] {
+            write_entry(
+                &CacheEntry {
+                    key: key.to_string(),
+                    fetched_at: now(),
+                    last_used,
+                    size: 10,
+                    ..Default::default()
+                },
+                "0123456789",
+            );
+        }
+
+        evict_to(20);
+
+        assert!(read_entry("evict-old").is_none());
+        assert!(read_entry("evict-middle").is_some());
+        assert!(read_entry("evict-newest").is_some());
+        // Reading a value marks it as used, so it outlives the ones that weren't
+        write_entry(
+            &CacheEntry {
+                key: "evict-value".to_string(),
+                fetched_at: now(),
+                last_used: 0,
+                size: 10,
+                ..Default::default()
+            },
+            "7",
+        );
+        assert_eq!(load::<u32>("evict-value", SEARCH_TTL), Some(7));
+        evict_to(20);
+        assert!(read_entry("evict-middle").is_none());
+        assert!(read_entry("evict-newest").is_some());
+        assert!(read_entry("evict-value").is_some());
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::api::types::Provider;
+
+use super::downloads::DownloadPolicy;
+use super::files::{read_with_backup, write_atomic};
+use super::setup::config_dir;
+
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Playback speeds the player can be set to
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+/// Longest skip the skip buttons can be set to, in seconds
+pub const MAX_SKIP_SECS: u32 = 600;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    /// Follows the system's light or dark setting
+    System,
+    Light,
+    #[default]
+    Dark,
+}
+
+impl Theme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::System => "System",
+            Theme::Light => "Light",
+            Theme::Dark => "Dark",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "system" => Theme::System,
+            "light" => Theme::Light,
+            _ => Theme::Dark,
+        }
+    }
+}
+
+/// Everything the settings view can change, kept in `config.json`. Anything missing from the
+/// file falls back to its default, so older files keep loading as fields are added.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AppConfig {
+    pub version: u32,
+    /// Where books are saved, the music folder when not set
+    pub library_dir: Option<PathBuf>,
+    /// Speed the player starts at
+    pub default_speed: f32,
+    pub skip_back_secs: u32,
+    pub skip_forward_secs: u32,
+    pub downloads: DownloadPolicy,
+    /// Whether downloaded mp3 chapters get their tags and cover rewritten
+    pub tag_downloads: bool,
+    pub theme: Theme,
+    /// Providers left out of searches
+    pub disabled_providers: Vec<Provider>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            library_dir: None,
+            default_speed: 1.0,
+            skip_back_secs: 10,
+            skip_forward_secs: 10,
+            downloads: DownloadPolicy::default(),
+            tag_downloads: true,
+            theme: Theme::default(),
+            disabled_providers: vec![],
+        }
+    }
+}
+
+impl AppConfig {
+    /// The providers searches go to, in the order they're shown.
+    pub fn enabled_providers(&self) -> Vec<Provider> {
+        Provider::ALL
+            .into_iter()
+            .filter(|provider| !self.disabled_providers.contains(provider))
+            .collect()
+    }
+
+    pub fn set_provider_enabled(&mut self, provider: Provider, enabled: bool) {
+        self.disabled_providers
+            .retain(|disabled| *disabled != provider);
+        if !enabled {
+            self.disabled_providers.push(provider);
+        }
+    }
+
+    // Hand edited files can have anything in them
+    fn clamped(mut self) -> Self {
+        if !self.default_speed.is_finite() {
+            self.default_speed = 1.0;
+        }
+        self.default_speed = self.default_speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.skip_back_secs = self.skip_back_secs.clamp(1, MAX_SKIP_SECS);
+        self.skip_forward_secs = self.skip_forward_secs.clamp(1, MAX_SKIP_SECS);
+        self.version = CONFIG_VERSION;
+        self
+    }
+}
+
+static CONFIG: Mutex<Option<AppConfig>> = Mutex::new(None);
+// Why the config on disk couldn't be used, for the settings page to say
+static LOAD_PROBLEM: Mutex<Option<String>> = Mutex::new(None);
+
+fn config_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config_dir()?.join("config.json"))
+}
+
+// The download policy had its own file before there was a config
+fn legacy_policy_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config_dir()?.join("download_policy.json"))
+}
+
+fn load() -> AppConfig {
+    let file = match config_file() {
+        Ok(file) => file,
+        Err(e) => {
+            log::warn!("No config directory, using the default config: {}", e);
+            return AppConfig::default();
+        }
+    };
+
+    let parse = |contents: &[u8]| -> io::Result<AppConfig> {
+        serde_json::from_slice(contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    match read_with_backup(&file, parse) {
+        Ok(config) => return config.clamped(),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            log::warn!(
+                "Failed to read {}, using the default config: {}",
+                file.display(),
+                e
+            );
+            // Out of the way of the next save, whatever was in it may still be wanted
+            let broken = file.with_extension("json.broken");
+            let problem = match fs::rename(&file, &broken) {
+                Ok(()) => format!(
+                    "Settings couldn't be read and were reset, the old ones are in {}",
+                    broken.display()
+                ),
+                Err(_) => format!("Settings couldn't be read and were reset: {}", e),
+            };
+            *LOAD_PROBLEM.lock().unwrap() = Some(problem);
+            return AppConfig::default();
+        }
+        Err(_) => {}
+    }
+
+    // First start with a config, bring over the old download policy if there is one
+    let mut config = AppConfig::default();
+    let Ok(legacy) = legacy_policy_file() else {
+        return config;
+    };
+    if let Some(policy) = fs::read_to_string(&legacy)
+        .ok()
+        .and_then(|policy| serde_json::from_str(&policy).ok())
+    {
+        config.downloads = policy;
+        match save(&config) {
+            Ok(()) => {
+                let _ = fs::remove_file(&legacy);
+            }
+            Err(e) => log::warn!("Failed to save the migrated config: {}", e),
+        }
+    }
+    config
+}
+
+fn save(config: &AppConfig) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomic(
+        &config_file()?,
+        serde_json::to_string_pretty(config)?.as_bytes(),
+    )?;
+    Ok(())
+}
+
+/// The current config, read from disk the first time it's asked for.
+pub fn get() -> AppConfig {
+    CONFIG.lock().unwrap().get_or_insert_with(load).clone()
+}
+
+/// Why the saved config couldn't be used, if it couldn't. It's kept as `config.json.broken`
+/// and the defaults are used instead.
+pub fn load_problem() -> Option<String> {
+    get();
+    LOAD_PROBLEM.lock().unwrap().clone()
+}
+
+/// Changes the config and saves it, returning the config as saved. Out of range values are
+/// pulled back into range.
+pub fn update(
+    change: impl FnOnce(&mut AppConfig),
+) -> Result<AppConfig, Box<dyn std::error::Error>> {
+    let mut cached = CONFIG.lock().unwrap();
+    let mut config = cached.get_or_insert_with(load).clone();
+    change(&mut config);
+    let config = config.clamped();
+    save(&config)?;
+    *cached = Some(config.clone());
+    Ok(config)
+}
@@ -0,0 +1,116 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::{io::Reader, DynamicImage};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use webp::Encoder;
+
+use super::setup::config_dir;
+
+/// Covers in lists never need to be bigger than this
+pub const THUMBNAIL_SIZE: u32 = 256;
+const WEBP_QUALITY: f32 = 75.0;
+/// Covers put inside audio files are kept small, some car stereos won't show big ones
+pub const EMBEDDED_COVER_SIZE: u32 = 600;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverSize {
+    Thumbnail,
+    Full,
+}
+
+fn covers_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let dir = config_dir()?.join("covers");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn cover_file(url: &str, size: CoverSize) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let hash: String = Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(covers_dir()?.join(match size {
+        CoverSize::Thumbnail => format!("{}_thumb.webp", hash),
+        CoverSize::Full => format!("{}.webp", hash),
+    }))
+}
+
+/// Path to a cached copy of the cover at `url`, downloading it and making the thumbnail
+/// the first time. `url` can also be a local file, like the cover of a saved book.
+pub fn get_cover(url: &str, size: CoverSize) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if url.is_empty() {
+        return Err("Book has no cover".into());
+    }
+    let cached = cover_file(url, size)?;
+    if cached.exists() {
+        return Ok(cached);
+    }
+
+    let image = if Path::new(url).is_file() {
+        // Saved covers are already full size webp files
+        if size == CoverSize::Full {
+            return Ok(PathBuf::from(url));
+        }
+        image::open(url)?
+    } else {
+        log::info!("Downloading cover {}", url);
+        decode_image(&download(url)?)?
+    };
+
+    save_as_webp(&image, &cover_file(url, CoverSize::Full)?)?;
+    save_as_webp(
+        &image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+        &cover_file(url, CoverSize::Thumbnail)?,
+    )?;
+    Ok(cached)
+}
+
+fn download(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut url = url.to_string();
+    // Google hosted covers come tiny unless asked for the original size
+    if url.contains("lh3.googleusercontent.com") {
+        url.push('0');
+    }
+    let response = ureq::get(&url).call()?;
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decodes an image in whatever format it came in.
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    Ok(Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+/// Writes an image as a lossy webp file.
+pub fn save_as_webp(image: &DynamicImage, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let rgba_image = image.to_rgba8();
+    let encoder = Encoder::from_rgba(rgba_image.as_raw(), rgba_image.width(), rgba_image.height());
+    fs::write(path, &*encoder.encode(WEBP_QUALITY))?;
+    Ok(())
+}
+
+/// The cover at `path` as a jpeg no bigger than `EMBEDDED_COVER_SIZE`, for putting inside
+/// audio files. Most players don't know webp.
+pub fn cover_as_jpeg(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut image = image::open(path)?;
+    if image.width() > EMBEDDED_COVER_SIZE || image.height() > EMBEDDED_COVER_SIZE {
+        image = image.thumbnail(EMBEDDED_COVER_SIZE, EMBEDDED_COVER_SIZE);
+    }
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+    Ok(jpeg)
+}
+
+/// Downloads a cover from `url` and stores it as a webp file at `path`, whatever format it
+/// was in originally.
+pub fn download_as_webp(url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let cached = get_cover(url, CoverSize::Full)?;
+    fs::copy(cached, path)?;
+    Ok(())
+}
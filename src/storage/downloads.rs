@@ -0,0 +1,898 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config;
+use super::files::{now, read_with_backup, write_atomic};
+use super::library::{self, add_book, book_id};
+use super::save::{download_audio_with_progress, get_chapter_offsets, save_cover};
+use super::setup::{config_dir, music_dir};
+
+/// How many downloads run at the same time
+pub const DEFAULT_WORKERS: usize = 2;
+/// A failing download is tried this many times before it's marked as failed
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for every one after
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Progress goes to the UI at most this often, so big downloads don't flood the event loop
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// How often a job held back by the download policy checks again
+const POLICY_RECHECK: Duration = Duration::from_secs(30);
+
+/// When downloads are allowed to run, and how far ahead of the listener to download.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct DownloadPolicy {
+    /// Chapters after the playing one to keep downloaded, 0 turns prefetching off
+    pub prefetch_chapters: usize,
+    /// Whether to download on mobile data and other metered connections
+    pub allow_metered: bool,
+    /// Downloads wait while the library's disk has less space than this left
+    pub min_free_space_mb: u64,
+}
+
+impl Default for DownloadPolicy {
+    fn default() -> Self {
+        Self {
+            prefetch_chapters: 2,
+            allow_metered: false,
+            min_free_space_mb: 500,
+        }
+    }
+}
+
+impl DownloadPolicy {
+    pub fn load() -> Self {
+        config::get().downloads
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        config::update(|config| config.downloads = self.clone())?;
+        Ok(())
+    }
+
+    // Why downloads can't run right now, if they can't
+    fn blocked(&self) -> Option<&'static str> {
+        if !self.allow_metered && is_metered() {
+            return Some("Waiting for an unmetered connection");
+        }
+        let Ok(dir) = music_dir() else {
+            return Some("Waiting for the library folder to be available");
+        };
+        let free_space = fs2::available_space(dir).ok();
+        if free_space.is_some_and(|free_space| free_space < self.min_free_space_mb * 1024 * 1024) {
+            return Some("Waiting for free disk space");
+        }
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum JobKind {
+    Chapter {
+        chapter: i32,
+        url: String,
+    },
+    /// Every chapter of a book, one after the other
+    Book {
+        chapter_urls: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Paused,
+    Failed,
+    Done,
+}
+
+impl JobStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "Queued",
+            JobStatus::Running => "Downloading",
+            JobStatus::Paused => "Paused",
+            JobStatus::Failed => "Failed",
+            JobStatus::Done => "Done",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadJob {
+    pub id: u64,
+    /// Title of the book, it gets added to the library when the job runs
+    pub book: String,
+    pub book_url: String,
+    #[serde(default)]
+    pub image_url: String,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    /// Bytes of the file currently downloading
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Size of the file currently downloading, when the server says
+    #[serde(default)]
+    pub total: Option<u64>,
+    /// Chapters already on disk, for whole book jobs
+    #[serde(default)]
+    pub chapters_done: usize,
+    /// Queued by prefetching rather than by the user, these leave the list once done
+    #[serde(default)]
+    pub prefetch: bool,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Unix time before which a failed job isn't tried again
+    #[serde(default)]
+    pub retry_at: u64,
+}
+
+impl DownloadJob {
+    /// Between 0 and 1, None while the size of a chapter download isn't known.
+    pub fn progress(&self) -> Option<f32> {
+        if self.status == JobStatus::Done {
+            return Some(1.0);
+        }
+        let file_progress = self
+            .total
+            .filter(|total| *total > 0)
+            .map(|total| self.downloaded as f32 / total as f32);
+        match &self.kind {
+            JobKind::Chapter { .. } => file_progress,
+            JobKind::Book { chapter_urls } => Some(
+                (self.chapters_done as f32 + file_progress.unwrap_or_default())
+                    / chapter_urls.len().max(1) as f32,
+            ),
+        }
+    }
+
+    /// What is being downloaded, like "Chapter 3" or "Whole book"
+    pub fn description(&self) -> String {
+        match &self.kind {
+            JobKind::Chapter { chapter, .. } => format!("Chapter {}", chapter + 1),
+            JobKind::Book { chapter_urls } => format!(
+                "Whole book, {} of {} chapters",
+                self.chapters_done,
+                chapter_urls.len()
+            ),
+        }
+    }
+
+    // Queues the job again after a failed attempt, waiting twice as long each time, until
+    // it has run out of attempts. Returns the status it ends up with.
+    fn failed(&mut self, error: &str, now: u64) -> JobStatus {
+        self.attempts += 1;
+        self.error = Some(error.to_string());
+        if self.attempts >= MAX_ATTEMPTS {
+            log::error!("Giving up on download of {}: {}", self.book, error);
+            self.status = JobStatus::Failed;
+        } else {
+            let delay = RETRY_DELAY * 2u32.pow(self.attempts - 1);
+            log::warn!(
+                "Download of {} failed, retrying in {}s: {}",
+                self.book,
+                delay.as_secs(),
+                error
+            );
+            self.status = JobStatus::Queued;
+            self.retry_at = now + delay.as_secs();
+        }
+        self.status
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(self.status, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+type Listener = Box<dyn Fn(Vec<DownloadJob>) + Send>;
+/// Told the downloaded file once a job is done, or why it failed for good
+type Waiter = Box<dyn FnOnce(Result<PathBuf, String>) + Send>;
+
+struct Shared {
+    jobs: Mutex<Vec<DownloadJob>>,
+    policy: Mutex<DownloadPolicy>,
+    // Set while the library is being moved, only changed with `jobs` locked
+    moving: AtomicBool,
+    // Woken whenever a job becomes runnable
+    wake: Condvar,
+    listener: Mutex<Option<Listener>>,
+    // Only kept in memory, they're gone after a restart along with whoever was waiting
+    waiters: Mutex<Vec<(u64, Waiter)>>,
+}
+
+/// Owns the download queue. Jobs are kept in `downloads.json` so they carry on after a
+/// restart, and a fixed number of worker threads work through them in order.
+#[derive(Clone)]
+pub struct DownloadManager {
+    shared: Arc<Shared>,
+}
+
+impl DownloadManager {
+    pub fn new(workers: usize) -> Self {
+        let manager = Self::load();
+        for _ in 0..workers.max(1) {
+            let manager = manager.clone();
+            thread::spawn(move || manager.work());
+        }
+        manager
+    }
+
+    // The saved queue, with nobody working through it yet
+    fn load() -> Self {
+        let mut jobs = load_jobs();
+        // Whatever was running when the app closed starts over
+        for job in jobs
+            .iter_mut()
+            .filter(|job| job.status == JobStatus::Running)
+        {
+            job.status = JobStatus::Queued;
+        }
+
+        Self {
+            shared: Arc::new(Shared {
+                jobs: Mutex::new(jobs),
+                policy: Mutex::new(DownloadPolicy::load()),
+                moving: AtomicBool::new(false),
+                wake: Condvar::new(),
+                listener: Mutex::new(None),
+                waiters: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Called with every job whenever one is added, changes state or makes progress.
+    pub fn on_change(&self, listener: impl Fn(Vec<DownloadJob>) + Send + 'static) {
+        *self.shared.listener.lock().unwrap() = Some(Box::new(listener));
+        self.notify();
+    }
+
+    pub fn jobs(&self) -> Vec<DownloadJob> {
+        self.lock().clone()
+    }
+
+    pub fn policy(&self) -> DownloadPolicy {
+        self.shared.policy.lock().unwrap().clone()
+    }
+
+    /// Changes and saves the download policy, waiting jobs are checked against it straight away.
+    pub fn set_policy(&self, policy: DownloadPolicy) -> Result<(), Box<dyn std::error::Error>> {
+        policy.save()?;
+        *self.shared.policy.lock().unwrap() = policy;
+        let mut jobs = self.lock();
+        for job in jobs
+            .iter_mut()
+            .filter(|job| job.status == JobStatus::Queued)
+        {
+            job.retry_at = job.retry_at.min(now());
+        }
+        self.changed(jobs);
+        Ok(())
+    }
+
+    /// Moves the library to `target`, see `library::move_library`. Downloads wait until it's
+    /// done, and it doesn't start while one is running.
+    pub fn move_library(
+        &self,
+        target: &Path,
+        on_progress: impl FnMut(u64, u64),
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        {
+            let jobs = self.lock();
+            if jobs.iter().any(|job| job.status == JobStatus::Running) {
+                return Err("Wait for the running downloads to finish, or pause them".into());
+            }
+            self.shared.moving.store(true, Ordering::SeqCst);
+        }
+        let result = library::move_library(target, on_progress);
+
+        let mut jobs = self.lock();
+        self.shared.moving.store(false, Ordering::SeqCst);
+        // The jobs that were held back can start straight away
+        for job in jobs
+            .iter_mut()
+            .filter(|job| job.status == JobStatus::Queued)
+        {
+            job.retry_at = job.retry_at.min(now());
+        }
+        self.changed(jobs);
+        result
+    }
+
+    /// Makes sure the chapters after `chapter` are downloaded, as many as the policy says.
+    /// Chapters already on disk finish straight away.
+    pub fn prefetch(
+        &self,
+        book: &str,
+        book_url: &str,
+        image_url: &str,
+        chapter_urls: &[String],
+        chapter: usize,
+    ) {
+        let count = self.policy().prefetch_chapters;
+        for (next, url) in chapter_urls
+            .iter()
+            .enumerate()
+            .skip(chapter + 1)
+            .take(count)
+            // Saved books list their local files, those are there already
+            .filter(|(_, url)| url.starts_with("http"))
+        {
+            let kind = JobKind::Chapter {
+                chapter: next as i32,
+                url: url.clone(),
+            };
+            self.add(book, book_url, image_url, kind, true);
+        }
+    }
+
+    /// Queues a single chapter, unless it's already queued.
+    pub fn enqueue_chapter(
+        &self,
+        book: &str,
+        book_url: &str,
+        image_url: &str,
+        chapter: i32,
+        url: &str,
+    ) -> u64 {
+        self.enqueue(
+            book,
+            book_url,
+            image_url,
+            JobKind::Chapter {
+                chapter,
+                url: url.to_string(),
+            },
+        )
+    }
+
+    /// Queues a chapter like `enqueue_chapter` and hands `on_done` its file once it's there,
+    /// or the error once it has failed for good. Chapters already in the library are handed
+    /// over straight away, whatever the download policy says.
+    pub fn fetch_chapter(
+        &self,
+        book: &str,
+        book_url: &str,
+        image_url: &str,
+        chapter: i32,
+        url: &str,
+        on_done: impl FnOnce(Result<PathBuf, String>) + Send + 'static,
+    ) {
+        let id = book_id(book_url, book);
+        // Books kept as one file only have the one file
+        let index = if get_chapter_offsets(&id).is_empty() { chapter.max(0) as u32 } else { 0 };
+        if let Ok(Some(file)) = library::chapter_file(&id, index) {
+            on_done(Ok(file));
+            return;
+        }
+        // Held until the waiter is in, so the job can't finish without telling it
+        let mut waiters = self.shared.waiters.lock().unwrap();
+        let job = self.enqueue_chapter(book, book_url, image_url, chapter, url);
+        waiters.push((job, Box::new(on_done)));
+    }
+
+    /// Queues every chapter of a book as one job.
+    pub fn enqueue_book(
+        &self,
+        book: &str,
+        book_url: &str,
+        image_url: &str,
+        chapter_urls: Vec<String>,
+    ) -> u64 {
+        self.enqueue(book, book_url, image_url, JobKind::Book { chapter_urls })
+    }
+
+    fn enqueue(&self, book: &str, book_url: &str, image_url: &str, kind: JobKind) -> u64 {
+        self.add(book, book_url, image_url, kind, false)
+    }
+
+    fn add(
+        &self,
+        book: &str,
+        book_url: &str,
+        image_url: &str,
+        kind: JobKind,
+        prefetch: bool,
+    ) -> u64 {
+        let mut jobs = self.lock();
+        if let Some(job) = jobs
+            .iter()
+            .find(|job| job.is_active() && job.book_url == book_url && job.kind == kind)
+        {
+            return job.id;
+        }
+
+        let id = jobs.iter().map(|job| job.id).max().unwrap_or_default() + 1;
+        log::info!("Queued download {} of {}", id, book);
+        jobs.push(DownloadJob {
+            id,
+            book: book.to_string(),
+            book_url: book_url.to_string(),
+            image_url: image_url.to_string(),
+            kind,
+            status: JobStatus::Queued,
+            downloaded: 0,
+            total: None,
+            chapters_done: 0,
+            prefetch,
+            attempts: 0,
+            error: None,
+            retry_at: 0,
+        });
+        self.changed(jobs);
+        id
+    }
+
+    /// Stops a job where it is. A running YouTube download can't be interrupted, so it only
+    /// stops once the file is done.
+    pub fn pause(&self, id: u64) {
+        self.update(id, |job| {
+            if job.is_active() {
+                job.status = JobStatus::Paused;
+            }
+        });
+    }
+
+    pub fn resume(&self, id: u64) {
+        self.update(id, |job| {
+            if job.status == JobStatus::Paused {
+                job.status = JobStatus::Queued;
+                job.retry_at = 0;
+            }
+        });
+    }
+
+    /// Tries a failed job again straight away, with a fresh set of attempts.
+    pub fn retry(&self, id: u64) {
+        self.update(id, |job| {
+            if matches!(job.status, JobStatus::Failed | JobStatus::Paused) {
+                job.status = JobStatus::Queued;
+                job.attempts = 0;
+                job.retry_at = 0;
+                job.error = None;
+            }
+        });
+    }
+
+    /// Drops a job from the queue, stopping it if it's running.
+    pub fn cancel(&self, id: u64) {
+        let mut jobs = self.lock();
+        jobs.retain(|job| job.id != id);
+        self.changed(jobs);
+        self.finish(id, Err("Download cancelled".to_string()));
+    }
+
+    /// Forgets the jobs that are done.
+    pub fn clear_finished(&self) {
+        let mut jobs = self.lock();
+        jobs.retain(|job| job.status != JobStatus::Done);
+        self.changed(jobs);
+    }
+
+    fn update(&self, id: u64, change: impl FnOnce(&mut DownloadJob)) {
+        let mut jobs = self.lock();
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+            change(job);
+        }
+        self.changed(jobs);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<DownloadJob>> {
+        self.shared.jobs.lock().unwrap()
+    }
+
+    // Saves the queue, wakes the workers and tells the listener
+    fn changed(&self, jobs: MutexGuard<'_, Vec<DownloadJob>>) {
+        save_jobs(&jobs);
+        drop(jobs);
+        self.shared.wake.notify_all();
+        self.notify();
+    }
+
+    // Tells whoever is waiting on job `id` how it went
+    fn finish(&self, id: u64, result: Result<PathBuf, String>) {
+        let waiting: Vec<Waiter> = {
+            let mut waiters = self.shared.waiters.lock().unwrap();
+            let (waiting, rest) = waiters.drain(..).partition(|(job, _)| *job == id);
+            *waiters = rest;
+            waiting.into_iter().map(|(_, waiter)| waiter).collect()
+        };
+        for waiter in waiting {
+            waiter(result.clone());
+        }
+    }
+
+    fn notify(&self) {
+        let jobs = self.jobs();
+        if let Some(listener) = self.shared.listener.lock().unwrap().as_ref() {
+            listener(jobs);
+        }
+    }
+
+    fn work(&self) {
+        loop {
+            let job = self.next_job();
+            log::info!("Downloading {} of {}", job.description(), job.book);
+            let id = job.id;
+            let result = self.run(&job);
+
+            let mut jobs = self.lock();
+            // Cancelled while it was running
+            let Some(job) = jobs.iter_mut().find(|queued| queued.id == id) else {
+                continue;
+            };
+            let finished = match result {
+                // Nobody asked for prefetched chapters, so they don't need to stay in the list
+                Ok(file) if job.prefetch => {
+                    jobs.retain(|queued| queued.id != id);
+                    Some(Ok(file))
+                }
+                Ok(file) => {
+                    job.status = JobStatus::Done;
+                    job.error = None;
+                    Some(Ok(file))
+                }
+                // Paused while it was running, it picks up from the last finished chapter
+                Err(_) if job.status != JobStatus::Running => None,
+                Err(e) => match job.failed(&e.to_string(), now()) {
+                    JobStatus::Failed => Some(Err(e.to_string())),
+                    _ => None,
+                },
+            };
+            self.changed(jobs);
+            // Whole book jobs have no one file to hand over, and nobody waits on them
+            if let Some(result) = finished {
+                let file = result.and_then(|file| file.ok_or("Nothing was downloaded".into()));
+                self.finish(id, file);
+            }
+        }
+    }
+
+    // Blocks until there's a queued job whose retry time has come and the policy allows
+    // downloading, then marks it as running
+    fn next_job(&self) -> DownloadJob {
+        loop {
+            let mut jobs = self.lock();
+            let now = now();
+            let runnable =
+                |job: &DownloadJob| job.status == JobStatus::Queued && job.retry_at <= now;
+
+            if jobs.iter().any(runnable) {
+                // Checking the connection and the disk takes a moment, don't hold the queue meanwhile
+                drop(jobs);
+                let blocked = self.policy().blocked();
+                jobs = self.lock();
+                let blocked = match self.shared.moving.load(Ordering::SeqCst) {
+                    true => Some("Waiting for the library to be moved"),
+                    false => blocked,
+                };
+
+                if let Some(reason) = blocked {
+                    for job in jobs.iter_mut().filter(|job| runnable(job)) {
+                        job.error = Some(reason.to_string());
+                        job.retry_at = now + POLICY_RECHECK.as_secs();
+                    }
+                    self.changed(jobs);
+                    continue;
+                }
+                if let Some(job) = jobs.iter_mut().find(|job| runnable(job)) {
+                    job.status = JobStatus::Running;
+                    job.error = None;
+                    let job = job.clone();
+                    self.changed(jobs);
+                    return job;
+                }
+            }
+
+            // Time out now and then, waiting jobs become runnable without anyone waking us
+            let _ = self
+                .shared
+                .wake
+                .wait_timeout(jobs, Duration::from_secs(1))
+                .unwrap();
+        }
+    }
+
+    // The downloaded file, for single chapters
+    fn run(&self, job: &DownloadJob) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+        let book = add_book(&job.book, &job.book_url)?;
+        save_cover(&book, &job.image_url);
+        match &job.kind {
+            JobKind::Chapter { chapter, url } => {
+                return Ok(Some(self.download(job, &book, *chapter, url)?));
+            }
+            JobKind::Book { chapter_urls } => {
+                for (chapter, url) in chapter_urls.iter().enumerate().skip(job.chapters_done) {
+                    self.download(job, &book, chapter as i32, url)?;
+                    self.update(job.id, |job| {
+                        job.chapters_done = chapter + 1;
+                        job.downloaded = 0;
+                        job.total = None;
+                    });
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn download(
+        &self,
+        job: &DownloadJob,
+        book: &str,
+        chapter: i32,
+        url: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let last_update = Mutex::new(Instant::now());
+        download_audio_with_progress(
+            book,
+            chapter,
+            url,
+            &job.book_url,
+            &|downloaded, total| {
+                let mut jobs = self.lock();
+                let Some(queued) = jobs
+                    .iter_mut()
+                    .find(|queued| queued.id == job.id && queued.status == JobStatus::Running)
+                else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "Download stopped",
+                    ));
+                };
+                queued.downloaded = downloaded;
+                queued.total = total;
+                drop(jobs);
+
+                let mut last_update = last_update.lock().unwrap();
+                if last_update.elapsed() >= PROGRESS_INTERVAL {
+                    *last_update = Instant::now();
+                    self.notify();
+                }
+                Ok(())
+            },
+        )
+    }
+}
+
+// Only NetworkManager tells us whether the connection is metered, anywhere else it's assumed
+// not to be
+fn is_metered() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        // Comes back as "u 1", 1 is metered and 3 is a guess that it is
+        .is_some_and(|output| {
+            matches!(
+                String::from_utf8_lossy(&output.stdout).trim(),
+                "u 1" | "u 3"
+            )
+        })
+}
+
+fn jobs_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config_dir()?.join("downloads.json"))
+}
+
+fn load_jobs() -> Vec<DownloadJob> {
+    jobs_file()
+        .ok()
+        .and_then(|file| {
+            read_with_backup(&file, |jobs| Ok(serde_json::from_slice(jobs)?))
+                .map_err(|e| {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        log::warn!("Failed to load the download queue: {}", e);
+                    }
+                })
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+fn save_jobs(jobs: &[DownloadJob]) {
+    let result = jobs_file().and_then(|file| -> Result<(), Box<dyn std::error::Error>> {
+        write_atomic(&file, serde_json::to_string_pretty(jobs)?.as_bytes())?;
+        Ok(())
+    });
+    if let Err(e) = result {
+        log::warn!("Failed to save the download queue: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::import::{import, ImportMode};
+    use crate::storage::library::LibraryBook;
+    use crate::storage::save::{get_progress, save_progress};
+    use crate::storage::setup::test_env;
+    use std::fs;
+    use std::sync::mpsc;
+
+    // A folder of audio files outside the library, named like a book on someone's disk
+    fn book_folder(test: &str, files: &[&str]) -> PathBuf {
+        let folder = std::env::temp_dir()
+            .join(format!("audiody-{}-{}", test, std::process::id()))
+            .join("The Odyssey");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        for file in files {
+            fs::write(folder.join(file), "not really audio").unwrap();
+        }
+        folder
+    }
+
+    // Asks for a chapter the way the player does, and records it as the one playing once
+    // its file is handed over
+    fn play(manager: &DownloadManager, book: &LibraryBook, chapter: i32) -> PathBuf {
+        let (sender, receiver) = mpsc::channel();
+        let id = book.id.clone();
+        let book_url = book.book_url.clone();
+        manager.fetch_chapter(&book.title, &book.book_url, "", chapter, "", move |file| {
+            save_progress(&id, Some(chapter), &book_url, None).unwrap();
+            sender.send(file).unwrap();
+        });
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap()
+    }
+
+    // A manager with nothing queued, and nothing left over from other tests on disk
+    fn empty_manager() -> DownloadManager {
+        save_jobs(&[]);
+        DownloadManager::load()
+    }
+
+    fn status(manager: &DownloadManager, id: u64) -> Option<JobStatus> {
+        manager.jobs().iter().find(|job| job.id == id).map(|job| job.status)
+    }
+
+    #[test]
+    fn imported_book_plays_the_chapter_asked_for() {
+        let _env = test_env();
+        let folder = book_folder(
+            "in-place",
+            &["01 - Invocation.mp3", "02 - Telemachus.mp3", "03 - Nestor.mp3"],
+        );
+        let id = import(&folder, ImportMode::InPlace).unwrap();
+        let book = library::book(&id).unwrap().unwrap();
+        let manager = empty_manager();
+
+        let file = play(&manager, &book, 1);
+
+        // Played from where it was imported, nothing got queued for it
+        assert_eq!(file, folder.canonicalize().unwrap().join("02 - Telemachus.mp3"));
+        assert!(manager.jobs().is_empty());
+        assert_eq!(get_progress(&id).unwrap().current_chapter, Some(1));
+        let _ = fs::remove_dir_all(folder.parent().unwrap());
+    }
+
+    #[test]
+    fn playlist_chapters_are_counted_from_0() {
+        let _env = test_env();
+        let book_url = "https://www.youtube.com/playlist?list=PLodyssey";
+        let id = library::add_book("Odyssey Playlist", book_url).unwrap();
+        // Playlist items are saved numbered from 1
+        let folder = library::book_dir(&id).unwrap();
+        for file in ["chapter_1.mp3", "chapter_2.mp3"] {
+            fs::write(folder.join(file), "not really audio").unwrap();
+        }
+        library::refresh_book(&id).unwrap();
+        let book = library::book(&id).unwrap().unwrap();
+        let manager = empty_manager();
+
+        assert_eq!(play(&manager, &book, 0), folder.join("chapter_1.mp3"));
+        assert_eq!(get_progress(&id).unwrap().current_chapter, Some(0));
+        assert_eq!(play(&manager, &book, 1), folder.join("chapter_2.mp3"));
+        assert_eq!(get_progress(&id).unwrap().current_chapter, Some(1));
+    }
+
+    #[test]
+    fn queue_carries_on_after_a_restart() {
+        let _env = test_env();
+        let manager = empty_manager();
+        let chapter = manager.enqueue_chapter("Saved Queue", "https://example.com/q", "", 2, "c");
+        let urls = vec!["a".to_string(), "b".to_string()];
+        let whole = manager.enqueue_book("Saved Queue", "https://example.com/q", "", urls);
+        manager.update(chapter, |job| job.status = JobStatus::Running);
+        manager.pause(whole);
+
+        let reloaded = DownloadManager::load();
+
+        let jobs = reloaded.jobs();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].kind, manager.jobs()[0].kind);
+        // It was cut off halfway, so it starts over
+        assert_eq!(status(&reloaded, chapter), Some(JobStatus::Queued));
+        assert_eq!(status(&reloaded, whole), Some(JobStatus::Paused));
+        // Ids keep counting up from the saved ones
+        let next = reloaded.enqueue_chapter("Saved Queue", "https://example.com/q", "", 3, "d");
+        assert_eq!(next, whole + 1);
+    }
+
+    #[test]
+    fn same_download_is_only_queued_once() {
+        let _env = test_env();
+        let manager = empty_manager();
+        let first = manager.enqueue_chapter("Twice", "https://example.com/t", "", 0, "url");
+        let again = manager.enqueue_chapter("Twice", "https://example.com/t", "", 0, "url");
+        let other = manager.enqueue_chapter("Twice", "https://example.com/t", "", 1, "url");
+
+        assert_eq!(first, again);
+        assert_ne!(first, other);
+        assert_eq!(manager.jobs().len(), 2);
+    }
+
+    #[test]
+    fn failed_downloads_back_off_then_give_up() {
+        let _env = test_env();
+        let manager = empty_manager();
+        let id = manager.enqueue_chapter("Flaky", "https://example.com/f", "", 0, "url");
+        let mut job = manager.jobs()[0].clone();
+        assert_eq!(job.id, id);
+
+        let mut delays = vec![];
+        for _ in 1..MAX_ATTEMPTS {
+            assert_eq!(job.failed("Connection reset", 1000), JobStatus::Queued);
+            delays.push(job.retry_at - 1000);
+        }
+        assert_eq!(delays, [5, 10, 20, 40]);
+        assert_eq!(job.failed("Connection reset", 1000), JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("Connection reset"));
+
+        // Retrying starts afresh
+        manager.update(id, |queued| *queued = job);
+        manager.retry(id);
+        let job = &manager.jobs()[0];
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!((job.attempts, job.retry_at, job.error.clone()), (0, 0, None));
+    }
+
+    #[test]
+    fn pause_resume_and_cancel() {
+        let _env = test_env();
+        let manager = empty_manager();
+        let id = manager.enqueue_chapter("Paused", "https://example.com/p", "", 0, "url");
+
+        manager.pause(id);
+        assert_eq!(status(&manager, id), Some(JobStatus::Paused));
+        manager.resume(id);
+        assert_eq!(status(&manager, id), Some(JobStatus::Queued));
+        // Finished jobs stay finished
+        manager.update(id, |job| job.status = JobStatus::Done);
+        manager.pause(id);
+        manager.resume(id);
+        assert_eq!(status(&manager, id), Some(JobStatus::Done));
+        manager.clear_finished();
+        assert_eq!(status(&manager, id), None);
+
+        // Whoever was waiting on a cancelled download hears about it
+        let (sender, receiver) = mpsc::channel();
+        let id = manager.enqueue_chapter("Paused", "https://example.com/p", "", 1, "url");
+        let waiter: Waiter = Box::new(move |result| sender.send(result).unwrap());
+        manager.shared.waiters.lock().unwrap().push((id, waiter));
+        manager.cancel(id);
+        assert!(manager.jobs().is_empty());
+        assert_eq!(receiver.try_recv(), Ok(Err("Download cancelled".to_string())));
+    }
+}
@@ -0,0 +1,278 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::binaries::{self, Tool};
+use super::covers::cover_as_jpeg;
+use super::library::{self, duration, folder_name, LibraryBook};
+use super::save::get_chapter_offsets;
+use super::setup::default_export_dir;
+
+/// Bitrate of the audio when it has to be converted, plenty for speech
+const AUDIO_BITRATE: &str = "64k";
+/// Chapters that are AAC already get copied over as they are
+const AAC_EXTENSIONS: [&str; 3] = ["m4a", "m4b", "aac"];
+
+// A chapter marker of the exported file, in seconds from the start
+struct Mark {
+    title: String,
+    start: f64,
+    end: f64,
+}
+
+/// Exports a saved book as one .m4b file with a chapter marker for every chapter, its cover
+/// and its details, for players that only do single file audiobooks. `target` is the file to
+/// write or a folder to write it to, the exports folder when None. `on_progress` is told how
+/// many seconds of how many have been written so far. Returns the file that was written.
+pub fn export_m4b(
+    id: &str,
+    target: Option<&Path>,
+    mut on_progress: impl FnMut(f64, f64),
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let book = library::book(id)?.ok_or_else(|| format!("No saved book {}", id))?;
+    check_complete(&book)?;
+    let marks = chapter_marks(&book)?;
+    let total = marks.last().map(|mark| mark.end).unwrap_or_default();
+    let output = output_file(&book, target)?;
+
+    // The lists ffmpeg reads go in a folder of their own, gone again once it's done
+    let work_dir = std::env::temp_dir().join(format!("audiody-export-{}", id));
+    fs::create_dir_all(&work_dir)?;
+    let mut part_name = OsString::from(output.as_os_str());
+    part_name.push(".part");
+    let part = PathBuf::from(part_name);
+    let result = run_ffmpeg(&book, &marks, &work_dir, &part, |seconds| {
+        on_progress(seconds.min(total), total)
+    });
+    let _ = fs::remove_dir_all(&work_dir);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part);
+        return Err(e);
+    }
+    fs::rename(&part, &output)?;
+    on_progress(total, total);
+
+    log::info!("Exported {} to {}", id, output.display());
+    Ok(output)
+}
+
+// A book with chapters missing would come out with holes in it
+fn check_complete(book: &LibraryBook) -> Result<(), Box<dyn std::error::Error>> {
+    if book.chapters.is_empty() {
+        return Err(format!("None of {} is downloaded yet", book.title).into());
+    }
+    let single_file = !get_chapter_offsets(&book.id).is_empty();
+    let expected = match &book.manifest {
+        Some(manifest) if !single_file => manifest.chapter_urls.len(),
+        _ => book.chapters.len(),
+    };
+    if book.chapters.len() < expected {
+        return Err(format!(
+            "Only {} of the {} chapters of {} are downloaded",
+            book.chapters.len(),
+            expected,
+            book.title
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Where every chapter starts and ends in the exported file
+fn chapter_marks(book: &LibraryBook) -> Result<Vec<Mark>, Box<dyn std::error::Error>> {
+    let titles = book
+        .manifest
+        .as_ref()
+        .map(|manifest| manifest.chapter_titles.clone())
+        .unwrap_or_default();
+    let title = |index: usize, fallback: String| match titles.get(index) {
+        Some(title) if !title.trim().is_empty() => title.trim().to_string(),
+        _ => fallback,
+    };
+
+    // Books kept as one file already know where their chapters are
+    let offsets = get_chapter_offsets(&book.id);
+    if !offsets.is_empty() {
+        return Ok(offsets
+            .iter()
+            .enumerate()
+            .map(|(index, offset)| Mark {
+                title: title(index, format!("Chapter {}", index + 1)),
+                start: offset.start,
+                end: offset.end,
+            })
+            .collect());
+    }
+
+    let mut marks: Vec<Mark> = vec![];
+    for chapter in &book.chapters {
+        let length = chapter
+            .duration
+            .or_else(|| duration(&chapter.file))
+            .ok_or_else(|| format!("Can't tell how long {} is", chapter.file.display()))?;
+        let start = marks.last().map(|mark| mark.end).unwrap_or_default();
+        let name = chapter
+            .file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        marks.push(Mark {
+            title: title(chapter.index as usize, name),
+            start,
+            end: start + length,
+        });
+    }
+    Ok(marks)
+}
+
+fn output_file(
+    book: &LibraryBook,
+    target: Option<&Path>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let name = format!("{}.m4b", folder_name(&book.title));
+    Ok(match target {
+        Some(target) if target.is_dir() => target.join(name),
+        Some(target) => target.to_path_buf(),
+        None => {
+            let dir = default_export_dir()?;
+            fs::create_dir_all(&dir)?;
+            dir.join(name)
+        }
+    })
+}
+
+fn run_ffmpeg(
+    book: &LibraryBook,
+    marks: &[Mark],
+    work_dir: &Path,
+    output: &Path,
+    mut on_progress: impl FnMut(f64),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let list = work_dir.join("chapters.txt");
+    fs::write(&list, concat_list(book))?;
+    let metadata = work_dir.join("metadata.txt");
+    fs::write(&metadata, ffmetadata(book, marks))?;
+    let cover = book.cover.as_ref().and_then(|cover| {
+        let jpeg = cover_as_jpeg(cover)
+            .map_err(|e| log::warn!("Failed to convert {}: {}", cover.display(), e))
+            .ok()?;
+        let file = work_dir.join("cover.jpg");
+        fs::write(&file, jpeg).ok()?;
+        Some(file)
+    });
+
+    // Quiet, apart from the progress on stdout
+    let mut args: Vec<OsString> =
+        words("-y -nostdin -hide_banner -loglevel error -nostats -progress pipe:1").collect();
+    args.extend(words("-f concat -safe 0 -i"));
+    args.push(list.into());
+    args.push("-i".into());
+    args.push(metadata.into());
+    let has_cover = cover.is_some();
+    if let Some(cover) = cover {
+        args.push("-i".into());
+        args.push(cover.into());
+    }
+    args.extend(words("-map 0:a -map_metadata 1 -map_chapters 1"));
+    if has_cover {
+        args.extend(words("-map 2:v -c:v copy -disposition:v:0 attached_pic"));
+    }
+    if is_aac(book) {
+        args.extend(words("-c:a copy"));
+    } else {
+        args.extend(words("-c:a aac -b:a"));
+        args.push(AUDIO_BITRATE.into());
+    }
+    args.extend(words("-movflags +faststart -f mp4"));
+    args.push(output.into());
+
+    // With -progress ffmpeg keeps printing key=value lines, out_time_us is how far it got
+    binaries::run_with_lines(Tool::Ffmpeg, args, |line| {
+        let Some((key, value)) = line.split_once('=') else {
+            return;
+        };
+        if key == "out_time_us" || key == "out_time_ms" {
+            if let Ok(micros) = value.trim().parse::<f64>() {
+                on_progress(micros / 1_000_000.0);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn words(args: &str) -> impl Iterator<Item = OsString> + '_ {
+    args.split_whitespace().map(OsString::from)
+}
+
+fn is_aac(book: &LibraryBook) -> bool {
+    book.chapters.iter().all(|chapter| {
+        chapter
+            .file
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                AAC_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+            })
+    })
+}
+
+// The chapter files in order, for ffmpeg's concat demuxer
+fn concat_list(book: &LibraryBook) -> String {
+    let mut list = String::from("ffconcat version 1.0\n");
+    for chapter in &book.chapters {
+        let path = chapter.file.display().to_string();
+        list.push_str(&format!("file '{}'\n", path.replace('\'', r"'\''")));
+    }
+    list
+}
+
+// The book's details and chapter markers in ffmpeg's metadata format
+fn ffmetadata(book: &LibraryBook, marks: &[Mark]) -> String {
+    let manifest = book.manifest.clone().unwrap_or_default();
+    let mut readers: Vec<&str> = vec![];
+    for reader in &manifest.chapter_reader {
+        let reader = reader.trim();
+        if !reader.is_empty() && !readers.contains(&reader) {
+            readers.push(reader);
+        }
+    }
+
+    let mut metadata = String::from(";FFMETADATA1\n");
+    let fields = [
+        ("title", book.title.clone()),
+        ("album", book.title.clone()),
+        ("artist", manifest.author.clone()),
+        ("album_artist", manifest.author.clone()),
+        // There's no field for the reader, audiobook players look for them as the composer
+        ("composer", readers.join(", ")),
+        ("genre", "Audiobook".to_string()),
+        ("comment", manifest.description.clone()),
+    ];
+    for (key, value) in fields {
+        if !value.trim().is_empty() {
+            metadata.push_str(&format!("{}={}\n", key, escape(value.trim())));
+        }
+    }
+    for mark in marks {
+        metadata.push_str(&format!(
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (mark.start * 1000.0).round() as u64,
+            (mark.end * 1000.0).round() as u64,
+            escape(&mark.title)
+        ));
+    }
+    metadata
+}
+
+// '=', ';', '#', '\' and line breaks have to be escaped in metadata values
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if "=;#\\\n".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,192 @@
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const COPY_BUFFER: usize = 256 * 1024;
+
+// `settings.json` -> `settings.json.<suffix>`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The copy of `path` from before its last write.
+pub fn backup_file(path: &Path) -> PathBuf {
+    with_suffix(path, "bak")
+}
+
+/// Replaces the file at `path` so that it's never left half written, even if the app dies
+/// halfway through. The new contents go to a temporary file that is then renamed over the
+/// old one, which is kept as the backup.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp = with_suffix(path, "tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if path.exists() {
+        fs::rename(path, backup_file(path))?;
+    }
+    fs::rename(&temp, path)
+}
+
+/// Reads the file at `path` with `parse`. When it's missing or can't be parsed the backup is
+/// tried instead, and put back in its place if that works.
+pub fn read_with_backup<T>(path: &Path, parse: impl Fn(&[u8]) -> io::Result<T>) -> io::Result<T> {
+    let error = match fs::read(path).and_then(|contents| parse(&contents)) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+
+    let backup = backup_file(path);
+    match fs::read(&backup).and_then(|contents| parse(&contents)) {
+        Ok(value) => {
+            log::warn!(
+                "{} is unreadable ({}), using its backup",
+                path.display(),
+                error
+            );
+            if let Err(e) = fs::copy(&backup, path) {
+                log::warn!("Failed to restore {}: {}", path.display(), e);
+            }
+            Ok(value)
+        }
+        // Nothing to recover from, the original error says more
+        Err(_) => Err(error),
+    }
+}
+
+/// Seconds since the Unix epoch, how the json files keep their timestamps.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Every file below `dir` with its size, the paths relative to `dir`.
+pub fn list_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = vec![];
+    let mut folders = vec![PathBuf::new()];
+    while let Some(folder) = folders.pop() {
+        for entry in fs::read_dir(dir.join(&folder))? {
+            let entry = entry?;
+            let path = folder.join(entry.file_name());
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                folders.push(path);
+            } else {
+                files.push((path, metadata.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Copies `from` to `to`, then reads the copy back to make sure it's the same. `on_copied`
+/// is told how many more bytes were written as it goes.
+pub fn copy_verified(from: &Path, to: &Path, mut on_copied: impl FnMut(u64)) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut copy = File::create(to)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; COPY_BUFFER];
+    loop {
+        let read = source.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copy.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        on_copied(read as u64);
+    }
+    copy.sync_all()?;
+    drop(copy);
+
+    let mut copied = Sha256::new();
+    io::copy(&mut File::open(to)?, &mut copied)?;
+    if copied.finalize() != hasher.finalize() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't match {}", to.display(), from.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audiody-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn parse_number(contents: &[u8]) -> io::Result<u32> {
+        String::from_utf8_lossy(contents)
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[test]
+    fn write_atomic_keeps_the_old_file_as_backup() {
+        let dir = test_dir("write-atomic");
+        let file = dir.join("value.txt");
+
+        write_atomic(&file, b"1").unwrap();
+        write_atomic(&file, b"2").unwrap();
+
+        assert_eq!(fs::read(&file).unwrap(), b"2");
+        assert_eq!(fs::read(backup_file(&file)).unwrap(), b"1");
+        assert!(!with_suffix(&file, "tmp").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn broken_file_is_recovered_from_its_backup() {
+        let dir = test_dir("recover");
+        let file = dir.join("value.txt");
+        fs::write(&file, "not a number").unwrap();
+        fs::write(backup_file(&file), "7").unwrap();
+
+        assert_eq!(read_with_backup(&file, parse_number).unwrap(), 7);
+        // The backup is put back in place of the broken file
+        assert_eq!(fs::read(&file).unwrap(), b"7");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn missing_file_is_recovered_from_its_backup() {
+        let dir = test_dir("recover-missing");
+        let file = dir.join("value.txt");
+        fs::write(backup_file(&file), "7").unwrap();
+
+        assert_eq!(read_with_backup(&file, parse_number).unwrap(), 7);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn both_broken_gives_the_error_of_the_file() {
+        let dir = test_dir("unrecoverable");
+        let file = dir.join("value.txt");
+        fs::write(&file, "not a number").unwrap();
+        fs::write(backup_file(&file), "not one either").unwrap();
+
+        let error = read_with_backup(&file, parse_number).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Nothing gets overwritten when there's nothing to recover
+        assert_eq!(fs::read(&file).unwrap(), b"not a number");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
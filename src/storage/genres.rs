@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::api::types::Genre;
+
+use super::files::now;
+use super::setup::config_dir;
+
+/// Genres barely ever change, so only ask LibriVox once a week
+const GENRE_CACHE_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct GenreCache {
+    fetched_at: u64,
+    genres: Vec<Genre>,
+}
+
+fn cache_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config_dir()?.join("genres.json"))
+}
+
+fn load_cache() -> Option<GenreCache> {
+    let cache = fs::read_to_string(cache_file().ok()?).ok()?;
+    serde_json::from_str(&cache).ok()
+}
+
+/// Genres from the local cache only, without going to the network.
+pub fn cached_genres() -> Vec<Genre> {
+    load_cache().map(|cache| cache.genres).unwrap_or_default()
+}
+
+/// Genres from the cache while it is fresh, otherwise from `fetch`. A stale cache is still
+/// used when fetching fails, so browsing works offline.
+pub fn get_genres<E: std::fmt::Display>(
+    fetch: impl FnOnce() -> Result<Vec<Genre>, E>,
+) -> Vec<Genre> {
+    let cache = load_cache();
+    if let Some(cache) = &cache {
+        if now().saturating_sub(cache.fetched_at) < GENRE_CACHE_TTL && !cache.genres.is_empty() {
+            return cache.genres.clone();
+        }
+    }
+
+    match fetch() {
+        Ok(genres) if !genres.is_empty() => {
+            let fresh = GenreCache {
+                fetched_at: now(),
+                genres: genres.clone(),
+            };
+            if let Err(e) = cache_file().and_then(|file| {
+                fs::write(file, serde_json::to_string(&fresh)?)?;
+                Ok(())
+            }) {
+                log::warn!("Failed to cache genres: {}", e);
+            }
+            genres
+        }
+        Ok(_) => cache.map(|cache| cache.genres).unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Failed to fetch genres: {}", e);
+            cache.map(|cache| cache.genres).unwrap_or_default()
+        }
+    }
+}
+
+/// Looks a genre up by name, ignoring case.
+pub fn find_genre(genres: &[Genre], name: &str) -> Option<Genre> {
+    genres
+        .iter()
+        .find(|genre| genre.name.eq_ignore_ascii_case(name.trim()))
+        .cloned()
+}
@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::api::types::Book;
+
+use super::covers::save_as_webp;
+use super::files::{copy_verified, list_files};
+use super::library::{self, add_book, book_dir, duration, find_cover, is_chapter_file};
+use super::save::{save_book_manifest, settings, BookManifest};
+use super::saved::is_audio_file;
+use super::tags::{read_tags, AudioTags};
+
+/// Pictures next to the audio that are used as the cover when none is embedded
+const COVER_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+const COVER_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Copies the files into the library
+    Copy,
+    /// Leaves the files where they are, the library only points at them
+    InPlace,
+}
+
+// An audio file and what its tags say about it
+struct Track {
+    file: PathBuf,
+    tags: AudioTags,
+}
+
+/// Imports a folder of audio files, or a single file, as one book and returns its id. The
+/// tags decide the title, author and order of the chapters, with the folder and file names
+/// filling in for missing ones.
+pub fn import(path: &Path, mode: ImportMode) -> Result<String, Box<dyn std::error::Error>> {
+    let path = path.canonicalize()?;
+    let tracks = read_tracks(&path)?;
+    if tracks.is_empty() {
+        return Err(format!("No audio files in {}", path.display()).into());
+    }
+    let mut details = describe(&path, &tracks);
+    let book = add_book(&details.title, &details.url)?;
+    let folder = book_dir(&book)?;
+
+    let files = match mode {
+        ImportMode::InPlace => tracks.iter().map(|track| track.file.clone()).collect(),
+        ImportMode::Copy => copy_tracks(&tracks, &folder)?,
+    };
+    save_cover_art(&tracks, &folder);
+    set_chapter_files(&mut details, &files);
+    save_book_manifest(&book, &details)?;
+    library::refresh_book(&book)?;
+
+    log::info!("Imported {} as {}", path.display(), book);
+    Ok(book)
+}
+
+/// Describes a folder that was put in the library by hand, so it's read like any other book
+/// instead of its file names being guessed at. The files stay as they are, book.json lists
+/// them in order. Folders we made ourselves are left alone.
+pub fn adopt_folder(folder: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !folder.is_dir()
+        || folder.join("settings.json").exists()
+        || folder.join("book.json").exists()
+    {
+        return Ok(());
+    }
+    let tracks = read_tracks(folder)?;
+    if tracks.is_empty() || tracks.iter().all(|track| is_chapter_file(&track.file)) {
+        return Ok(());
+    }
+
+    log::info!("Reading the tags of {}", folder.display());
+    let mut details = describe(folder, &tracks);
+    let files: Vec<PathBuf> = tracks.iter().map(|track| track.file.clone()).collect();
+    set_chapter_files(&mut details, &files);
+    save_cover_art(&tracks, folder);
+
+    let mut book_settings = settings::new();
+    book_settings.title = details.title.clone();
+    book_settings.book_url = details.url.clone();
+    book_settings.save(&folder.join("settings.json"))?;
+    BookManifest::from_book(&details, find_cover(folder)).save(&folder.join("book.json"))?;
+    Ok(())
+}
+
+/// The book url of local files, it only has to tell imports apart.
+pub fn local_url(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+// The audio files at `path` in chapter order, with their tags
+fn read_tracks(path: &Path) -> Result<Vec<Track>, Box<dyn std::error::Error>> {
+    let files = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        list_files(path)?
+            .into_iter()
+            .map(|(file, _)| path.join(file))
+            .collect()
+    };
+    let mut tracks: Vec<Track> = files
+        .into_iter()
+        .filter(|file| is_audio_file(&file.display().to_string()))
+        .map(|file| Track {
+            tags: read_tags(&file),
+            file,
+        })
+        .collect();
+
+    // Books split over CDs often have a folder per disc and start counting tracks again in
+    // each, so the folder comes before the track number
+    tracks.sort_by(|a, b| {
+        let folder =
+            |track: &Track| natural_key(&track.file.parent().unwrap_or(path).display().to_string());
+        let name = |track: &Track| {
+            natural_key(&track.file.file_name().unwrap_or_default().to_string_lossy())
+        };
+        a.tags
+            .disc
+            .unwrap_or(0)
+            .cmp(&b.tags.disc.unwrap_or(0))
+            .then_with(|| folder(a).cmp(&folder(b)))
+            .then_with(|| compare_tracks(a.tags.track, b.tags.track))
+            .then_with(|| name(a).cmp(&name(b)))
+    });
+    Ok(tracks)
+}
+
+// Numbered tracks first
+fn compare_tracks(a: Option<u32>, b: Option<u32>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NamePart {
+    Number(u64),
+    Text(String),
+}
+
+// Splits a name into text and numbers, so "Chapter 2" comes before "Chapter 10"
+fn natural_key(name: &str) -> Vec<NamePart> {
+    let mut parts = vec![];
+    let mut chars = name.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let digit = c.is_ascii_digit();
+        let mut part = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_digit() != digit {
+                break;
+            }
+            part.push(c);
+            chars.next();
+        }
+        parts.push(match part.parse() {
+            Ok(number) if digit => NamePart::Number(number),
+            _ => NamePart::Text(part.to_lowercase()),
+        });
+    }
+    parts
+}
+
+// The book as far as the tags tell, without its chapter files yet
+fn describe(path: &Path, tracks: &[Track]) -> Book {
+    let name = match path.is_file() {
+        true => path.file_stem(),
+        false => path.file_name(),
+    }
+    .unwrap_or_default()
+    .to_string_lossy()
+    .to_string();
+    let title = most_common(tracks.iter().map(|track| &track.tags.album)).unwrap_or(name);
+    let author = most_common(tracks.iter().map(|track| &track.tags.album_artist))
+        .or_else(|| most_common(tracks.iter().map(|track| &track.tags.artist)))
+        .unwrap_or_default();
+    let chapter_titles = tracks
+        .iter()
+        .map(|track| {
+            track.tags.title.clone().unwrap_or_else(|| {
+                track
+                    .file
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            })
+        })
+        .collect();
+
+    Book {
+        saved: true,
+        title,
+        chapter_titles,
+        author,
+        url: local_url(path),
+        ..Default::default()
+    }
+}
+
+// The value most tracks agree on, a stray tag on one file shouldn't rename the book
+fn most_common<'a>(values: impl Iterator<Item = &'a Option<String>>) -> Option<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for value in values.flatten() {
+        *counts.entry(value.as_str()).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then_with(|| b.cmp(a)))
+        .map(|(value, _)| value.to_string())
+}
+
+fn set_chapter_files(details: &mut Book, files: &[PathBuf]) {
+    details.chapter_urls = files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect();
+    details.chapter_durations = files
+        .iter()
+        .map(|file| {
+            duration(file)
+                .map(|duration| (duration as u64).to_string())
+                .unwrap_or_default()
+        })
+        .collect();
+}
+
+// Copies the tracks into the book's folder, named like downloaded chapters
+fn copy_tracks(
+    tracks: &[Track],
+    folder: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = vec![];
+    for (index, track) in tracks.iter().enumerate() {
+        let extension = track
+            .file
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        let file = folder.join(format!("chapter_{}.{}", index, extension));
+        copy_verified(&track.file, &file, |_| {})
+            .map_err(|e| format!("Failed to copy {}: {}", track.file.display(), e))?;
+        files.push(file);
+    }
+    Ok(files)
+}
+
+// Saves the embedded cover, or a picture found next to the files, as the book's cover
+fn save_cover_art(tracks: &[Track], folder: &Path) {
+    if find_cover(folder).is_some() {
+        return;
+    }
+    let embedded = tracks
+        .iter()
+        .filter_map(|track| track.tags.cover.as_ref())
+        .find_map(|cover| image::load_from_memory(cover).ok());
+    let image = embedded.or_else(|| {
+        let source = tracks.first()?.file.parent()?;
+        COVER_NAMES
+            .iter()
+            .flat_map(|name| {
+                COVER_EXTENSIONS
+                    .iter()
+                    .map(move |extension| source.join(format!("{}.{}", name, extension)))
+            })
+            .find(|file| file.is_file())
+            .and_then(|file| image::open(file).ok())
+    });
+    let Some(image) = image else {
+        return;
+    };
+    if let Err(e) = save_as_webp(&image, &folder.join("cover.webp")) {
+        log::warn!("Failed to save the cover in {}: {}", folder.display(), e);
+    }
+}
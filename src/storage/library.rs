@@ -0,0 +1,966 @@
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::TimeBase;
+
+use crate::api::types::Book;
+use crate::api::webapi::{provider_name, source_id};
+use crate::api::yt::is_playlist_url;
+
+use super::config;
+use super::files::{copy_verified, list_files, read_with_backup, write_atomic};
+use super::import::adopt_folder;
+use super::save::{settings, BookManifest};
+use super::saved::is_audio_file;
+use super::setup::{config_dir, default_music_dir, music_dir};
+
+/// Bump this when the layout changes, older indexes then get rebuilt from disk
+const LIBRARY_VERSION: u32 = 5;
+/// Folder names are cut off after this many characters, long paths upset some systems
+const MAX_FOLDER_NAME: usize = 100;
+/// Moving the library reports how far it got at most this often
+const MOVE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A downloaded chapter and the file it's in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryChapter {
+    pub index: u32,
+    pub file: PathBuf,
+    /// In seconds, None when the decoder couldn't tell
+    #[serde(default)]
+    pub duration: Option<f64>,
+}
+
+/// Everything known about a saved book without having to look at its folder.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LibraryBook {
+    /// See `book_id`
+    pub id: String,
+    pub title: String,
+    /// Named after the title, but not always exactly, see `folder_name`
+    pub folder: PathBuf,
+    pub book_url: String,
+    /// From book.json, books saved before it existed don't have one
+    #[serde(default)]
+    pub manifest: Option<BookManifest>,
+    #[serde(default)]
+    pub cover: Option<PathBuf>,
+    /// Sorted by index. Books kept as one file have a single entry, their chapters are
+    /// offsets in settings.json
+    pub chapters: Vec<LibraryChapter>,
+    #[serde(default)]
+    pub current_chapter: Option<i32>,
+    /// Seconds into the current chapter
+    #[serde(default)]
+    pub current_chapter_time: Option<f64>,
+}
+
+impl LibraryBook {
+    pub fn chapter_file(&self, index: u32) -> Option<&PathBuf> {
+        self.chapters
+            .iter()
+            .find(|chapter| chapter.index == index)
+            .map(|chapter| &chapter.file)
+    }
+
+    /// The book as the UI wants it. Without a manifest all that's known are the chapter
+    /// files, so those stand in for the chapter urls.
+    pub fn to_book(&self) -> Book {
+        let cover = self
+            .cover
+            .as_ref()
+            .map(|cover| cover.display().to_string())
+            .unwrap_or_default();
+        if let Some(manifest) = &self.manifest {
+            return Book {
+                saved: true,
+                title: self.title.clone(),
+                chapter_titles: manifest.chapter_titles.clone(),
+                chapter_urls: manifest.chapter_urls.clone(),
+                chapter_durations: manifest.chapter_durations.clone(),
+                chapter_reader: manifest.chapter_reader.clone(),
+                description: manifest.description.clone(),
+                author: manifest.author.clone(),
+                url: self.book_url.clone(),
+                image_URL: if cover.is_empty() {
+                    manifest.image_url.clone()
+                } else {
+                    cover
+                },
+            };
+        }
+
+        Book {
+            saved: true,
+            title: self.title.clone(),
+            chapter_titles: vec![],
+            chapter_urls: self
+                .chapters
+                .iter()
+                .map(|chapter| chapter.file.display().to_string())
+                .collect(),
+            chapter_durations: self
+                .chapters
+                .iter()
+                .map(|chapter| {
+                    chapter
+                        .duration
+                        .map(|duration| (duration as u64).to_string())
+                        .unwrap_or_default()
+                })
+                .collect(),
+            chapter_reader: vec![],
+            description: "".to_string(),
+            author: "".to_string(),
+            url: self.book_url.clone(),
+            image_URL: cover,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Library {
+    version: u32,
+    books: Vec<LibraryBook>,
+}
+
+impl Library {
+    fn book_mut(&mut self, id: &str) -> Option<&mut LibraryBook> {
+        self.books.iter_mut().find(|book| book.id == id)
+    }
+
+    fn insert(&mut self, book: LibraryBook) {
+        self.books.retain(|known| known.id != book.id);
+        self.books.push(book);
+        self.books.sort_by(|a, b| a.title.cmp(&b.title));
+    }
+}
+
+// Loaded once and then kept up to date in memory, the file is only read at startup
+static LIBRARY: Mutex<Option<Library>> = Mutex::new(None);
+
+/// Stable id of a book, made from who provides it and its id there (see `source_id`), like
+/// `librivox-1f3a9c0e2b4d`. Books without a url go by their title instead.
+pub fn book_id(book_url: &str, title: &str) -> String {
+    let provider = match provider_name(book_url) {
+        "" => "local".to_string(),
+        name => name.to_ascii_lowercase().replace(' ', "-"),
+    };
+    let source = if book_url.is_empty() { title.to_string() } else { source_id(book_url) };
+    let hash: String = Sha256::digest(source.as_bytes())
+        .iter()
+        .take(6)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}-{}", provider, hash)
+}
+
+/// `title` made safe to use as a folder name on any system.
+pub fn folder_name(title: &str) -> String {
+    let name: String = title
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"/\:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .take(MAX_FOLDER_NAME)
+        .collect();
+    // Windows won't have names ending in a dot or space, and "." or ".." aren't names at all
+    let name = name.trim_matches(|c| c == '.' || c == ' ');
+    if name.is_empty() {
+        "Untitled".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+fn library_file() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config_dir()?.join("library.json"))
+}
+
+// Runs `f` on the index, loading it first or rebuilding it when there's no usable one
+fn with_library<T>(f: impl FnOnce(&mut Library) -> T) -> Result<T, Box<dyn std::error::Error>> {
+    let mut library = LIBRARY.lock().unwrap();
+    if library.is_none() {
+        let loaded = read_with_backup(&library_file()?, |library| {
+            Ok(serde_json::from_slice::<Library>(library)?)
+        })
+        .ok()
+        .filter(|library| library.version == LIBRARY_VERSION);
+        *library = Some(match loaded {
+            Some(loaded) => loaded,
+            None => {
+                log::info!("No library index, scanning {}", music_dir()?.display());
+                let scanned = scan()?;
+                save(&scanned)?;
+                scanned
+            }
+        });
+    }
+    Ok(f(library.as_mut().unwrap()))
+}
+
+fn save(library: &Library) -> Result<(), Box<dyn std::error::Error>> {
+    write_atomic(&library_file()?, &serde_json::to_vec(library)?)?;
+    Ok(())
+}
+
+// Changes the index and writes it out straight away
+fn update<T>(f: impl FnOnce(&mut Library) -> T) -> Result<T, Box<dyn std::error::Error>> {
+    with_library(|library| {
+        let result = f(library);
+        save(library).map(|_| result)
+    })?
+}
+
+/// All saved books.
+pub fn books() -> Result<Vec<LibraryBook>, Box<dyn std::error::Error>> {
+    with_library(|library| library.books.clone())
+}
+
+/// The saved book with id `id`.
+pub fn book(id: &str) -> Result<Option<LibraryBook>, Box<dyn std::error::Error>> {
+    with_library(|library| library.books.iter().find(|book| book.id == id).cloned())
+}
+
+/// Folder of a saved book.
+pub fn book_dir(id: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    book(id)?
+        .map(|book| book.folder)
+        .ok_or_else(|| format!("No saved book {}", id).into())
+}
+
+/// Adds a book to the library and makes its folder, returning its id. A book that's
+/// already saved is left alone.
+pub fn add_book(title: &str, book_url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let id = book_id(book_url, title);
+    if book(&id)?.is_some() {
+        return Ok(id);
+    }
+
+    // Another book with the same title gets a number after its folder name
+    let music_dir = music_dir()?;
+    let name = folder_name(title);
+    let folder = (1..)
+        .map(|n| match n {
+            1 => music_dir.join(&name),
+            n => music_dir.join(format!("{} ({})", name, n)),
+        })
+        .find(|folder| folder_id(folder).is_none_or(|folder_id| folder_id == id))
+        .unwrap();
+    fs::create_dir_all(&folder)?;
+
+    let settings_file = folder.join("settings.json");
+    let mut book_settings = settings::load(&settings_file).unwrap_or_else(|_| settings::new());
+    book_settings.title = title.to_string();
+    book_settings.book_url = book_url.to_string();
+    book_settings.save(&settings_file)?;
+
+    log::info!("Adding {} to the library as {}", title, id);
+    update(|library| {
+        if library.book_mut(&id).is_none() {
+            if let Some(book) = scan_book(&folder, None) {
+                library.insert(book);
+            }
+        }
+    })?;
+    Ok(id)
+}
+
+// Id of the book already in `folder`, if there is one
+fn folder_id(folder: &Path) -> Option<String> {
+    let book_settings = settings::load(&folder.join("settings.json")).ok()?;
+    let title = match book_settings.title.is_empty() {
+        true => folder.file_name()?.to_str()?.to_string(),
+        false => book_settings.title,
+    };
+    Some(book_id(&book_settings.book_url, &title))
+}
+
+/// The file chapter `index` of a book was downloaded to. If the index points at a file
+/// that has gone missing the book is looked at again first.
+pub fn chapter_file(id: &str, index: u32) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let file = book(id)?.and_then(|book| book.chapter_file(index).cloned());
+    match file {
+        Some(file) if !file.exists() => {
+            log::warn!("{} is gone, rescanning {}", file.display(), id);
+            refresh_book(id)?;
+            Ok(book(id)?.and_then(|book| book.chapter_file(index).cloned()))
+        }
+        file => Ok(file),
+    }
+}
+
+/// Throws the index away and builds it again from what's in the music folder. Returns how
+/// many books were found.
+pub fn rescan() -> Result<usize, Box<dyn std::error::Error>> {
+    let scanned = scan()?;
+    let count = scanned.books.len();
+    save(&scanned)?;
+    *LIBRARY.lock().unwrap() = Some(scanned);
+    log::info!("Rescanned library, {} books", count);
+    Ok(count)
+}
+
+/// Looks at the folder of one book again, after files were added, moved or removed
+/// behind the index's back. Drops the book if its folder is gone.
+pub fn refresh_book(id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // A library on a drive that was unplugged would lose all its books otherwise
+    music_dir()?;
+    update(|library| {
+        let Some(known) = library.book_mut(id).cloned() else {
+            return;
+        };
+        library.books.retain(|book| book.id != id);
+        if let Some(book) = scan_book(&known.folder, Some(&known)) {
+            library.insert(book);
+        }
+    })
+}
+
+/// Keeps the details of a book that were just written to its book.json.
+pub fn set_manifest(id: &str, manifest: BookManifest) -> Result<(), Box<dyn std::error::Error>> {
+    update(|library| {
+        let book = library
+            .book_mut(id)
+            .ok_or_else(|| format!("No saved book {}", id))?;
+        book.manifest = Some(manifest);
+        Ok(())
+    })?
+}
+
+/// Records a finished download of chapter `index`.
+pub fn record_chapter(id: &str, index: u32, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let already_known =
+        book(id)?.is_some_and(|book| book.chapter_file(index).is_some_and(|known| known == file));
+    if already_known {
+        return Ok(());
+    }
+    let chapter = LibraryChapter {
+        index,
+        file: file.to_path_buf(),
+        duration: duration(file),
+    };
+    update(|library| {
+        let book = library
+            .book_mut(id)
+            .ok_or_else(|| format!("No saved book {}", id))?;
+        if book.cover.is_none() {
+            book.cover = find_cover(&book.folder);
+        }
+        // The same file can't be two chapters, and the same chapter can't be two files
+        book.chapters
+            .retain(|known| known.index != chapter.index && known.file != chapter.file);
+        book.chapters.push(chapter);
+        book.chapters.sort_by_key(|chapter| chapter.index);
+        Ok(())
+    })?
+}
+
+/// Keeps the listening position in the index, only written out when `write` is set.
+/// `save::save_progress` decides how often that is.
+pub fn set_progress(
+    id: &str,
+    chapter: Option<i32>,
+    time: Option<f64>,
+    write: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let known = with_library(|library| {
+        library.book_mut(id).map(|book| {
+            book.current_chapter = chapter;
+            book.current_chapter_time = time;
+        })
+    })?;
+    if known.is_some() && write {
+        with_library(|library| save(library))??;
+    }
+    Ok(())
+}
+
+/// Moves every book to `target` and makes that the library folder, returning how many books
+/// were moved. Each file is copied and checked before anything is removed from the old
+/// folder, so a move that fails leaves the library where it was. `on_progress` is given the
+/// bytes copied so far and the total.
+pub fn move_library(
+    target: &Path,
+    mut on_progress: impl FnMut(u64, u64),
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if !target.is_absolute() {
+        return Err("The new library folder has to be a full path".into());
+    }
+    let source = music_dir()?;
+    let created = !target.exists();
+    fs::create_dir_all(target)?;
+    let files = match plan_move(&source, target) {
+        Ok(files) => files,
+        Err(e) => {
+            if created {
+                let _ = fs::remove_dir(target);
+            }
+            return Err(e);
+        }
+    };
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+
+    log::info!(
+        "Moving the library from {} to {}",
+        source.display(),
+        target.display()
+    );
+    let mut copied = 0;
+    let mut last_progress = Instant::now();
+    on_progress(0, total);
+    for (done, (file, _)) in files.iter().enumerate() {
+        let to = target.join(file);
+        let result = fs::create_dir_all(to.parent().unwrap_or(target)).and_then(|_| {
+            copy_verified(&source.join(file), &to, |bytes| {
+                copied += bytes;
+                if last_progress.elapsed() >= MOVE_PROGRESS_INTERVAL {
+                    last_progress = Instant::now();
+                    on_progress(copied, total);
+                }
+            })
+        });
+        if let Err(e) = result {
+            // Put the new folder back the way it was
+            remove_files(target, &files[..=done]);
+            if created {
+                let _ = fs::remove_dir(target);
+            }
+            return Err(format!("Failed to copy {}: {}", file.display(), e).into());
+        }
+    }
+    on_progress(total, total);
+
+    // Everything is in both places, so switch over before the old copies go
+    let moved = with_library(|library| {
+        let mut moved = library.clone();
+        for book in moved.books.iter_mut() {
+            rebase(&mut book.folder, &source, target);
+            if let Some(cover) = book.cover.as_mut() {
+                rebase(cover, &source, target);
+            }
+            for chapter in book.chapters.iter_mut() {
+                rebase(&mut chapter.file, &source, target);
+            }
+            if let Some(manifest) = book.manifest.as_mut() {
+                if let Some(cover) = manifest.cover.as_mut() {
+                    rebase(cover, &source, target);
+                }
+                // Imported and adopted books list their chapters by path
+                for url in manifest.chapter_urls.iter_mut().filter(|url| !url.contains("://")) {
+                    let mut file = PathBuf::from(url.as_str());
+                    rebase(&mut file, &source, target);
+                    *url = file.display().to_string();
+                }
+                if let Err(e) = manifest.save(&book.folder.join("book.json")) {
+                    log::warn!("Failed to update {}: {}", book.folder.display(), e);
+                }
+            }
+        }
+        moved
+    })?;
+    set_library_dir(target)?;
+    save(&moved)?;
+    let count = moved.books.len();
+    *LIBRARY.lock().unwrap() = Some(moved);
+
+    remove_files(&source, &files);
+    if let Err(e) = fs::remove_dir(&source) {
+        log::warn!("Left {} behind: {}", source.display(), e);
+    }
+    log::info!("Moved {} books to {}", count, target.display());
+    Ok(count)
+}
+
+// The files that have to be copied to move the library from `source` to `target`, or why
+// it can't be moved there
+fn plan_move(
+    source: &Path,
+    target: &Path,
+) -> Result<Vec<(PathBuf, u64)>, Box<dyn std::error::Error>> {
+    let real_source = source.canonicalize()?;
+    let real_target = target.canonicalize()?;
+    if real_source == real_target {
+        return Err("The library is already there".into());
+    }
+    if real_target.starts_with(&real_source) {
+        return Err("The library can't be moved into itself".into());
+    }
+
+    let files = list_files(source)?;
+    if let Some((taken, _)) = files.iter().find(|(file, _)| target.join(file).exists()) {
+        return Err(format!("{} is already in {}", taken.display(), target.display()).into());
+    }
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    if fs2::available_space(target)? < total {
+        return Err(format!(
+            "Not enough space in {}, the library needs {} MB",
+            target.display(),
+            total / 1024 / 1024 + 1
+        )
+        .into());
+    }
+    Ok(files)
+}
+
+/// Makes `dir` the library folder without moving anything, for a library that was moved by
+/// hand or a drive that was plugged back in. Returns how many books are in it.
+pub fn use_library_dir(dir: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    if !dir.is_absolute() || !dir.is_dir() {
+        return Err(format!("{} is not a folder", dir.display()).into());
+    }
+    set_library_dir(dir)?;
+    rescan()
+}
+
+// The default folder isn't written to the config, so it still follows the music folder
+fn set_library_dir(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let library_dir = match default_music_dir() {
+        Ok(default) if default == dir => None,
+        _ => Some(dir.to_path_buf()),
+    };
+    config::update(|config| config.library_dir = library_dir)?;
+    Ok(())
+}
+
+fn rebase(path: &mut PathBuf, from: &Path, to: &Path) {
+    if let Ok(relative) = path.strip_prefix(from) {
+        *path = to.join(relative);
+    }
+}
+
+// Removes `files` from `dir` along with the folders they leave empty
+fn remove_files(dir: &Path, files: &[(PathBuf, u64)]) {
+    let mut folders = vec![];
+    for (file, _) in files {
+        if let Err(e) = fs::remove_file(dir.join(file)) {
+            log::warn!("Failed to remove {}: {}", dir.join(file).display(), e);
+        }
+        folders.extend(file.ancestors().skip(1).filter(|folder| !folder.as_os_str().is_empty()));
+    }
+    // Deepest first, so a folder is empty by the time it's reached
+    folders.sort_by(|a, b| {
+        let depth = |folder: &Path| folder.components().count();
+        depth(b).cmp(&depth(a)).then_with(|| a.cmp(b))
+    });
+    folders.dedup();
+    for folder in folders {
+        let _ = fs::remove_dir(dir.join(folder));
+    }
+}
+
+fn scan() -> Result<Library, Box<dyn std::error::Error>> {
+    let mut library = Library {
+        version: LIBRARY_VERSION,
+        books: vec![],
+    };
+    for entry in fs::read_dir(music_dir()?)? {
+        let folder = migrate_folder(&entry?.path());
+        if let Err(e) = adopt_folder(&folder) {
+            log::warn!("Failed to read {}: {}", folder.display(), e);
+        }
+        let Some(book) = scan_book(&folder, None) else {
+            continue;
+        };
+        if let Some(known) = library.book_mut(&book.id) {
+            log::warn!(
+                "{} and {} are the same book, only using the first",
+                known.folder.display(),
+                folder.display()
+            );
+            continue;
+        }
+        library.insert(book);
+    }
+    Ok(library)
+}
+
+// Folders from before book ids are named after the raw title, which may not be a safe
+// folder name, so those get renamed
+fn migrate_folder(folder: &Path) -> PathBuf {
+    let Some(name) = folder.file_name().and_then(|name| name.to_str()) else {
+        return folder.to_path_buf();
+    };
+    let safe_name = folder_name(name);
+    if !folder.is_dir() || safe_name == name {
+        return folder.to_path_buf();
+    }
+    let target = folder.with_file_name(&safe_name);
+    if target.exists() {
+        log::warn!("Can't rename {} to {}, it's taken", name, safe_name);
+        return folder.to_path_buf();
+    }
+
+    // Loading migrates the settings, which takes the title from the folder name, so they
+    // have to be saved while it's still the old name
+    let settings_file = folder.join("settings.json");
+    if let Ok(book_settings) = settings::load(&settings_file) {
+        if let Err(e) = book_settings.save(&settings_file) {
+            log::warn!("Failed to migrate {}: {}", settings_file.display(), e);
+        }
+    }
+
+    log::info!("Renaming {} to {}", name, safe_name);
+    match fs::rename(folder, &target) {
+        Ok(()) => target,
+        Err(e) => {
+            log::warn!("Failed to rename {}: {}", folder.display(), e);
+            folder.to_path_buf()
+        }
+    }
+}
+
+// Builds the entry for a book folder. Chapters that are already in `known` keep their
+// index, anything else gets it from the file name.
+fn scan_book(folder: &Path, known: Option<&LibraryBook>) -> Option<LibraryBook> {
+    if !folder.is_dir() {
+        return None;
+    }
+    let settings = settings::load(&folder.join("settings.json")).ok();
+    let manifest = BookManifest::load(&folder.join("book.json")).ok();
+    let book_url = settings
+        .as_ref()
+        .map(|settings| settings.book_url.clone())
+        .or_else(|| known.map(|known| known.book_url.clone()))
+        .unwrap_or_default();
+    let title = settings
+        .as_ref()
+        .map(|settings| settings.title.clone())
+        .filter(|title| !title.is_empty())
+        .or_else(|| manifest.as_ref().map(|manifest| manifest.title.clone()))
+        .unwrap_or_else(|| {
+            folder
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        });
+    // YouTube playlists number their files from 1, other books from 0
+    let first_chapter = if is_playlist_url(&book_url) { 1 } else { 0 };
+
+    let known_duration = |file: &Path| {
+        known
+            .and_then(|known| known.chapters.iter().find(|chapter| chapter.file == file))
+            .and_then(|chapter| chapter.duration)
+            .or_else(|| duration(file))
+    };
+    let mut chapters = vec![];
+    // Imported books list their files in book.json, they aren't named after their chapters
+    if let Some(listed) = manifest
+        .as_ref()
+        .and_then(|manifest| listed_files(manifest, folder))
+    {
+        for (index, file) in listed {
+            chapters.push(LibraryChapter {
+                index,
+                duration: known_duration(&file),
+                file,
+            });
+        }
+    } else {
+        for item in fs::read_dir(folder).ok()?.filter_map(|item| item.ok()) {
+            let file = item.path();
+            let file_name = file.display().to_string();
+            if !file.is_file() || !is_audio_file(&file_name) || file_name.contains("_NA") {
+                continue;
+            }
+            let known_index = known
+                .and_then(|known| known.chapters.iter().find(|chapter| chapter.file == file))
+                .map(|chapter| chapter.index);
+            if let Some(index) = known_index.or_else(|| chapter_index(&file, first_chapter)) {
+                chapters.push(LibraryChapter {
+                    index,
+                    duration: known_duration(&file),
+                    file,
+                });
+            }
+        }
+    }
+    chapters.sort_by_key(|chapter| chapter.index);
+
+    Some(LibraryBook {
+        id: book_id(&book_url, &title),
+        title,
+        folder: folder.to_path_buf(),
+        book_url,
+        manifest,
+        cover: find_cover(folder),
+        chapters,
+        current_chapter: settings
+            .as_ref()
+            .and_then(|settings| settings.current_chapter),
+        current_chapter_time: settings.and_then(|settings| settings.current_chapter_time),
+    })
+}
+
+// The chapter files a book.json lists, for books whose chapters are local files rather than
+// downloads. A file that isn't where it was is looked for in the book's folder, in case the
+// library was moved. Files that are gone are left out.
+fn listed_files(manifest: &BookManifest, folder: &Path) -> Option<Vec<(u32, PathBuf)>> {
+    let urls = &manifest.chapter_urls;
+    if urls.is_empty() || urls.iter().any(|url| url.contains("://")) {
+        return None;
+    }
+    let files = urls
+        .iter()
+        .enumerate()
+        .filter_map(|(index, url)| {
+            let file = PathBuf::from(url);
+            let moved = folder.join(file.file_name()?);
+            [file, moved]
+                .into_iter()
+                .find(|file| file.is_file())
+                .map(|file| (index as u32, file))
+        })
+        .collect();
+    Some(files)
+}
+
+/// Whether `file` is named the way we name downloaded chapters.
+pub fn is_chapter_file(file: &Path) -> bool {
+    file.file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|stem| {
+            stem == "book"
+                || stem
+                    .strip_prefix("chapter_")
+                    .is_some_and(|number| number.parse::<u32>().is_ok())
+        })
+}
+
+/// Chapter number from a file name, `chapter_3.mp3` from us or `Title - 004 Name [id].mp3`
+/// from yt-dlp splitting a video, which counts from 1. A book kept as one file is chapter 0.
+pub fn chapter_index(file: &Path, first_chapter: u32) -> Option<u32> {
+    let stem = file.file_stem()?.to_str()?;
+    if stem == "book" {
+        return Some(0);
+    }
+    if let Some(number) = stem
+        .strip_prefix("chapter_")
+        .and_then(|number| number.parse::<u32>().ok())
+    {
+        return number.checked_sub(first_chapter);
+    }
+    stem.split('-')
+        .skip(1)
+        .find_map(|part| {
+            let digits: String = part
+                .trim_start_matches([' ', '_'])
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse::<u32>().ok()
+        })?
+        .checked_sub(1)
+}
+
+/// The webp cover saved in a book folder.
+pub fn find_cover(audio_path: &Path) -> Option<PathBuf> {
+    fs::read_dir(audio_path)
+        .ok()?
+        .filter_map(|item| item.ok())
+        .map(|item| item.path())
+        .find(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "webp")
+        })
+}
+
+/// Length of an audio file in seconds, None when it can't be told.
+pub fn duration(file: &Path) -> Option<f64> {
+    let decoder = Decoder::new(BufReader::new(fs::File::open(file).ok()?)).ok();
+    decoder
+        .and_then(|decoder| decoder.total_duration())
+        .map(|duration| duration.as_secs_f64())
+        .or_else(|| counted_duration(file))
+}
+
+// Files that don't say how long they are, like mp3s without a Xing header, are measured by
+// adding up their packets. That only reads the file, nothing gets decoded.
+fn counted_duration(path: &Path) -> Option<f64> {
+    let stream = MediaSourceStream::new(Box::new(fs::File::open(path).ok()?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?
+        .format;
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let time_base = track
+        .codec_params
+        .time_base
+        .or_else(|| track.codec_params.sample_rate.map(|rate| TimeBase::new(1, rate)))?;
+
+    let mut length = 0;
+    // Runs until the end of the file, or the first packet that can't be read
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() == track_id {
+            length += packet.dur;
+        }
+    }
+    let time = time_base.calc_time(length);
+    Some(time.seconds as f64 + time.frac).filter(|seconds| *seconds > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::setup::test_env;
+
+    const ODYSSEY: &str = "https://librivox.org/api/feed/audiobooks/?id=1234";
+
+    // An empty folder outside the library for it to be moved to
+    fn empty_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audiody-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn book_id_goes_by_provider_and_source() {
+        let id = book_id(ODYSSEY, "The Odyssey");
+
+        assert!(id.starts_with("librivox-"));
+        assert_eq!(id.len(), "librivox-".len() + 12);
+        // The title doesn't matter when there's a url, the LibriVox id does
+        assert_eq!(id, book_id("https://librivox.app/book/1234", "Odyssey (Butler)"));
+        assert_ne!(id, book_id("https://librivox.org/api/feed/audiobooks/?id=99", "The Odyssey"));
+        // Books of local files go by their title
+        assert!(book_id("", "My Recording").starts_with("local-"));
+        assert_ne!(book_id("", "My Recording"), book_id("", "Another Recording"));
+    }
+
+    #[test]
+    fn folder_name_is_safe_everywhere() {
+        assert_eq!(folder_name("AC/DC: Live?"), "AC_DC_ Live_");
+        assert_eq!(folder_name("  ..Trailing dots.. "), "Trailing dots");
+        assert_eq!(folder_name(".."), "Untitled");
+        assert_eq!(folder_name(""), "Untitled");
+        assert_eq!(folder_name(&"a".repeat(300)).len(), MAX_FOLDER_NAME);
+    }
+
+    #[test]
+    fn books_with_the_same_title_get_numbered_folders() {
+        let _env = test_env();
+        let first = add_book("Same Title", "https://librivox.org/same-title/?id=1").unwrap();
+        let second = add_book("Same Title", "https://librivox.org/same-title/?id=2").unwrap();
+        let third = add_book("Same Title", "https://librivox.org/same-title/?id=3").unwrap();
+
+        let library = music_dir().unwrap();
+        assert_eq!(book_dir(&first).unwrap(), library.join("Same Title"));
+        assert_eq!(book_dir(&second).unwrap(), library.join("Same Title (2)"));
+        assert_eq!(book_dir(&third).unwrap(), library.join("Same Title (3)"));
+        // Adding a saved book again doesn't make it another folder
+        let again = add_book("Same Title", "https://librivox.org/same-title/?id=2").unwrap();
+        assert_eq!(again, second);
+        assert!(!library.join("Same Title (4)").exists());
+    }
+
+    #[test]
+    fn rescan_finds_what_is_on_disk() {
+        let _env = test_env();
+        let gone = add_book("Deleted By Hand", "https://librivox.org/deleted/?id=4321").unwrap();
+        fs::remove_dir_all(book_dir(&gone).unwrap()).unwrap();
+        // Copied in by hand, with nothing but the chapters
+        let folder = music_dir().unwrap().join("Copied In");
+        fs::create_dir_all(&folder).unwrap();
+        for file in ["chapter_1.mp3", "chapter_0.mp3", "notes.txt"] {
+            fs::write(folder.join(file), "not really audio").unwrap();
+        }
+
+        rescan().unwrap();
+
+        assert!(book(&gone).unwrap().is_none());
+        let copied = book(&book_id("", "Copied In")).unwrap().unwrap();
+        assert_eq!(copied.title, "Copied In");
+        let chapters: Vec<(u32, PathBuf)> = copied
+            .chapters
+            .iter()
+            .map(|chapter| (chapter.index, chapter.file.clone()))
+            .collect();
+        assert_eq!(
+            chapters,
+            vec![(0, folder.join("chapter_0.mp3")), (1, folder.join("chapter_1.mp3"))]
+        );
+    }
+
+    #[test]
+    fn moving_the_library_rebases_every_path() {
+        let _env = test_env();
+        let original = music_dir().unwrap();
+        let from = empty_dir("move-from");
+        let to = empty_dir("move-to").join("library");
+        use_library_dir(&from).unwrap();
+
+        let id = add_book("Moving Book", ODYSSEY).unwrap();
+        let chapter = book_dir(&id).unwrap().join("chapter_0.mp3");
+        fs::write(&chapter, "not really audio").unwrap();
+        record_chapter(&id, 0, &chapter).unwrap();
+        let manifest = BookManifest {
+            title: "Moving Book".to_string(),
+            chapter_urls: vec![
+                chapter.display().to_string(),
+                "https://example.com/chapter_1.mp3".to_string(),
+            ],
+            ..Default::default()
+        };
+        manifest.save(&book_dir(&id).unwrap().join("book.json")).unwrap();
+        set_manifest(&id, manifest).unwrap();
+
+        let moved = move_library(&to, |_, _| {}).unwrap();
+
+        assert_eq!(moved, 1);
+        assert_eq!(music_dir().unwrap(), to);
+        assert!(!from.exists());
+        let moved_chapter = to.join("Moving Book").join("chapter_0.mp3");
+        assert!(moved_chapter.exists());
+        let book = book(&id).unwrap().unwrap();
+        assert_eq!(book.folder, to.join("Moving Book"));
+        assert_eq!(chapter_file(&id, 0).unwrap(), Some(moved_chapter.clone()));
+        let urls = &book.manifest.unwrap().chapter_urls;
+        assert_eq!(urls[0], moved_chapter.display().to_string());
+        assert_eq!(urls[1], "https://example.com/chapter_1.mp3");
+        // The copy on disk is rebased too, it's what a rescan goes by
+        let saved = BookManifest::load(&to.join("Moving Book").join("book.json")).unwrap();
+        assert_eq!(saved.chapter_urls[0], moved_chapter.display().to_string());
+
+        use_library_dir(&original).unwrap();
+        let _ = fs::remove_dir_all(to.parent().unwrap());
+    }
+
+    #[test]
+    fn chapter_index_reads_our_names_and_yt_dlp_ones() {
+        let index = |name: &str, first_chapter| chapter_index(Path::new(name), first_chapter);
+
+        assert_eq!(index("chapter_3.mp3", 0), Some(3));
+        // Playlists count from 1
+        assert_eq!(index("chapter_3.mp3", 1), Some(2));
+        assert_eq!(index("chapter_0.mp3", 1), None);
+        assert_eq!(index("book.m4a", 0), Some(0));
+        assert_eq!(index("The Odyssey - 004 Nestor [abc123].mp3", 0), Some(3));
+        assert_eq!(index("chapter_NA.mp3", 0), None);
+        assert_eq!(index("cover.webp", 0), None);
+    }
+}
@@ -1,3 +1,17 @@
 pub mod setup;
 pub mod save;
-pub mod saved;
\ No newline at end of file
+pub mod binaries;
+pub mod cache;
+pub mod config;
+pub mod covers;
+pub mod downloads;
+pub mod export;
+pub mod files;
+pub mod genres;
+pub mod import;
+pub mod library;
+pub mod opml;
+pub mod saved;
+pub mod subscriptions;
+pub mod tags;
+
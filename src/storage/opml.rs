@@ -0,0 +1,87 @@
+use roxmltree::Document;
+use std::fs;
+use std::path::Path;
+
+use crate::api::rss::is_feed_url;
+
+use super::library;
+use super::save::settings;
+use super::subscriptions::subscribe;
+
+/// Subscribes to every feed in an OPML file and returns the titles of the subscribed books.
+pub fn import_opml(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let body = fs::read_to_string(path)?;
+    let document = Document::parse(&body)?;
+
+    let mut subscribed = Vec::new();
+    // Outlines can be nested in folders, so look at all of them
+    for outline in document
+        .descendants()
+        .filter(|n| n.has_tag_name("outline"))
+    {
+        let Some(feed_url) = outline.attribute("xmlUrl") else {
+            continue;
+        };
+        match subscribe(feed_url) {
+            Ok(book) => subscribed.push(book.title),
+            Err(e) => log::error!("Failed to import {}: {}", feed_url, e),
+        }
+    }
+
+    log::info!("Imported {} feeds from {}", subscribed.len(), path.display());
+    Ok(subscribed)
+}
+
+/// Writes the subscriptions and the rest of the library to an OPML file.
+pub fn export_opml(path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut subscriptions = Vec::new();
+    let mut books = Vec::new();
+
+    for book in library::books()? {
+        let title = book.title;
+        let Ok(book_settings) = settings::load(&book.folder.join("settings.json")) else {
+            continue;
+        };
+        if book_settings.book_url.is_empty() {
+            continue;
+        }
+
+        if book_settings.feed.is_some() || is_feed_url(&book_settings.book_url) {
+            subscriptions.push(format!(
+                "      <outline type=\"rss\" text=\"{0}\" title=\"{0}\" xmlUrl=\"{1}\"/>\n",
+                escape(&title),
+                escape(&book_settings.book_url)
+            ));
+        } else {
+            books.push(format!(
+                "      <outline type=\"link\" text=\"{}\" url=\"{}\"/>\n",
+                escape(&title),
+                escape(&book_settings.book_url)
+            ));
+        }
+    }
+
+    let mut opml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    opml.push_str("<opml version=\"2.0\">\n");
+    opml.push_str("  <head>\n    <title>Audiody library</title>\n  </head>\n");
+    opml.push_str("  <body>\n");
+    opml.push_str("    <outline text=\"Subscriptions\">\n");
+    opml.push_str(&subscriptions.concat());
+    opml.push_str("    </outline>\n");
+    opml.push_str("    <outline text=\"Library\">\n");
+    opml.push_str(&books.concat());
+    opml.push_str("    </outline>\n");
+    opml.push_str("  </body>\n</opml>\n");
+    fs::write(path, opml)?;
+
+    let exported = subscriptions.len() + books.len();
+    log::info!("Exported {} books to {}", exported, path.display());
+    Ok(exported)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
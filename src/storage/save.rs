@@ -1,69 +1,371 @@
-use image::io::Reader;
-use image::DynamicImage;
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
 use ureq;
-use webp::Encoder;
 
-use crate::api::yt::YouTubeClient;
+use crate::api::archive::parse_length;
+use crate::api::types::{Book, ChapterOffset};
+use crate::api::webapi::{provider_name, WebApiClient};
+use crate::api::yt::{download_backend, is_playlist_url, YouTubeClient};
 
-use super::saved::extract_number;
-use super::setup::music_dir;
+use super::config;
+use super::covers::{cover_as_jpeg, download_as_webp};
+use super::files::{read_with_backup, write_atomic};
+use super::library::{self, book_dir};
+use super::saved::{is_audio_file, AUDIO_EXTENSIONS};
+use super::subscriptions::{order_book, FeedSettings};
+use super::tags::{write_id3, ChapterTags};
+
+/// Name of the audio file, without extension, for books whose chapters are offsets inside
+/// a single file
+const SINGLE_FILE: &str = "book";
+
+/// Layout version of settings.json. Bump it when the layout changes and teach
+/// `migrate_settings` how to get there from the version before.
+pub const SETTINGS_VERSION: u32 = 3;
+/// Listening position only reaches the disk this often while playing, other changes
+/// are written straight away. `flush_progress` writes whatever is left.
+const PROGRESS_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+// When the progress of each book was last written, and what hasn't been yet
+static PROGRESS_WRITES: Mutex<Option<HashMap<String, ProgressWrite>>> = Mutex::new(None);
+
+struct ProgressWrite {
+    written: Instant,
+    pending: Option<PendingProgress>,
+}
+
+#[derive(Clone)]
+struct PendingProgress {
+    chapter: Option<i32>,
+    book_url: String,
+    time: Option<f64>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct settings {
+    /// Files from before versioning don't have one, those are version 1
+    #[serde(default)]
+    pub version: u32,
+    /// The title the book was saved as, its folder name may differ
+    #[serde(default)]
+    pub title: String,
     pub book_url: String,
     pub current_chapter: Option<i32>,
+    /// Seconds into the current chapter
     pub current_chapter_time: Option<f64>,
+    /// Chapters that were heard to the end, sorted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listened: Vec<u32>,
+    #[serde(default)]
+    pub finished: bool,
+    /// Only set for books that are podcast/RSS subscriptions
+    #[serde(default)]
+    pub feed: Option<FeedSettings>,
+    /// Set when the book is kept as one file, chapters are then played from these offsets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chapter_offsets: Vec<ChapterOffset>,
 }
 
 impl settings {
     pub fn new() -> Self {
         settings {
+            version: SETTINGS_VERSION,
+            title: "".to_string(),
             book_url: "".to_string(),
             current_chapter: None,
             current_chapter_time: None,
+            listened: vec![],
+            finished: false,
+            feed: None,
+            chapter_offsets: vec![],
+        }
+    }
+
+    pub fn save(&self, file_path: &Path) -> io::Result<()> {
+        write_atomic(file_path, &serde_json::to_vec(self)?)
+    }
+
+    /// Loads the settings, upgrading them from older versions. Falls back on the backup
+    /// when the file is broken.
+    pub fn load(file_path: &Path) -> io::Result<Self> {
+        read_with_backup(file_path, |contents| {
+            let value = serde_json::from_slice(contents)?;
+            Ok(serde_json::from_value(migrate_settings(value, file_path))?)
+        })
+    }
+}
+
+// Brings settings written by older versions up to date, one version at a time
+fn migrate_settings(mut value: serde_json::Value, file_path: &Path) -> serde_json::Value {
+    if !value.is_object() {
+        return value;
+    }
+    let mut version = value
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(1) as u32;
+    while version < SETTINGS_VERSION {
+        // Version 2 keeps the title, before that the folder was named after it exactly
+        if version == 1 {
+            let has_title = value
+                .get("title")
+                .and_then(|title| title.as_str())
+                .is_some_and(|title| !title.is_empty());
+            if !has_title {
+                let title = file_path
+                    .parent()
+                    .and_then(|folder| folder.file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                value["title"] = title.into();
+            }
+        }
+        // Version 3 keeps the time within the chapter in seconds, it used to be a fraction of
+        // the chapter. When the chapter's length can't be found it starts over.
+        if version == 2 {
+            let fraction = value.get("current_chapter_time").and_then(|time| time.as_f64());
+            let chapter = value.get("current_chapter").and_then(|chapter| chapter.as_u64());
+            if let (Some(fraction), Some(chapter)) = (fraction, chapter) {
+                let length = file_path
+                    .parent()
+                    .and_then(|folder| legacy_chapter_length(&value, folder, chapter as usize));
+                let seconds = length.map(|length| fraction * length).unwrap_or_default();
+                value["current_chapter_time"] = seconds.into();
+            }
+        }
+        version += 1;
+        log::debug!("Migrated {} to version {}", file_path.display(), version);
+    }
+    value["version"] = version.into();
+    value
+}
+
+// How long a chapter of the book in `folder` is, from the settings being migrated, book.json or
+// the chapter's file
+fn legacy_chapter_length(
+    value: &serde_json::Value,
+    folder: &Path,
+    chapter: usize,
+) -> Option<f64> {
+    if let Some(offset) = value
+        .get("chapter_offsets")
+        .and_then(|offsets| offsets.get(chapter))
+    {
+        let offset: ChapterOffset = serde_json::from_value(offset.clone()).ok()?;
+        return Some(offset.duration());
+    }
+    let manifest = BookManifest::load(&folder.join("book.json")).ok();
+    let listed = manifest.as_ref().and_then(|manifest| {
+        manifest
+            .chapter_durations
+            .get(chapter)
+            .and_then(|length| parse_length(length))
+            .filter(|length| *length > 0.0)
+    });
+    listed.or_else(|| {
+        // Imported books list their files, downloads are found by name
+        let listed_file = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.chapter_urls.get(chapter))
+            .map(PathBuf::from)
+            .filter(|file| file.is_file());
+        let first_chapter = match value.get("book_url").and_then(|url| url.as_str()) {
+            Some(url) if is_playlist_url(url) => 1,
+            _ => 0,
+        };
+        let file = listed_file.or_else(|| {
+            fs::read_dir(folder)
+                .ok()?
+                .filter_map(|item| item.ok())
+                .map(|item| item.path())
+                .find(|file| {
+                    is_audio_file(&file.display().to_string())
+                        && library::chapter_index(file, first_chapter) == Some(chapter as u32)
+                })
+        })?;
+        library::duration(&file)
+    })
+}
+
+/// What a book looked like when it was first downloaded, kept in `book.json` so the saved
+/// copy still has its author, description and chapters when offline.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BookManifest {
+    pub title: String,
+    pub author: String,
+    pub description: String,
+    /// Like "LibriVox" or "RSS", see `provider_name`
+    pub provider: String,
+    pub url: String,
+    pub image_url: String,
+    /// The cover saved next to the chapters, if there was one yet
+    #[serde(default)]
+    pub cover: Option<PathBuf>,
+    pub chapter_titles: Vec<String>,
+    pub chapter_urls: Vec<String>,
+    pub chapter_durations: Vec<String>,
+    pub chapter_reader: Vec<String>,
+}
+
+impl BookManifest {
+    pub fn from_book(book: &Book, cover: Option<PathBuf>) -> Self {
+        BookManifest {
+            title: book.title.clone(),
+            author: book.author.clone(),
+            description: book.description.clone(),
+            provider: provider_name(&book.url).to_string(),
+            url: book.url.clone(),
+            image_url: book.image_URL.clone(),
+            cover,
+            chapter_titles: book.chapter_titles.clone(),
+            chapter_urls: book.chapter_urls.clone(),
+            chapter_durations: book.chapter_durations.clone(),
+            chapter_reader: book.chapter_reader.clone(),
         }
     }
 
-    pub fn save(&self, file_path: &PathBuf) -> io::Result<()> {
-        let mut file = File::create(file_path)?;
-        let mut writer = BufWriter::new(&mut file);
-        serde_json::to_writer(&mut writer, self)?;
-        Ok(())
+    pub fn save(&self, file_path: &Path) -> io::Result<()> {
+        write_atomic(file_path, &serde_json::to_vec(self)?)
     }
 
-    pub fn load(file_path: &PathBuf) -> io::Result<Self> {
-        let file = File::open(file_path)?;
-        let reader = BufReader::new(file);
-        let settings = serde_json::from_reader(reader)?;
-        Ok(settings)
+    pub fn load(file_path: &Path) -> io::Result<Self> {
+        read_with_backup(file_path, |contents| Ok(serde_json::from_slice(contents)?))
     }
 }
 
-/// Book, Chapter, URL
+/// Gets told the bytes downloaded so far and the size of the file when it's known.
+/// Returning an error stops the download.
+pub type Progress<'a> = &'a dyn Fn(u64, Option<u64>) -> io::Result<()>;
+
+/// Book id, Chapter, URL
 pub fn download_audio(
     book: &str,
     chapt: i32,
     url: &str,
     book_url: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    download_audio_with_progress(book, chapt, url, book_url, &|_, _| Ok(()))
+}
+
+/// Like `download_audio`, reporting progress as it goes. YouTube downloads happen in one go,
+/// so they don't report any progress and can't be stopped halfway.
+pub fn download_audio_with_progress(
+    book: &str,
+    chapt: i32,
+    url: &str,
+    book_url: &str,
+    progress: Progress,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Err(e) = ensure_book_manifest(book, book_url) {
+        log::warn!("Failed to save the details of {}: {}", book, e);
+    }
+    let file = fetch_audio(book, chapt, url, book_url, progress)?;
+    // Books kept as one file only have the one file, whichever chapter was asked for
+    let index = if get_chapter_offsets(book).is_empty() { chapt.max(0) as u32 } else { 0 };
+    let already_known = library::book(book)
+        .ok()
+        .flatten()
+        .is_some_and(|known| known.chapter_file(index) == Some(&file));
+    if !already_known && config::get().tag_downloads {
+        if let Err(e) = tag_chapter(book, index, &file) {
+            log::warn!("Failed to tag {}: {}", file.display(), e);
+        }
+    }
+    if let Err(e) = library::record_chapter(book, index, &file) {
+        log::warn!("Failed to add {} to the library: {}", file.display(), e);
+    }
+    Ok(file)
+}
+
+// Gives a new chapter the same tags as the rest of its book. Only mp3 files have ID3 tags,
+// anything else is left as it came.
+fn tag_chapter(book: &str, index: u32, file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let is_mp3 = file
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("mp3"));
+    if !is_mp3 {
+        return Ok(());
+    }
+    let manifest = get_book_manifest(book).ok_or("The book has no details saved")?;
+    let single_file = !get_chapter_offsets(book).is_empty();
+    let track_count = if single_file { 1 } else { manifest.chapter_urls.len().max(1) };
+    let at = |list: &[String]| list.get(index as usize).cloned().unwrap_or_default();
+    let title = match at(&manifest.chapter_titles) {
+        title if single_file || title.is_empty() => manifest.title.clone(),
+        title => title,
+    };
+    // Chapters often have no reader of their own when the whole book has one
+    let reader = match at(&manifest.chapter_reader) {
+        reader if reader.is_empty() => manifest
+            .chapter_reader
+            .iter()
+            .find(|reader| !reader.is_empty())
+            .cloned()
+            .unwrap_or_default(),
+        reader => reader,
+    };
+    let cover = library::find_cover(&book_dir(book)?).and_then(|cover| {
+        cover_as_jpeg(&cover)
+            .map_err(|e| log::warn!("Failed to convert {}: {}", cover.display(), e))
+            .ok()
+    });
+
+    let tags = ChapterTags {
+        title,
+        album: manifest.title,
+        author: manifest.author,
+        reader,
+        track: index + 1,
+        track_count: track_count as u32,
+        cover,
+    };
+    write_id3(file, &tags)?;
+    log::info!("Tagged {}", file.display());
+    Ok(())
+}
+
+fn fetch_audio(
+    book: &str,
+    chapt: i32,
+    url: &str,
+    book_url: &str,
+    progress: Progress,
 ) -> Result<PathBuf, Box<dyn std::error::Error>> {
     // Send the GET request to the URL
-    let audio_path = music_dir().unwrap().as_path().join(book);
+    let audio_path = book_dir(book)?;
     fs::create_dir_all(&audio_path)?;
     // Open the output file to write the audio content
     let mut output_file = audio_path.clone().join(format!("chapter_{}.mp3", chapt));
+    let playlist = url.contains("youtube") && is_playlist_url(book_url);
+    if playlist {
+        // Playlist items are numbered from 1, like split YouTube chapters
+        output_file = audio_file(&audio_path, &format!("chapter_{}", chapt + 1));
+    }
     if !audio_path.join("settings.json").exists() {
         save_progress(book, None, book_url, None)?;
     }
+    let single_file = url.contains("youtube") && !get_chapter_offsets(book).is_empty();
+    if single_file {
+        output_file = single_file_path(&audio_path);
+    }
     for item in fs::read_dir(audio_path.clone())? {
         let item = item?;
         if item.path().is_file() {
             let file_name = item.path().display().to_string();
 
-            if file_name.contains(".mp3") && !file_name.contains("_NA") && ((extract_number(PathBuf::from(audio_path.clone()).join(file_name.clone()).display().to_string().as_str()).unwrap()-1) as i32 == chapt) {
+            // Counted like the library does, playlist files start at 1
+            let first_chapter = if playlist { 1 } else { 0 };
+            if !single_file
+                && is_audio_file(&file_name)
+                && !file_name.contains("_NA")
+                && library::chapter_index(&item.path(), first_chapter) == Some(chapt as u32)
+            {
                 output_file = PathBuf::from(audio_path.clone()).join(file_name.clone());
             } 
         }
@@ -73,6 +375,20 @@ pub fn download_audio(
         if url.contains("youtube") {
             let yt = YouTubeClient::new();
 
+            if single_file {
+                return yt.get_book_audio(
+                    url.to_string(),
+                    audio_path.to_str().unwrap().to_string(),
+                    SINGLE_FILE,
+                );
+            }
+            if playlist {
+                return yt.get_playlist_item(
+                    url.to_string(),
+                    audio_path.to_str().unwrap().to_string(),
+                    chapt,
+                );
+            }
             return Ok(yt.get_chapter(
                 url.to_string(),
                 audio_path.to_str().unwrap().to_string(),
@@ -83,44 +399,7 @@ pub fn download_audio(
             if url.contains("archive.org") {
                 let pos = url.rfind("/");
                 let (img, _) = url.split_at(pos.unwrap());
-                let img = img.to_owned() + "/__ia_thumb.jpg";
-
-                log::info!("Downloading image {img}");
-
-                let response = ureq::get(&img).call()?;
-                if response.status() != 200 {
-                    return Err(format!("Failed to download file: {}", response.status()).into());
-                }
-
-                // Save the downloaded .jpg file
-                let mut p_osstr = output_file.clone().as_os_str().to_owned();
-                p_osstr.push(".jpg");
-                let jpg_path = std::path::Path::new(&p_osstr);
-
-                let mut jpg_file = File::create(jpg_path)?;
-                let mut reader = response.into_reader();
-                io::copy(&mut reader, &mut jpg_file)?;
-
-                // Convert the .jpg file to .webp
-                log::info!("Converting image to WEBP format");
-                let img = Reader::open(jpg_path)?.decode()?;
-                let rgba_image = match img {
-                    DynamicImage::ImageRgba8(img) => img,
-                    other => other.to_rgba8(),
-                };
-
-                let encoder = Encoder::from_rgba(
-                    rgba_image.as_raw(),
-                    rgba_image.width(),
-                    rgba_image.height(),
-                );
-                let webp_data = encoder.encode(75.0); // Adjust quality if needed
-
-                let webp_path = output_file.with_extension("webp");
-                let output_file = File::create(&webp_path)?;
-                let mut writer = BufWriter::new(output_file);
-                fs::remove_file(jpg_path)?;
-                writer.write_all(&webp_data)?;
+                save_cover(book, &(img.to_owned() + "/__ia_thumb.jpg"));
             }
 
             log::info!(
@@ -128,43 +407,497 @@ pub fn download_audio(
                 output_file.display()
             );
 
-            let response = ureq::get(url).call()?;
+            // The chapter only gets its real name once it's complete, an interrupted
+            // download stays in the part file and carries on from there next time
+            let part_file = part_file(&output_file);
+            let mut resume_from = fs::metadata(&part_file)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            let mut request = ureq::get(url);
+            if resume_from > 0 {
+                log::info!("Resuming {} from {} bytes", url, resume_from);
+                request = request.set("Range", &format!("bytes={}-", resume_from));
+            }
+            let response = match request.call() {
+                // The part file doesn't fit the file on the server anymore, start over
+                Err(ureq::Error::Status(416, _)) => {
+                    resume_from = 0;
+                    ureq::get(url).call()?
+                }
+                response => response?,
+            };
 
-            if response.status() != 200 {
+            if response.status() != 200 && response.status() != 206 {
                 return Err(format!("Failed to download file: {}", response.status()).into());
             }
 
-            let mut file = File::create(output_file.clone())?;
+            // Servers that don't do ranges send the whole file again
+            let resumed = response.status() == 206;
+            if !resumed {
+                resume_from = 0;
+            }
+            let total = response
+                .header("Content-Length")
+                .and_then(|length| length.parse::<u64>().ok())
+                .map(|length| resume_from + length);
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resumed)
+                .truncate(!resumed)
+                .open(&part_file)?;
             let mut reader = response.into_reader();
-            std::io::copy(&mut reader, &mut file)?;
+            let size = copy_with_progress(&mut reader, &mut file, resume_from, total, progress)?;
+            drop(file);
+
+            if let Some(total) = total.filter(|total| size != *total) {
+                // Too big means the part file was for a different file, so it's no use
+                if size > total {
+                    let _ = fs::remove_file(&part_file);
+                }
+                return Err(format!(
+                    "Download of {} stopped at {} of {} bytes",
+                    url, size, total
+                )
+                .into());
+            }
+            fs::rename(&part_file, &output_file)?;
         }
     }
 
     Ok(output_file)
 }
+
+// The first download of a book also keeps its details, the book page is normally still in
+// the cache from just having been looked at
+fn ensure_book_manifest(book: &str, book_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if book_dir(book)?.join("book.json").exists() {
+        return Ok(());
+    }
+    let mut details = Runtime::new()?.block_on(WebApiClient::new().get_book(book_url.to_string()))?;
+    // Better no manifest than one that hides the chapter files
+    if details.chapter_urls.is_empty() {
+        return Err(format!("No details found at {}", book_url).into());
+    }
+    if let Some(feed_settings) = get_progress(book).ok().and_then(|settings| settings.feed) {
+        details = order_book(details, &feed_settings);
+    }
+    save_book_manifest(book, &details)
+}
+
+/// Writes `book.json` for a saved book from `details`, replacing whatever was there.
+pub fn save_book_manifest(book: &str, details: &Book) -> Result<(), Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    fs::create_dir_all(&audio_path)?;
+    let manifest = BookManifest::from_book(details, library::find_cover(&audio_path));
+    manifest.save(&audio_path.join("book.json"))?;
+    library::set_manifest(book, manifest)?;
+    Ok(())
+}
+
+/// The details kept in `book.json`, if the book has them.
+pub fn get_book_manifest(book: &str) -> Option<BookManifest> {
+    let file = book_dir(book).ok()?.join("book.json");
+    BookManifest::load(&file).ok()
+}
+
+// Copies everything from `reader`, `start` is how much was already downloaded before.
+// Returns how much has been downloaded in total.
+fn copy_with_progress(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    start: u64,
+    total: Option<u64>,
+    progress: Progress,
+) -> io::Result<u64> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut downloaded = start;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+        downloaded += read as u64;
+        progress(downloaded, total)?;
+    }
+    Ok(downloaded)
+}
+
+/// Where a download goes until it's complete, like `chapter_1.mp3.part`.
+pub fn part_file(file: &Path) -> PathBuf {
+    let mut part = file.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+// An already downloaded file named `stem`, or where the current backend would save it
+fn audio_file(audio_path: &Path, stem: &str) -> PathBuf {
+    AUDIO_EXTENSIONS
+        .iter()
+        .map(|extension| audio_path.join(format!("{}.{}", stem, extension)))
+        .find(|file| file.exists())
+        .unwrap_or_else(|| audio_path.join(format!("{}.{}", stem, download_backend().extension())))
+}
+
+/// The audio file of a book kept as one file, mp3 when it came from yt-dlp and m4a from the
+/// native downloader.
+pub fn single_file_path(audio_path: &Path) -> PathBuf {
+    audio_file(audio_path, SINGLE_FILE)
+}
+/// Keeps a webp copy of the cover next to the chapters, so the book still has one offline.
+/// Doesn't do anything if the book already has a cover.
+pub fn save_cover(book: &str, image_url: &str) {
+    let Ok(audio_path) = book_dir(book) else {
+        return;
+    };
+    let has_cover = fs::read_dir(&audio_path).is_ok_and(|items| {
+        items
+            .filter_map(|item| item.ok())
+            .any(|item| item.path().extension().is_some_and(|extension| extension == "webp"))
+    });
+    if has_cover || image_url.is_empty() {
+        return;
+    }
+
+    log::info!("Saving cover {image_url}");
+    if let Err(e) = download_as_webp(image_url, &audio_path.join("cover.webp")) {
+        log::warn!("Failed to save cover for {}: {}", book, e);
+    }
+}
+
 pub fn save_progress(
     book: &str,
     chapt: Option<i32>,
     url: &str,
     chapter_play_time: Option<f64>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let audio_path = music_dir().unwrap().as_path().join(book);
-    let settings_file = audio_path.clone().join("settings.json");
-    let mut settings = settings::new();
-    settings = settings {
+    let progress = PendingProgress {
+        chapter: chapt,
         book_url: url.to_string(),
-        current_chapter: chapt,
-        current_chapter_time: chapter_play_time,
+        time: chapter_play_time,
     };
+    let moved_on = get_progress(book)
+        .map_or(true, |settings| settings.book_url != url || settings.current_chapter != chapt);
+
+    // Playing saves the position twice a second, in between writes it's only kept in memory
+    if progress_write_due(book, moved_on, &progress) {
+        return write_progress(book, &progress);
+    }
+    if let Err(e) = library::set_progress(book, chapt, chapter_play_time, false) {
+        log::warn!("Failed to keep progress of {} in the library: {}", book, e);
+    }
+    Ok(())
+}
+
+/// Writes the listening positions that are only in memory so far. Called when playback
+/// pauses or stops and when the app closes, so nothing is lost.
+pub fn flush_progress() {
+    let pending: Vec<(String, PendingProgress)> = {
+        let mut writes = PROGRESS_WRITES.lock().unwrap();
+        writes
+            .iter_mut()
+            .flatten()
+            .filter_map(|(book, write)| {
+                write.written = Instant::now();
+                write.pending.take().map(|progress| (book.clone(), progress))
+            })
+            .collect()
+    };
+    for (book, progress) in pending {
+        if let Err(e) = write_progress(&book, &progress) {
+            log::warn!("Failed to save progress of {}: {}", book, e);
+        }
+    }
+}
 
+fn write_progress(
+    book: &str,
+    progress: &PendingProgress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    // Keep anything else stored with the book, like feed settings
+    let mut settings = settings::load(&settings_file).unwrap_or_else(|_| settings::new());
+    settings.book_url = progress.book_url.clone();
+    settings.current_chapter = progress.chapter;
+    settings.current_chapter_time = progress.time;
     settings.save(&settings_file)?;
+    if let Err(e) = library::set_progress(book, progress.chapter, progress.time, true) {
+        log::warn!("Failed to keep progress of {} in the library: {}", book, e);
+    }
+    Ok(())
+}
+
+// Whether it's time to write the progress of `book` again, `force` always makes it so.
+// When it isn't, `progress` is kept to be written later.
+fn progress_write_due(book: &str, force: bool, progress: &PendingProgress) -> bool {
+    let mut writes = PROGRESS_WRITES.lock().unwrap();
+    let writes = writes.get_or_insert_with(HashMap::new);
+    match writes.get_mut(book) {
+        Some(write) if !force && write.written.elapsed() < PROGRESS_WRITE_INTERVAL => {
+            write.pending = Some(progress.clone());
+            false
+        }
+        _ => {
+            writes.insert(
+                book.to_string(),
+                ProgressWrite {
+                    written: Instant::now(),
+                    pending: None,
+                },
+            );
+            true
+        }
+    }
+}
 
+// The position of `book` that hasn't been written yet, if any
+fn pending_progress(book: &str) -> Option<PendingProgress> {
+    let writes = PROGRESS_WRITES.lock().unwrap();
+    writes.as_ref()?.get(book)?.pending.clone()
+}
+
+// Throws away the position of `book` that hasn't been written yet
+fn forget_pending_progress(book: &str) {
+    let mut writes = PROGRESS_WRITES.lock().unwrap();
+    if let Some(write) = writes.as_mut().and_then(|writes| writes.get_mut(book)) {
+        write.pending = None;
+    }
+}
+
+/// Keeps the book as one file with chapters at `offsets`, or as a file per chapter when
+/// `offsets` is empty.
+pub fn set_chapter_offsets(
+    book: &str,
+    book_url: &str,
+    offsets: Vec<ChapterOffset>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    fs::create_dir_all(&audio_path)?;
+    let settings_file = audio_path.join("settings.json");
+    let mut settings = settings::load(&settings_file).unwrap_or_else(|_| settings::new());
+    settings.book_url = book_url.to_string();
+    settings.chapter_offsets = offsets;
+    settings.save(&settings_file)?;
     Ok(())
 }
 
+/// Chapter offsets of a book kept as one file, empty for books with a file per chapter.
+pub fn get_chapter_offsets(book: &str) -> Vec<ChapterOffset> {
+    get_progress(book)
+        .map(|settings| settings.chapter_offsets)
+        .unwrap_or_default()
+}
+
 pub fn get_progress(book: &str) -> Result<settings, Box<dyn std::error::Error>> {
-    let audio_path = music_dir().unwrap().as_path().join(book);
-    let settings_file = audio_path.clone().join("settings.json");
-    let settings_str = fs::read_to_string(settings_file)?;
-    Ok(serde_json::from_str(&settings_str)?)
+    let settings_file = book_dir(book)?.join("settings.json");
+    let mut settings = settings::load(&settings_file)?;
+    // While playing, the latest position may not have reached the disk yet
+    if let Some(progress) = pending_progress(book) {
+        settings.book_url = progress.book_url;
+        settings.current_chapter = progress.chapter;
+        settings.current_chapter_time = progress.time;
+    }
+    Ok(settings)
+}
+
+/// How far into the whole book the listener is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BookProgress {
+    /// Seconds from the start of the book
+    pub position: f64,
+    /// In seconds, None while the length of some chapter isn't known
+    pub length: Option<f64>,
+    pub finished: bool,
+    /// Chapters heard to the end, sorted
+    pub listened: Vec<u32>,
+}
+
+impl BookProgress {
+    /// How much of the book has been heard, from 0 to 1.
+    pub fn fraction(&self) -> Option<f64> {
+        if self.finished {
+            return Some(1.0);
+        }
+        self.length
+            .filter(|length| *length > 0.0)
+            .map(|length| (self.position / length).clamp(0.0, 1.0))
+    }
+
+    /// Seconds of listening left at `speed`.
+    pub fn remaining(&self, speed: f32) -> Option<f64> {
+        if self.finished {
+            return Some(0.0);
+        }
+        let left = (self.length? - self.position).max(0.0);
+        Some(left / speed.max(0.1) as f64)
+    }
+}
+
+/// Where the listener is in the whole book, worked out from the chapter they're on and the
+/// length of the chapters before it.
+pub fn book_progress(book: &str) -> Result<BookProgress, Box<dyn std::error::Error>> {
+    let settings = get_progress(book)?;
+    let lengths = chapter_lengths(book, &settings)?;
+    let chapter = settings.current_chapter.unwrap_or_default().max(0) as usize;
+    let before: f64 = lengths.iter().take(chapter).map(|length| length.unwrap_or_default()).sum();
+    let length = lengths.iter().copied().sum::<Option<f64>>();
+    Ok(BookProgress {
+        position: before + settings.current_chapter_time.unwrap_or_default(),
+        length,
+        finished: settings.finished,
+        listened: settings.listened,
+    })
+}
+
+// Length of every chapter of the book, from the library for the downloaded ones and from the
+// book's details for the rest
+fn chapter_lengths(
+    book: &str,
+    settings: &settings,
+) -> Result<Vec<Option<f64>>, Box<dyn std::error::Error>> {
+    if !settings.chapter_offsets.is_empty() {
+        return Ok(settings
+            .chapter_offsets
+            .iter()
+            .map(|offset| Some(offset.duration()))
+            .collect());
+    }
+    let saved = library::book(book)?.ok_or_else(|| format!("No saved book {}", book))?;
+    let listed = saved
+        .manifest
+        .map(|manifest| manifest.chapter_durations)
+        .unwrap_or_default();
+    let count = saved
+        .chapters
+        .iter()
+        .map(|chapter| chapter.index as usize + 1)
+        .max()
+        .unwrap_or_default()
+        .max(listed.len());
+    Ok((0..count)
+        .map(|index| {
+            let downloaded = saved
+                .chapters
+                .iter()
+                .find(|chapter| chapter.index as usize == index)
+                .and_then(|chapter| chapter.duration);
+            downloaded.or_else(|| {
+                listed
+                    .get(index)
+                    .and_then(|length| parse_length(length))
+                    .filter(|length| *length > 0.0)
+            })
+        })
+        .collect())
+}
+
+/// Remembers that `chapter` was heard to the end. Hearing the last chapter finishes the book.
+pub fn mark_listened(book: &str, chapter: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    let mut settings = settings::load(&settings_file)?;
+    let count = chapter_lengths(book, &settings)?.len();
+    if let Err(at) = settings.listened.binary_search(&chapter) {
+        settings.listened.insert(at, chapter);
+    }
+    if chapter as usize + 1 >= count {
+        settings.finished = true;
+    }
+    settings.save(&settings_file)?;
+    Ok(())
+}
+
+/// Marks the whole book as heard, or as not heard at all, which also takes it back to the
+/// start.
+pub fn set_finished(book: &str, finished: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    let mut settings = settings::load(&settings_file)?;
+    settings.finished = finished;
+    if finished {
+        let count = chapter_lengths(book, &settings)?.len() as u32;
+        settings.listened = (0..count).collect();
+    } else {
+        // Back to the start, not to wherever it was playing
+        forget_pending_progress(book);
+        settings.listened.clear();
+        settings.current_chapter = Some(0);
+        settings.current_chapter_time = Some(0.0);
+    }
+    settings.save(&settings_file)?;
+    library::set_progress(book, settings.current_chapter, settings.current_chapter_time, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A fresh folder for the book, named like one in the library
+    fn book_folder(test: &str) -> PathBuf {
+        let folder = std::env::temp_dir()
+            .join(format!("audiody-{}-{}", test, std::process::id()))
+            .join("The Odyssey");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    #[test]
+    fn v1_settings_are_brought_up_to_date() {
+        let folder = book_folder("migrate");
+        let file = folder.join("settings.json");
+        // Version 1 had no version, no title, and the time as a fraction of the chapter
+        let v1 = json!({
+            "book_url": "https://librivox.org/the-odyssey-by-homer/",
+            "current_chapter": 1,
+            "current_chapter_time": 0.25,
+            "chapter_offsets": [{"start": 0.0, "end": 100.0}, {"start": 100.0, "end": 300.0}],
+        });
+        fs::write(&file, v1.to_string()).unwrap();
+
+        let loaded = settings::load(&file).unwrap();
+
+        assert_eq!(loaded.version, SETTINGS_VERSION);
+        assert_eq!(loaded.title, "The Odyssey");
+        assert_eq!(loaded.current_chapter, Some(1));
+        assert_eq!(loaded.current_chapter_time, Some(50.0));
+        assert!(loaded.listened.is_empty());
+        assert!(!loaded.finished);
+        let _ = fs::remove_dir_all(folder.parent().unwrap());
+    }
+
+    #[test]
+    fn v2_time_starts_over_without_a_chapter_length() {
+        let folder = book_folder("migrate-v2");
+        let v2 = json!({
+            "version": 2,
+            "title": "Odyssey",
+            "current_chapter": 3,
+            "current_chapter_time": 0.5,
+        });
+
+        let migrated = migrate_settings(v2, &folder.join("settings.json"));
+
+        assert_eq!(migrated["version"], SETTINGS_VERSION);
+        // The title it had is kept
+        assert_eq!(migrated["title"], "Odyssey");
+        assert_eq!(migrated["current_chapter_time"], 0.0);
+        let _ = fs::remove_dir_all(folder.parent().unwrap());
+    }
+
+    #[test]
+    fn current_settings_are_left_alone() {
+        let current = json!({
+            "version": SETTINGS_VERSION,
+            "title": "Odyssey",
+            "current_chapter": 3,
+            "current_chapter_time": 0.5,
+        });
+
+        let migrated = migrate_settings(current.clone(), Path::new("settings.json"));
+
+        assert_eq!(migrated, current);
+    }
 }
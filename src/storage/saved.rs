@@ -1,151 +1,74 @@
-use super::{save::settings, setup::music_dir};
+use super::{library, setup::music_dir};
 use crate::api::types::Book;
+use std::time::{Duration, SystemTime};
 use std::{fs, path::PathBuf};
 
-pub fn get_saved_book(book_title: String) -> Result<Option<Book>, Box<dyn std::error::Error>> {
-    let music_dir: PathBuf = music_dir()?;
-    let mut book: Option<Book> = None;
+/// Partial downloads nobody has touched in this long are given up on
+pub const STALE_PARTIAL_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
-    for entry in fs::read_dir(music_dir)? {
-        let entry = entry?;
-        let mut chapter_urls = Vec::new();
-        let mut image_url = Vec::new();
-        let mut settings: settings = settings {
-            book_url: "".to_string(),
-            current_chapter: None,
-            current_chapter_time: None,
-        };
-
-        if entry
-            .path()
-            .display()
-            .to_string()
-            .contains(book_title.as_str())
-        {
-            for item in fs::read_dir(entry.path())? {
-                let item = item?;
-                if item.path().is_file() {
-                    let file_name = item.path().display().to_string();
+/// Extensions of the chapter files we download, yt-dlp and the other providers give mp3
+/// while the native YouTube downloader keeps the m4a stream
+pub const AUDIO_EXTENSIONS: [&str; 2] = ["mp3", "m4a"];
 
-                    if file_name.contains(".webp") {
-                        log::info!("Found: {}", file_name);
-                        image_url.push(file_name);
-                    } else if file_name.contains(".mp3") {
-                        if !file_name.contains("_NA") {
-                            chapter_urls.push(file_name);
-                        }
-                    } else if file_name.contains("settings.json") {
-                        settings =
-                            serde_json::from_str(&fs::read_to_string(file_name).unwrap()).unwrap();
-                    }
-                }
-            }
+/// Everything the player can open, for books that were imported rather than downloaded
+pub const PLAYABLE_EXTENSIONS: [&str; 8] =
+    ["mp3", "m4a", "m4b", "aac", "flac", "ogg", "oga", "wav"];
 
-            // Ensure image_url is valid and has at least one entry
-            let image_url = image_url
-                .first()
-                .cloned()
-                .unwrap_or_else(|| "default_image_url".to_string()); // Replace with a fallback value if necessary
+pub fn is_audio_file(file_name: &str) -> bool {
+    let file_name = file_name.to_ascii_lowercase();
+    PLAYABLE_EXTENSIONS
+        .iter()
+        .any(|extension| file_name.ends_with(&format!(".{}", extension)))
+}
 
-            chapter_urls.sort_by(|a, b| {
-                let a_num = extract_number(a).unwrap_or(0);
-                let b_num = extract_number(b).unwrap_or(0);
-                a_num.cmp(&b_num)
-            });
-            
-            log::info!("Chapter URLs: {}", chapter_urls.join(", "));
-            book = Some(Book {
-                saved: true,
-                title: entry.file_name().into_string().unwrap_or_default(),
-                chapter_urls,
-                chapter_durations: vec![], // Update with appropriate data
-                chapter_reader: vec![],    // Update with appropriate data
-                description: "".to_string(),
-                author: "".to_string(),
-                url: settings.book_url.to_string(),
-                image_URL: image_url,
-            });
-            return Ok(book);
-        }
-    }
-    Ok(book)
+/// The saved book with id `book_id`, from the library index.
+pub fn get_saved_book(book_id: String) -> Result<Option<Book>, Box<dyn std::error::Error>> {
+    Ok(library::book(&book_id)?.map(|book| book.to_book()))
 }
 
+/// Every saved book, from the library index. Use `library::rescan` when the music folder
+/// was changed by hand.
 pub fn get_saved_books() -> Result<Vec<Book>, Box<dyn std::error::Error>> {
-    let mut books: Vec<Book> = Vec::new();
+    Ok(library::books()?
+        .iter()
+        .map(|book| book.to_book())
+        .collect())
+}
 
-    let music_dir = music_dir()?;
+/// File of chapter `chapt` (counting from 0) of a saved book, if it has been downloaded.
+pub fn check_book_chapter_url(chapt: u32, book_id: String) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    library::chapter_file(&book_id, chapt)
+}
 
-    for entry in fs::read_dir(music_dir)? {
+/// Removes the `.part` files of downloads that were left unfinished for longer than
+/// `STALE_PARTIAL_AGE`. Newer ones are kept so the download can resume, and only logged.
+pub fn clean_stale_partials() -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut removed = vec![];
+    for entry in fs::read_dir(music_dir()?)? {
         let entry = entry?;
-        let mut chapter_urls = Vec::new();
-        let mut image_url = Vec::new();
-        let mut settings: settings = settings {
-            book_url: "".to_string(),
-            current_chapter: None,
-            current_chapter_time: None,
-        };
-
+        if !entry.path().is_dir() {
+            continue;
+        }
         for item in fs::read_dir(entry.path())? {
-            let item = item?;
-            if item.path().is_file() {
-                let file_name = item.path().display().to_string();
-
-                if file_name.contains(".webp") {
-                    log::info!("Found: {}", file_name);
-                    image_url.push(file_name);
-                } else if file_name.contains(".mp3") {
-                    chapter_urls.push(file_name);
-                } else if file_name.contains("settings.json") {
-                    settings =
-                        serde_json::from_str(&fs::read_to_string(file_name).unwrap()).unwrap();
-                }
+            let path = item?.path();
+            if path.extension().is_none_or(|extension| extension != "part") {
+                continue;
             }
-        }
-
-        // Ensure image_url is valid and has at least one entry
-        let image_url = image_url
-            .first()
-            .cloned()
-            .unwrap_or_else(|| "default_image_url".to_string()); // Replace with a fallback value if necessary
-
-        books.push(Book {
-            saved: true,
-            title: entry.file_name().into_string().unwrap_or_default(),
-            chapter_urls,
-            chapter_durations: vec![], // Update with appropriate data
-            chapter_reader: vec![],    // Update with appropriate data
-            description: "".to_string(),
-            author: "".to_string(),
-            url: settings.book_url.to_string(),
-            image_URL: image_url,
-        });
-    }
-
-    Ok(books)
-}
-
-pub fn check_book_chapter_url(chapt: u32, title: String) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
-    let music_dir: PathBuf = music_dir()?.join(title);
-    let mut chapter_urls = Vec::new();
-
-    for item in fs::read_dir(music_dir.as_path())? {
-        let item = item?;
-        if item.path().is_file() {
-            let file_name = item.path().display().to_string();
-
-            if file_name.contains(".mp3") && !file_name.contains("_NA") {
-                if file_name.contains("chapter") && file_name.contains(chapt.to_string().as_str()) {
-                    return Ok(Some(music_dir.join(file_name)));
-                } else if (extract_number(&file_name).unwrap() - 1) == chapt {
-                    return Ok(Some(music_dir.join(file_name)));
-                    
-                }
-                chapter_urls.push(file_name);
+            let age = fs::metadata(&path)?
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or_default();
+            if age < STALE_PARTIAL_AGE {
+                log::info!("Unfinished download: {}", path.display());
+                continue;
             }
+            log::info!("Removing stale partial download: {}", path.display());
+            fs::remove_file(&path)?;
+            removed.push(path);
         }
     }
-    Ok(None)
+    Ok(removed)
 }
 
 pub fn extract_number(filename: &str) -> Option<u32> {
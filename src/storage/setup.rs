@@ -1,16 +1,26 @@
 use std::fs;
 use std::path::PathBuf;
 
+use super::config;
+
+/// Set to keep the settings, library index and cache somewhere other than the usual config
+/// folder, e.g. for a portable install or to keep tests away from the real files.
+pub const CONFIG_DIR_VAR: &str = "AUDIODY_CONFIG_DIR";
+
 pub fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
     // Get the config directory path
     // Lin: Some(/home/alice/.config)
     // Win: Some(C:\Users\Alice\AppData\Roaming)
     // Mac: Some(/Users/Alice/Library/Application Support)
 
-    let base_dir = dirs::config_dir().ok_or("Unable to get config directory")?;
-
-    // Specify the name of the directory you want to create
-    let new_dir = base_dir.join("Audiody");
+    let new_dir = match std::env::var_os(CONFIG_DIR_VAR) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let base_dir = dirs::config_dir().ok_or("Unable to get config directory")?;
+            // Specify the name of the directory you want to create
+            base_dir.join("Audiody")
+        }
+    };
     if new_dir.exists() {
         return Ok(new_dir.clone());
     }
@@ -23,15 +33,16 @@ pub fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
 }
 
 pub fn music_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
-    // Get the config directory path
-    // Lin: Some(/home/alice/Music)
-    // Win: Some(C:\Users\Alice\Music)
-    // Mac: Some(/Users/Alice/Music)
-
-    let base_dir = dirs::audio_dir().ok_or("Unable to get audio directory")?;
+    // A library moved somewhere else in the settings. That can be a drive that isn't
+    // plugged in right now, so it's not made again as an empty folder.
+    if let Some(dir) = config::get().library_dir {
+        if !dir.is_dir() {
+            return Err(format!("The library folder {} is not available", dir.display()).into());
+        }
+        return Ok(dir);
+    }
 
-    // Specify the name of the directory you want to create
-    let new_dir = base_dir.join("Audiody").join("books");
+    let new_dir = default_music_dir()?;
     if new_dir.exists() {
         return Ok(new_dir.clone());
     }
@@ -41,3 +52,51 @@ pub fn music_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
 
     Ok(new_dir)
 }
+
+/// Where the library is kept unless it was moved.
+pub fn default_music_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // Get the music directory path
+    // Lin: Some(/home/alice/Music)
+    // Win: Some(C:\Users\Alice\Music)
+    // Mac: Some(/Users/Alice/Music)
+    // Linux only has one when XDG_MUSIC_DIR is set, otherwise ~/Music is used, or the app
+    // data folder when there's no home either
+    let base_dir = dirs::audio_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join("Music")))
+        .or_else(dirs::data_dir)
+        .ok_or("Unable to find a folder for the library")?;
+
+    Ok(base_dir.join("Audiody").join("books"))
+}
+
+/// Where exported books go unless told otherwise.
+pub fn default_export_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let base_dir = dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .ok_or("Unable to find a folder for exports")?;
+
+    Ok(base_dir.join("Audiody"))
+}
+
+/// Points the config folder at a scratch folder for this test run, with the library in a
+/// `books` folder next to it. The config, library index and cache are shared by the whole
+/// process, so tests that touch them hold on to the guard while they run.
+#[cfg(test)]
+pub(crate) fn test_env() -> std::sync::MutexGuard<'static, ()> {
+    use std::sync::{Mutex, Once};
+
+    static SETUP: Once = Once::new();
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    SETUP.call_once(|| {
+        let root = std::env::temp_dir().join(format!("audiody-test-env-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let library = root.join("books");
+        fs::create_dir_all(&library).unwrap();
+        let config = serde_json::json!({ "library_dir": library });
+        fs::write(root.join("config.json"), config.to_string()).unwrap();
+        std::env::set_var(CONFIG_DIR_VAR, &root);
+    });
+    // A failed test shouldn't take every test after it down too
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
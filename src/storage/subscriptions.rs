@@ -0,0 +1,443 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::api::rss::{Feed, RssClient};
+use crate::api::types::Book;
+
+use super::files::now;
+use super::library::{self, book_dir};
+use super::save::{download_audio, get_book_manifest, save_book_manifest, settings};
+
+/// How often subscribed feeds are checked for new episodes
+pub const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EpisodeOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
+}
+
+/// Per-feed settings, stored inside the book's settings.json. The feed URL is the book URL.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FeedSettings {
+    #[serde(default)]
+    pub order: EpisodeOrder,
+    /// Keep only this many downloaded episodes, older ones get deleted
+    #[serde(default)]
+    pub retention: Option<u32>,
+    /// Download the newest N episodes whenever the feed is refreshed
+    #[serde(default)]
+    pub auto_download: u32,
+    /// Episode ids seen on the last refresh
+    #[serde(default)]
+    pub known_episodes: Vec<String>,
+    /// Unix time of the last refresh
+    #[serde(default)]
+    pub last_refreshed: u64,
+}
+
+impl FeedSettings {
+    /// Chapter number of the episode at `position` (oldest first) in a feed of `len` episodes
+    pub fn chapter_index(&self, position: usize, len: usize) -> usize {
+        match self.order {
+            EpisodeOrder::OldestFirst => position,
+            EpisodeOrder::NewestFirst => len - 1 - position,
+        }
+    }
+}
+
+/// Subscribes to a feed, creating the book folder for it. Subscribing twice just returns the book.
+pub fn subscribe(feed_url: &str) -> Result<Book, Box<dyn std::error::Error>> {
+    let feed = RssClient::new().get_feed(feed_url)?;
+    if feed.title.is_empty() {
+        return Err("Feed has no title".into());
+    }
+
+    let book_id = library::add_book(&feed.title, feed_url)?;
+    let settings_file = book_dir(&book_id)?.join("settings.json");
+
+    let mut book_settings = settings::load(&settings_file).unwrap_or_else(|_| settings::new());
+    if book_settings.feed.is_none() {
+        log::info!("Subscribing to {} ({})", feed.title, feed_url);
+        book_settings.book_url = feed_url.to_string();
+        book_settings.feed = Some(FeedSettings {
+            known_episodes: feed.episodes.iter().map(|e| e.id.clone()).collect(),
+            last_refreshed: now(),
+            ..Default::default()
+        });
+        book_settings.save(&settings_file)?;
+    }
+
+    let feed_settings = book_settings.feed.unwrap_or_default();
+    let mut book = order_book(feed.to_book(feed_url), &feed_settings);
+    save_book_manifest(&book_id, &book)?;
+    apply_policies(&book_id, feed_url, &feed, &feed_settings)?;
+
+    book.saved = true;
+    Ok(book)
+}
+
+/// Stops refreshing the feed, downloaded episodes are kept.
+pub fn unsubscribe(book: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    let mut book_settings = settings::load(&settings_file)?;
+    book_settings.feed = None;
+    book_settings.save(&settings_file)?;
+    Ok(())
+}
+
+/// The settings of a subscribed feed.
+pub fn feed_settings(book: &str) -> Result<FeedSettings, Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    Ok(settings::load(&settings_file)?
+        .feed
+        .ok_or("Book is not a subscription")?)
+}
+
+/// Changes the order, retention and auto-download of a subscribed feed. Switching the order
+/// renumbers the episodes already downloaded. The feed is refreshed straight away so the
+/// new settings apply, when it can't be reached they apply on the next refresh.
+pub fn set_feed_settings(
+    book: &str,
+    order: EpisodeOrder,
+    retention: Option<u32>,
+    auto_download: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let settings_file = book_dir(book)?.join("settings.json");
+    let mut book_settings = settings::load(&settings_file)?;
+    let mut feed_settings = book_settings.feed.clone().ok_or("Book is not a subscription")?;
+
+    if feed_settings.order != order {
+        // Chapter numbers count from the other end now
+        let len = feed_settings.known_episodes.len();
+        reverse_chapter_files(book, len)?;
+        let flip = |chapter: usize| len.saturating_sub(1 + chapter);
+        book_settings.current_chapter = book_settings
+            .current_chapter
+            .map(|chapter| flip(chapter.max(0) as usize) as i32);
+        book_settings.listened = book_settings
+            .listened
+            .iter()
+            .map(|chapter| flip(*chapter as usize) as u32)
+            .collect();
+        book_settings.listened.sort_unstable();
+        if let Some(mut manifest) = get_book_manifest(book) {
+            manifest.chapter_titles.reverse();
+            manifest.chapter_urls.reverse();
+            manifest.chapter_durations.reverse();
+            manifest.chapter_reader.reverse();
+            manifest.save(&book_dir(book)?.join("book.json"))?;
+            library::set_manifest(book, manifest)?;
+        }
+    }
+    feed_settings.order = order;
+    feed_settings.retention = retention;
+    feed_settings.auto_download = auto_download;
+    book_settings.feed = Some(feed_settings);
+    book_settings.save(&settings_file)?;
+    library::refresh_book(book)?;
+
+    if let Err(e) = refresh_subscription(book) {
+        log::warn!("Failed to refresh {}, the settings apply next time: {}", book, e);
+    }
+    Ok(())
+}
+
+/// Ids of all saved books that are feed subscriptions, with their settings.
+pub fn get_subscriptions() -> Result<Vec<(String, settings)>, Box<dyn std::error::Error>> {
+    let mut subscriptions = Vec::new();
+    for book in library::books()? {
+        let settings_file = book.folder.join("settings.json");
+        if let Ok(book_settings) = settings::load(&settings_file) {
+            if book_settings.feed.is_some() {
+                subscriptions.push((book.id, book_settings));
+            }
+        }
+    }
+    Ok(subscriptions)
+}
+
+/// Checks a subscribed feed for new episodes and returns how many showed up.
+pub fn refresh_subscription(book: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    let settings_file = audio_path.join("settings.json");
+    let mut book_settings = settings::load(&settings_file)?;
+    let mut feed_settings = book_settings.feed.clone().ok_or("Book is not a subscription")?;
+
+    let feed = RssClient::new().get_feed(&book_settings.book_url)?;
+    let new_episodes = feed
+        .episodes
+        .iter()
+        .filter(|e| !feed_settings.known_episodes.contains(&e.id))
+        .count();
+
+    // Newest first puts new episodes at the front, so everything already saved moves down
+    if new_episodes > 0 && feed_settings.order == EpisodeOrder::NewestFirst {
+        shift_chapter_files(book, new_episodes)?;
+        book_settings.current_chapter = book_settings
+            .current_chapter
+            .map(|chapter| chapter + new_episodes as i32);
+    }
+
+    feed_settings.known_episodes = feed.episodes.iter().map(|e| e.id.clone()).collect();
+    feed_settings.last_refreshed = now();
+    book_settings.feed = Some(feed_settings.clone());
+    book_settings.save(&settings_file)?;
+    // New episodes change the chapter list
+    save_book_manifest(
+        book,
+        &order_book(feed.to_book(&book_settings.book_url), &feed_settings),
+    )?;
+
+    apply_policies(book, &book_settings.book_url, &feed, &feed_settings)?;
+
+    log::info!("Refreshed {}: {} new episodes", book, new_episodes);
+    Ok(new_episodes)
+}
+
+/// Refreshes every subscription, returning the total number of new episodes.
+pub fn refresh_all() -> usize {
+    let subscriptions = match get_subscriptions() {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            log::error!("Failed to list subscriptions: {}", e);
+            return 0;
+        }
+    };
+
+    subscriptions
+        .iter()
+        .map(|(book, _)| {
+            refresh_subscription(book).unwrap_or_else(|e| {
+                log::error!("Failed to refresh {}: {}", book, e);
+                0
+            })
+        })
+        .sum()
+}
+
+/// Puts the chapters of a feed book in the order the user picked for it.
+pub fn order_book(mut book: Book, feed_settings: &FeedSettings) -> Book {
+    if feed_settings.order == EpisodeOrder::NewestFirst {
+        book.chapter_titles.reverse();
+        book.chapter_urls.reverse();
+        book.chapter_durations.reverse();
+        book.chapter_reader.reverse();
+    }
+    book
+}
+
+// Auto-download the newest episodes and then drop the ones past the retention limit
+fn apply_policies(
+    book: &str,
+    feed_url: &str,
+    feed: &Feed,
+    feed_settings: &FeedSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    let len = feed.episodes.len();
+
+    for (position, episode) in feed
+        .episodes
+        .iter()
+        .enumerate()
+        .rev()
+        .take(feed_settings.auto_download as usize)
+    {
+        let chapter = feed_settings.chapter_index(position, len);
+        if !audio_path.join(format!("chapter_{}.mp3", chapter)).exists() {
+            log::info!("Auto downloading {}: {}", book, episode.title);
+            download_audio(book, chapter as i32, &episode.url, feed_url)?;
+        }
+    }
+
+    if let Some(retention) = feed_settings.retention {
+        for position in 0..len.saturating_sub(retention as usize) {
+            let chapter_file =
+                audio_path.join(format!("chapter_{}.mp3", feed_settings.chapter_index(position, len)));
+            if chapter_file.exists() {
+                log::info!("Removing old episode {}", chapter_file.display());
+                fs::remove_file(chapter_file)?;
+            }
+        }
+    }
+
+    // Renumbered and removed episodes have to be picked up by the index again
+    library::refresh_book(book)?;
+    Ok(())
+}
+
+fn shift_chapter_files(book: &str, by: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    let mut chapters: Vec<usize> = fs::read_dir(&audio_path)?
+        .filter_map(|item| item.ok())
+        .filter_map(|item| {
+            item.file_name()
+                .to_str()?
+                .strip_prefix("chapter_")?
+                .strip_suffix(".mp3")?
+                .parse()
+                .ok()
+        })
+        .collect();
+
+    // Highest first so nothing gets overwritten
+    chapters.sort_unstable_by(|a, b| b.cmp(a));
+    for chapter in chapters {
+        fs::rename(
+            audio_path.join(format!("chapter_{}.mp3", chapter)),
+            audio_path.join(format!("chapter_{}.mp3", chapter + by)),
+        )?;
+    }
+    Ok(())
+}
+
+// Swaps episode files end for end, chapter 0 of `len` becomes chapter `len - 1` and so on
+fn reverse_chapter_files(book: &str, len: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let audio_path = book_dir(book)?;
+    let chapter_file = |chapter: usize| audio_path.join(format!("chapter_{}.mp3", chapter));
+    let moving: Vec<usize> = (0..len).filter(|chapter| chapter_file(*chapter).exists()).collect();
+    // Out of the way first, so nothing gets overwritten
+    for chapter in &moving {
+        fs::rename(
+            chapter_file(*chapter),
+            audio_path.join(format!("chapter_{}.mp3.reorder", chapter)),
+        )?;
+    }
+    for chapter in moving {
+        fs::rename(
+            audio_path.join(format!("chapter_{}.mp3.reorder", chapter)),
+            chapter_file(len - 1 - chapter),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::rss::Episode;
+    use crate::storage::setup::test_env;
+    use std::path::Path;
+
+    // A subscribed book with the given episode files downloaded, each holding its chapter number
+    fn feed_book(title: &str, chapters: &[usize]) -> String {
+        let book = library::add_book(title, &format!("https://example.com/{}.rss", title)).unwrap();
+        let folder = book_dir(&book).unwrap();
+        for chapter in chapters {
+            fs::write(folder.join(format!("chapter_{}.mp3", chapter)), chapter.to_string())
+                .unwrap();
+        }
+        book
+    }
+
+    // Chapter number to what the file holds, i.e. the chapter it was before
+    fn chapter_files(folder: &Path) -> Vec<(usize, String)> {
+        let mut files: Vec<(usize, String)> = fs::read_dir(folder)
+            .unwrap()
+            .filter_map(|item| {
+                let item = item.ok()?;
+                let chapter = item
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("chapter_")?
+                    .strip_suffix(".mp3")?
+                    .parse()
+                    .ok()?;
+                Some((chapter, fs::read_to_string(item.path()).ok()?))
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn feed(episodes: usize) -> Feed {
+        Feed {
+            title: "Feed".to_string(),
+            episodes: (0..episodes)
+                .map(|episode| Episode {
+                    id: format!("ep-{}", episode),
+                    title: format!("Episode {}", episode),
+                    url: format!("https://example.com/{}.mp3", episode),
+                    duration: "".to_string(),
+                    published: "".to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chapter_numbers_follow_the_order() {
+        let oldest = FeedSettings::default();
+        let newest = FeedSettings {
+            order: EpisodeOrder::NewestFirst,
+            ..Default::default()
+        };
+
+        assert_eq!(oldest.chapter_index(0, 5), 0);
+        assert_eq!(oldest.chapter_index(4, 5), 4);
+        assert_eq!(newest.chapter_index(0, 5), 4);
+        assert_eq!(newest.chapter_index(4, 5), 0);
+
+        let book = order_book(feed(3).to_book("https://example.com/feed.rss"), &newest);
+        assert_eq!(book.chapter_titles, ["Episode 2", "Episode 1", "Episode 0"]);
+        assert_eq!(book.chapter_urls[0], "https://example.com/2.mp3");
+    }
+
+    #[test]
+    fn reversing_swaps_downloaded_episodes_end_for_end() {
+        let _env = test_env();
+        let book = feed_book("Reversed Feed", &[0, 1, 3]);
+
+        reverse_chapter_files(&book, 5).unwrap();
+
+        let folder = book_dir(&book).unwrap();
+        let expected = [(1, "3"), (3, "1"), (4, "0")].map(|(c, was)| (c, was.to_string()));
+        assert_eq!(chapter_files(&folder), expected);
+        // Nothing is left behind halfway
+        let leftovers = fs::read_dir(&folder)
+            .unwrap()
+            .filter(|item| item.as_ref().unwrap().path().extension() == Some("reorder".as_ref()))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn shifting_makes_room_for_new_episodes() {
+        let _env = test_env();
+        let book = feed_book("Shifted Feed", &[0, 1, 2]);
+
+        shift_chapter_files(&book, 2).unwrap();
+
+        let expected = [(2, "0"), (3, "1"), (4, "2")].map(|(c, was)| (c, was.to_string()));
+        assert_eq!(chapter_files(&book_dir(&book).unwrap()), expected);
+    }
+
+    #[test]
+    fn retention_keeps_the_newest_episodes() {
+        let _env = test_env();
+        let oldest_first = feed_book("Kept Oldest First", &[0, 1, 2, 3, 4]);
+        let newest_first = feed_book("Kept Newest First", &[0, 1, 2, 3, 4]);
+        let feed = feed(5);
+        let settings = |order| FeedSettings {
+            order,
+            retention: Some(2),
+            ..Default::default()
+        };
+
+        let feed_url = "https://example.com/feed.rss";
+        apply_policies(&oldest_first, feed_url, &feed, &settings(EpisodeOrder::OldestFirst))
+            .unwrap();
+        apply_policies(&newest_first, feed_url, &feed, &settings(EpisodeOrder::NewestFirst))
+            .unwrap();
+
+        let kept = |book: &str| -> Vec<usize> {
+            chapter_files(&book_dir(book).unwrap()).into_iter().map(|(c, _)| c).collect()
+        };
+        assert_eq!(kept(&oldest_first), [3, 4]);
+        assert_eq!(kept(&newest_first), [0, 1]);
+    }
+}
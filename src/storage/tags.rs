@@ -0,0 +1,373 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey, StandardVisualKey};
+use symphonia::core::probe::Hint;
+
+/// The tags of an audio file that say which book it belongs to and where.
+#[derive(Debug, Clone, Default)]
+pub struct AudioTags {
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    /// Embedded cover art, as whatever image format it was stored in
+    pub cover: Option<Vec<u8>>,
+}
+
+impl AudioTags {
+    // When a file has the same tag twice, like ID3 and APE, the first one wins
+    fn set(&mut self, key: StandardTagKey, value: &str) {
+        let value = value.trim();
+        if value.is_empty() {
+            return;
+        }
+        let text = match key {
+            StandardTagKey::TrackTitle => &mut self.title,
+            StandardTagKey::Album => &mut self.album,
+            StandardTagKey::Artist => &mut self.artist,
+            StandardTagKey::AlbumArtist => &mut self.album_artist,
+            StandardTagKey::TrackNumber => {
+                self.track = self.track.or_else(|| leading_number(value));
+                return;
+            }
+            StandardTagKey::DiscNumber => {
+                self.disc = self.disc.or_else(|| leading_number(value));
+                return;
+            }
+            _ => return,
+        };
+        if text.is_none() {
+            *text = Some(value.to_string());
+        }
+    }
+
+    // Vorbis comments, used by FLAC and Ogg
+    fn set_comment(&mut self, key: &str, value: &str) {
+        let key = match key.to_ascii_uppercase().as_str() {
+            "TITLE" => StandardTagKey::TrackTitle,
+            "ALBUM" => StandardTagKey::Album,
+            "ARTIST" => StandardTagKey::Artist,
+            "ALBUMARTIST" | "ALBUM ARTIST" => StandardTagKey::AlbumArtist,
+            "TRACKNUMBER" => StandardTagKey::TrackNumber,
+            "DISCNUMBER" => StandardTagKey::DiscNumber,
+            _ => return,
+        };
+        self.set(key, value);
+    }
+
+    fn add_revision(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            if let Some(key) = tag.std_key {
+                self.set(key, &tag.value.to_string());
+            }
+        }
+        if self.cover.is_none() {
+            // The front cover if it's marked as one, otherwise whatever picture there is
+            let visuals = revision.visuals();
+            self.cover = visuals
+                .iter()
+                .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+                .or(visuals.first())
+                .map(|visual| visual.data.to_vec());
+        }
+    }
+}
+
+/// What a downloaded chapter gets tagged with, so other players file it under its book.
+#[derive(Debug, Clone, Default)]
+pub struct ChapterTags {
+    /// The chapter's title
+    pub title: String,
+    /// The book's title
+    pub album: String,
+    pub author: String,
+    pub reader: String,
+    /// Counting from 1
+    pub track: u32,
+    pub track_count: u32,
+    /// A jpeg, see `covers::cover_as_jpeg`
+    pub cover: Option<Vec<u8>>,
+}
+
+/// Reads the tags of an audio file. A file without tags, or one that can't be read, gives
+/// empty tags.
+pub fn read_tags(path: &Path) -> AudioTags {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let tags = match extension.as_str() {
+        "flac" => read_flac(path),
+        "ogg" | "oga" => read_vorbis(path),
+        _ => read_symphonia(path, &extension),
+    };
+    tags.unwrap_or_else(|e| {
+        log::warn!("Failed to read the tags of {}: {}", path.display(), e);
+        AudioTags::default()
+    })
+}
+
+// ID3 in mp3 files and the metadata atoms of MP4 files
+fn read_symphonia(path: &Path, extension: &str) -> Result<AudioTags, Box<dyn std::error::Error>> {
+    let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+    let mut probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut tags = AudioTags::default();
+    // ID3 tags in front of the audio are found while probing, MP4 ones are part of the container
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|log| log.current()) {
+        tags.add_revision(revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.add_revision(revision);
+    }
+    Ok(tags)
+}
+
+fn read_flac(path: &Path) -> Result<AudioTags, Box<dyn std::error::Error>> {
+    let reader = claxon::FlacReader::open(path)?;
+    let mut tags = AudioTags::default();
+    for (key, value) in reader.tags() {
+        tags.set_comment(key, value);
+    }
+    Ok(tags)
+}
+
+fn read_vorbis(path: &Path) -> Result<AudioTags, Box<dyn std::error::Error>> {
+    let reader = lewton::inside_ogg::OggStreamReader::new(File::open(path)?)?;
+    let mut tags = AudioTags::default();
+    for (key, value) in &reader.comment_hdr.comment_list {
+        tags.set_comment(key, value);
+    }
+    Ok(tags)
+}
+
+// "3/12" and "03" are both track 3
+fn leading_number(value: &str) -> Option<u32> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Replaces the ID3 tags of an mp3 file with `tags`. They're written as ID3v2.3, which is
+/// what car stereos and older players understand, and any ID3v1 tag at the end goes too so
+/// nothing disagrees with them.
+pub fn write_id3(path: &Path, tags: &ChapterTags) -> io::Result<()> {
+    let mut source = File::open(path)?;
+    let length = source.metadata()?.len();
+    let audio_start = id3v2_length(&mut source)?;
+    let audio_end = length - id3v1_length(&mut source, length)?;
+    if audio_end <= audio_start {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} has no audio", path.display()),
+        ));
+    }
+
+    // The audio is copied behind the new tag and then swapped in, a file that's cut short
+    // halfway would be worse than one with the old tags
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp = Path::new(&temp_name);
+    let result = (|| {
+        let mut output = BufWriter::new(File::create(temp)?);
+        output.write_all(&id3v2_tag(tags))?;
+        source.seek(SeekFrom::Start(audio_start))?;
+        io::copy(
+            &mut (&mut source).take(audio_end - audio_start),
+            &mut output,
+        )?;
+        output.into_inner()?.sync_all()
+    })();
+    drop(source);
+    match result {
+        Ok(()) => fs::rename(temp, path),
+        Err(e) => {
+            let _ = fs::remove_file(temp);
+            Err(e)
+        }
+    }
+}
+
+// Size of the ID3v2 tag at the start of the file, 0 when there isn't one
+fn id3v2_length(file: &mut File) -> io::Result<u64> {
+    let mut header = [0; 10];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+    let size = header[6..]
+        .iter()
+        .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7f) as u64);
+    // v2.4 tags can have a copy of the header at the end
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+// Size of the 128 byte ID3v1 tag at the end of the file, 0 when there isn't one
+fn id3v1_length(file: &mut File, length: u64) -> io::Result<u64> {
+    if length < 128 {
+        return Ok(0);
+    }
+    let mut marker = [0; 3];
+    file.seek(SeekFrom::Start(length - 128))?;
+    file.read_exact(&mut marker)?;
+    Ok(if &marker == b"TAG" { 128 } else { 0 })
+}
+
+fn id3v2_tag(tags: &ChapterTags) -> Vec<u8> {
+    let mut frames = vec![];
+    let texts = [
+        (b"TIT2", tags.title.clone()),
+        (b"TALB", tags.album.clone()),
+        (b"TPE1", tags.author.clone()),
+        (b"TPE2", tags.author.clone()),
+        // There's no frame for the reader, audiobook apps look for them as the composer
+        (b"TCOM", tags.reader.clone()),
+        (b"TRCK", format!("{}/{}", tags.track, tags.track_count)),
+        (b"TCON", "Audiobook".to_string()),
+    ];
+    for (id, text) in texts {
+        if !text.trim().is_empty() {
+            frames.extend(id3_frame(id, &text_frame(text.trim())));
+        }
+    }
+    if let Some(cover) = &tags.cover {
+        // Latin-1 mime type, front cover, no description
+        let mut picture = vec![0];
+        picture.extend_from_slice(b"image/jpeg\0");
+        picture.extend_from_slice(&[3, 0]);
+        picture.extend_from_slice(cover);
+        frames.extend(id3_frame(b"APIC", &picture));
+    }
+
+    let size = frames.len() as u32;
+    let mut tag = b"ID3".to_vec();
+    // Version 2.3, no flags
+    tag.extend_from_slice(&[3, 0, 0]);
+    // The size is stored 7 bits a byte
+    tag.extend((0..4).rev().map(|byte| ((size >> (byte * 7)) & 0x7f) as u8));
+    tag.extend(frames);
+    tag
+}
+
+fn id3_frame(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut frame = id.to_vec();
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(data);
+    frame
+}
+
+// Latin-1 when the text fits, otherwise UTF-16 with a byte order mark
+fn text_frame(text: &str) -> Vec<u8> {
+    if text.chars().all(|c| (c as u32) < 0x100) {
+        let mut data = vec![0];
+        data.extend(text.chars().map(|c| c as u8));
+        return data;
+    }
+    let mut data = vec![1, 0xff, 0xfe];
+    data.extend(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Silent MPEG-1 layer III frames, 128 kbit/s at 44.1 kHz, enough for the probe to lock on
+    fn mp3_frames() -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        frame.repeat(20)
+    }
+
+    fn mp3_file(test: &str, contents: &[u8]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("audiody-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chapter.mp3");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn chapter_tags() -> ChapterTags {
+        ChapterTags {
+            title: "Chapter 3".to_string(),
+            album: "Le Comte de Monte-Cristo".to_string(),
+            author: "Alexandre Dumas".to_string(),
+            reader: "Zoë".to_string(),
+            track: 3,
+            track_count: 117,
+            cover: Some(vec![0xff, 0xd8, 0xff, 0xd9]),
+        }
+    }
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).filter(|window| *window == needle).count()
+    }
+
+    #[test]
+    fn written_tags_are_read_back() {
+        let path = mp3_file("written-tags", &mp3_frames());
+        write_id3(&path, &chapter_tags()).unwrap();
+
+        let tags = read_tags(&path);
+        assert_eq!(tags.title.as_deref(), Some("Chapter 3"));
+        assert_eq!(tags.album.as_deref(), Some("Le Comte de Monte-Cristo"));
+        assert_eq!(tags.artist.as_deref(), Some("Alexandre Dumas"));
+        assert_eq!(tags.album_artist.as_deref(), Some("Alexandre Dumas"));
+        assert_eq!(tags.track, Some(3));
+        assert_eq!(tags.cover, Some(vec![0xff, 0xd8, 0xff, 0xd9]));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn old_tags_are_replaced_not_added_to() {
+        let audio = mp3_frames();
+        let old = ChapterTags {
+            title: "Old title".to_string(),
+            track: 9,
+            track_count: 9,
+            ..Default::default()
+        };
+        let mut contents = id3v2_tag(&old);
+        contents.extend_from_slice(&audio);
+        let mut v1 = b"TAG".to_vec();
+        v1.resize(128, 0);
+        contents.extend(v1);
+        let path = mp3_file("replaced-tags", &contents);
+
+        write_id3(&path, &chapter_tags()).unwrap();
+        // Tagging twice shouldn't stack up tags either
+        write_id3(&path, &chapter_tags()).unwrap();
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(count(&written, b"ID3"), 1);
+        assert_eq!(count(&written, b"Old title"), 0);
+        assert!(written.ends_with(&audio));
+        let tags = read_tags(&path);
+        assert_eq!(tags.title.as_deref(), Some("Chapter 3"));
+        assert_eq!(tags.track, Some(3));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn track_numbers_ignore_the_count() {
+        assert_eq!(leading_number("3/12"), Some(3));
+        assert_eq!(leading_number("03"), Some(3));
+        assert_eq!(leading_number("side A"), None);
+    }
+}